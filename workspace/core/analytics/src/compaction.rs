@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs::{create_dir_all, File};
+use std::sync::Arc;
+use arrow::datatypes::SchemaRef;
+use log::{error, info, warn};
+use object_store::path::Path;
+use object_store::ObjectMeta;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use ulid::Ulid;
+use yaml_rust2::Yaml;
+use crate::writers::arrow::parquet::writer::writer_properties;
+use crate::writers::object_storage::{new_object_storage, ObjectStorage, ObjectStorageConfig};
+
+const DEFAULT_TARGET_FILE_SIZE: u64 = 134217728;
+const DEFAULT_ROW_GROUP_SIZE: usize = 1048576;
+
+#[derive(Clone)]
+pub struct CompactionConfig {
+    pub prefix: String,
+    /// Files at or above this size are left alone, smaller ones are merged up to it.
+    pub target_file_size: u64,
+    pub row_group_size: usize,
+    pub temp_dir: String,
+}
+
+impl CompactionConfig {
+    pub fn from_env() -> Self {
+        Self {
+            prefix: env::var("COMPACTION_PREFIX").unwrap_or("ingest/raw".to_owned()),
+            target_file_size: env::var("COMPACTION_TARGET_FILE_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_TARGET_FILE_SIZE),
+            row_group_size: env::var("COMPACTION_ROW_GROUP_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_ROW_GROUP_SIZE),
+            temp_dir: env::var("TEMP_DIR").unwrap_or("./analytics/temp".to_owned()),
+        }
+    }
+}
+
+impl From<&Yaml> for CompactionConfig {
+    fn from(yaml: &Yaml) -> Self {
+        let defaults = Self::from_env();
+        Self {
+            prefix: yaml["prefix"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .unwrap_or(defaults.prefix),
+            target_file_size: yaml["target_file_size"]
+                .as_i64()
+                .map(|s| s as u64)
+                .unwrap_or(defaults.target_file_size),
+            row_group_size: yaml["row_group_size"]
+                .as_i64()
+                .map(|s| s as usize)
+                .unwrap_or(defaults.row_group_size),
+            temp_dir: yaml["temp_dir"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .unwrap_or(defaults.temp_dir),
+        }
+    }
+}
+
+/// Merges the small parquet objects found under the prefix, each directory (partition) is compacted on its own.
+pub async fn compact(
+    config: &CompactionConfig,
+    storage_config: &ObjectStorageConfig,
+    prefix: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let storage = new_object_storage(storage_config)?;
+    let prefix = Path::parse(prefix.unwrap_or(config.prefix.clone()))?;
+    create_dir_all(&config.temp_dir)?;
+
+    let mut directories: BTreeMap<String, Vec<ObjectMeta>> = BTreeMap::new();
+    for object in storage.list(&prefix).await? {
+        let location = object.location.to_string();
+        if !location.ends_with(".parquet") || object.size >= config.target_file_size {
+            continue;
+        }
+        let directory = location
+            .rsplit_once('/')
+            .map(|(dir, _)| dir.to_owned())
+            .unwrap_or_default();
+        directories.entry(directory).or_default().push(object);
+    }
+
+    for (directory, mut objects) in directories {
+        if objects.len() < 2 {
+            continue;
+        }
+        objects.sort_by_key(|o| o.last_modified);
+        let mut bin = Vec::new();
+        let mut bin_size = 0u64;
+        for object in objects {
+            if !bin.is_empty() && bin_size + object.size > config.target_file_size {
+                compact_bin(config, &storage, &directory, std::mem::take(&mut bin)).await?;
+                bin_size = 0;
+            }
+            bin_size += object.size;
+            bin.push(object);
+        }
+        compact_bin(config, &storage, &directory, bin).await?;
+    }
+    Ok(())
+}
+
+async fn compact_bin(
+    config: &CompactionConfig,
+    storage: &ObjectStorage,
+    directory: &str,
+    objects: Vec<ObjectMeta>,
+) -> Result<(), Box<dyn Error>> {
+    if objects.len() < 2 {
+        return Ok(());
+    }
+    let id = Ulid::new();
+    let temp_file = format!("{}/compaction-{id}.parquet", config.temp_dir);
+    let mut writer: Option<(SchemaRef, ArrowWriter<File>)> = None;
+    let mut compacted = Vec::new();
+    let mut rows = 0;
+    for object in objects {
+        let bytes = storage.get_bytes(&object.location).await?;
+        let builder = ParquetRecordBatchReaderBuilder::try_new(bytes)?;
+        let schema = Arc::clone(builder.schema());
+        if writer.is_none() {
+            let file = File::create(&temp_file)?;
+            let props = writer_properties(config.row_group_size);
+            writer = Some((Arc::clone(&schema), ArrowWriter::try_new(file, Arc::clone(&schema), Some(props))?));
+        }
+        let (expected, arrow_writer) = writer.as_mut().unwrap();
+        if expected.fields() != schema.fields() {
+            warn!("skipping {} during compaction, schema differs", object.location);
+            continue;
+        }
+        let reader = builder.with_batch_size(8192).build()?;
+        for batch in reader {
+            let batch = batch?;
+            rows += batch.num_rows();
+            arrow_writer.write(&batch)?;
+        }
+        compacted.push(object.location);
+    }
+    let Some((_, writer)) = writer else {
+        return Ok(());
+    };
+    writer.close()?;
+    if compacted.len() < 2 {
+        tokio::fs::remove_file(&temp_file).await?;
+        return Ok(());
+    }
+    let location = Path::parse(format!("{directory}/compacted-{id}.parquet"))?;
+    info!("compacting {} files ({rows} rows) into {location}", compacted.len());
+    let result = storage.put_file(&location, &temp_file).await;
+    tokio::fs::remove_file(&temp_file).await?;
+    result?;
+    // the inputs are only removed once the compacted file is in place, a failure here leaves duplicates rather than gaps
+    for location in compacted {
+        if let Err(e) = storage.delete(&location).await {
+            error!("error deleting compacted file {location}: {e:?}");
+        }
+    }
+    Ok(())
+}
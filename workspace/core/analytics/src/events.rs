@@ -57,6 +57,9 @@ pub struct Geo {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Device {
     pub installation_id: String,
+    /// Returned by `/register` along with the installation id, checked and removed on ingest.
+    #[serde(alias="installationToken", skip_serializing_if="Option::is_none")]
+    pub installation_token: Option<String>,
     pub manufacturer: String,
     pub model: String,
     pub platform: String,
@@ -70,9 +73,28 @@ pub struct Device {
     pub version: String,
 }
 
+/// Everything but `agent` is set by the user agent transform, whatever the client sent is cleared on ingest.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Browser {
-    pub agent: String
+    pub agent: String,
+    pub family: Option<String>,
+    pub version: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub device_class: Option<String>,
+    pub bot: Option<bool>,
+}
+
+impl Browser {
+    /// Clears everything parsed from the agent.
+    pub fn clear_parsed(&mut self) {
+        self.family = None;
+        self.version = None;
+        self.os = None;
+        self.os_version = None;
+        self.device_class = None;
+        self.bot = None;
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -85,6 +107,14 @@ pub struct EventContext {
     pub geo: Geo,
     pub session_id: String,
     pub user_id: Option<String>,
+    #[serde(alias="analyticsConsent")]
+    pub analytics_consent: Option<bool>,
+    /// Set by the filter transform when it tags rather than drops events, whatever the client sent is
+    /// cleared on ingest.
+    pub filter_reason: Option<String>,
+    /// Whether the installation token proved the installation id was issued by `/register`,
+    /// not set when installation tokens aren't configured.
+    pub installation_verified: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -95,4 +125,7 @@ pub struct Events {
     pub sent_micros: u32,
     pub received: Option<i64>,
     pub received_micros: Option<u32>,
+    /// Sent again unchanged when a client retries a post, used for deduplication.
+    #[serde(alias="batchId")]
+    pub batch_id: Option<String>,
 }
\ No newline at end of file
@@ -1,19 +1,51 @@
+use std::net::IpAddr;
+use std::str::FromStr;
 use http::HeaderMap;
+use ipnetwork::IpNetwork;
 use crate::events::Events;
 
 #[derive(Clone)]
 pub struct EventPipelineContext {
     headers: HeaderMap,
+    remote_addr: Option<IpAddr>,
 }
 
 impl EventPipelineContext {
-    pub fn new(headers: HeaderMap) -> Self {
-        Self { headers }
+    pub fn new(headers: HeaderMap, remote_addr: Option<IpAddr>) -> Self {
+        Self { headers, remote_addr }
     }
 
     pub fn get_header_value(&self, key: &str) -> Option<&str> {
         self.headers.get(key).map(|v| v.to_str().unwrap_or(""))
     }
+
+    /// The address of the client that sent the events. `X-Forwarded-For` is only followed (right to left)
+    /// while the hop that reported it is one of the trusted proxies.
+    pub fn client_ip(&self, trusted_proxies: &[IpNetwork]) -> Option<IpAddr> {
+        let trusted = |ip: IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+        let mut client = self.remote_addr;
+        if let Some(remote) = client {
+            if !trusted(remote) {
+                return client;
+            }
+        }
+        let forwarded: Vec<&str> = self.headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        for value in forwarded.iter().rev() {
+            let Ok(ip) = IpAddr::from_str(value.trim()) else {
+                break;
+            };
+            client = Some(ip);
+            if !trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
 }
 
 #[async_trait::async_trait]
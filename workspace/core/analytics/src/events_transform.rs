@@ -4,5 +4,6 @@ use crate::events_sink::EventPipelineContext;
 
 #[async_trait::async_trait]
 pub trait EventTransform {
+    /// Mutates the events in place, a transform drops them by clearing `event.events`.
     async fn transform(&self, context: &mut EventPipelineContext, event: &mut Events) -> Result<(), Box<dyn Error>>;
 }
\ No newline at end of file
@@ -0,0 +1,194 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use axum::extract::{ConnectInfo, State};
+use bytes::Bytes;
+use chrono::Utc;
+use http::header::{CONTENT_TYPE, RETRY_AFTER};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use serde_json::{json, Value};
+use crate::events_sink::EventPipelineContext;
+use crate::installation::InstallationTokens;
+use crate::metrics::{EventLabels, METRICS};
+use crate::validation::{Rejection, ValidationConfig, ValidationReport};
+use crate::writers::writer::{EventsWriter, WriteError};
+
+pub struct EventsContext {
+    pub writer: Arc<EventsWriter>,
+    pub validation: ValidationConfig,
+    pub installation_tokens: Option<Arc<InstallationTokens>>,
+}
+
+enum BodyFormat {
+    /// A single `Events` document, also what `navigator.sendBeacon` sends as `text/plain`.
+    Json,
+    /// One `Events` document per line, used by clients flushing a backlog.
+    Ndjson,
+}
+
+impl BodyFormat {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let Some(content_type) = headers.get(CONTENT_TYPE) else {
+            return Some(BodyFormat::Json);
+        };
+        let content_type = content_type.to_str().ok()?;
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        match mime.as_str() {
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" | "application/x-jsonlines" => {
+                Some(BodyFormat::Ndjson)
+            }
+            "" | "application/json" | "text/plain" => Some(BodyFormat::Json),
+            _ if mime.ends_with("+json") => Some(BodyFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+fn json_response(status: StatusCode, body: String) -> (StatusCode, HeaderMap, String) {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_str("Content-Type").unwrap(),
+        HeaderValue::from_str("application/json").unwrap(),
+    );
+    (status, headers, body)
+}
+
+fn count_rejections(app_id: &str, rejections: &[Rejection]) {
+    let app_id = METRICS.app_id_label(app_id);
+    let mut counted = Vec::new();
+    for rejection in rejections {
+        // an event can fail several rules but is only rejected once
+        if rejection.index.is_some() && counted.contains(&rejection.index) {
+            continue;
+        }
+        counted.push(rejection.index);
+        let event_type = rejection.event_type.as_deref().unwrap_or("unknown");
+        METRICS.events_received.with_label_values(&[event_type, app_id]).inc();
+        METRICS.events_rejected.with_label_values(&[event_type, app_id, "invalid"]).inc();
+    }
+}
+
+fn with_line(mut rejections: Vec<Rejection>, line: Option<usize>) -> Vec<Rejection> {
+    for rejection in rejections.iter_mut() {
+        rejection.line = line;
+    }
+    rejections
+}
+
+/// Accepts JSON, NDJSON or `text/plain` bodies, gzip and zstd `Content-Encoding` is decoded before
+/// this handler sees the body. Every document is validated on its own and the valid ones are written
+/// together, when that write is rejected (429 or 503) none of the body was written. The response
+/// reports the events that were accepted and why any were rejected.
+pub async fn events(
+    State(events_context): State<Arc<EventsContext>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, HeaderMap, String), (StatusCode, HeaderMap, String)> {
+    let Some(format) = BodyFormat::from_headers(&headers) else {
+        return Err(json_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            json!({ "error": "expected application/json, application/x-ndjson or text/plain" }).to_string(),
+        ));
+    };
+    let mut report = ValidationReport { accepted: 0, rejected: Vec::new() };
+    let documents: Vec<(Option<usize>, Value)> = match format {
+        BodyFormat::Json => match serde_json::from_slice(&body) {
+            Ok(document) => vec![(None, document)],
+            Err(e) => {
+                report.rejected.push(Rejection::payload(None, e.to_string()));
+                return Err(json_response(StatusCode::BAD_REQUEST, json!(report).to_string()));
+            }
+        },
+        BodyFormat::Ndjson => {
+            let mut documents = Vec::new();
+            for (line, contents) in body.split(|b| *b == b'\n').enumerate() {
+                if contents.iter().all(|b| b.is_ascii_whitespace()) {
+                    continue;
+                }
+                match serde_json::from_slice(contents) {
+                    Ok(document) => documents.push((Some(line), document)),
+                    Err(e) => report.rejected.push(Rejection::payload(Some(line), e.to_string())),
+                }
+            }
+            documents
+        }
+    };
+    let context = EventPipelineContext::new(headers, Some(remote_addr.ip()));
+    let writer = &events_context.writer;
+    let mut payloads = Vec::new();
+    let mut labels = Vec::new();
+    let now = Utc::now();
+    for (line, document) in documents {
+        let app_id = document["context"]["app_id"].as_str().unwrap_or("").to_owned();
+        let (mut payload, rejected) = match events_context.validation.validate(document, now.timestamp_millis()) {
+            Ok(validated) => validated,
+            Err(rejected) => {
+                count_rejections(&app_id, &rejected);
+                report.rejected.extend(with_line(rejected, line));
+                continue;
+            }
+        };
+        count_rejections(&app_id, &rejected);
+        report.rejected.extend(with_line(rejected, line));
+        // the token is never stored or forwarded, only whether it checked out
+        let token = payload.context.device.installation_token.take();
+        if let Some(tokens) = &events_context.installation_tokens {
+            if let Err(rejection) = tokens.verify(&mut payload, token) {
+                let rejected = vec![rejection];
+                count_rejections(&app_id, &rejected);
+                report.rejected.extend(with_line(rejected, line));
+                continue;
+            }
+        } else {
+            payload.context.installation_verified = None;
+        }
+        // only the transforms set these, never the client
+        payload.context.filter_reason = None;
+        if let Some(browser) = payload.context.browser.as_mut() {
+            browser.clear_parsed();
+        }
+        if payload.events.is_empty() {
+            continue;
+        }
+        let payload_labels = EventLabels::from(&payload);
+        METRICS.count_events(&METRICS.events_received, &payload_labels, &[]);
+        payload.received = Some(now.timestamp_millis());
+        payload.received_micros = Some(now.timestamp_subsec_micros());
+        labels.push(payload_labels);
+        payloads.push(payload);
+    }
+    if !payloads.is_empty() {
+        let accepted: usize = payloads.iter().map(|payload| payload.events.len()).sum();
+        if let Err(e) = writer.write(context, payloads).await {
+            let reason = match e {
+                WriteError::Overloaded => "overloaded",
+                WriteError::Stopped => "stopped",
+                WriteError::Closed => "closed",
+            };
+            for labels in &labels {
+                METRICS.count_events(&METRICS.events_rejected, labels, &[reason]);
+            }
+            let status = match e {
+                WriteError::Overloaded => StatusCode::TOO_MANY_REQUESTS,
+                WriteError::Stopped | WriteError::Closed => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            // nothing in the body was written, so the client can send all of it again
+            report.rejected.push(Rejection::payload(None, format!("Error writing payload: {e}")));
+            let (status, mut headers, body) = json_response(status, json!(report).to_string());
+            headers.insert(
+                RETRY_AFTER,
+                HeaderValue::from(writer.retry_after().as_secs().max(1)),
+            );
+            return Err((status, headers, body));
+        }
+        for labels in &labels {
+            METRICS.count_events(&METRICS.events_accepted, labels, &[]);
+        }
+        report.accepted += accepted;
+    }
+    if report.accepted == 0 && !report.rejected.is_empty() {
+        return Err(json_response(StatusCode::UNPROCESSABLE_ENTITY, json!(report).to_string()));
+    }
+    Ok(json_response(StatusCode::OK, json!(report).to_string()))
+}
@@ -1,16 +1,26 @@
 use std::collections::HashSet;
 use std::env;
-use std::net::Ipv4Addr;
+use std::error::Error;
+use std::net::IpAddr;
 use std::sync::{Mutex, OnceLock};
 use std::time::{Duration, SystemTime};
-use log::info;
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::{info, warn};
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use ulid::Ulid;
+use yaml_rust2::Yaml;
+use crate::events::Events;
+use crate::validation::Rejection;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Installation {
-    pub id: String
+    pub id: String,
+    /// Sent back as `context.device.installation_token` to prove the id was issued here.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
 }
 
 struct LastCreation {
@@ -24,22 +34,243 @@ fn last() -> &'static Mutex<LastCreation> {
 }
 
 const NODE_BITS: u16 = 8;
+const MAX_NODE_ID: u16 = (1 << NODE_BITS) - 1;
 
-fn node_id() -> &'static u16 {
-    static LOCK: OnceLock<u16> = OnceLock::new();
-    LOCK.get_or_init(|| {
-        let ip: Ipv4Addr = env::var("POD_IP").unwrap().parse().unwrap();
-        let node_id: u16 = ip.octets()[3] as u16;
-        let max_node_id: u16 = (1 << NODE_BITS) - 1;
-        if node_id > max_node_id {
-            panic!("node_id is too large for the specified number of bits: {node_id}, {max_node_id}");
-        } else {
-            info!(target: "bosca", "node_id: {node_id}");
+/// Where the node id embedded in every installation id comes from. Replicas need distinct node ids
+/// for the ids they issue in the same millisecond not to collide.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeIdSource {
+    /// `POD_IP` when set, then the hostname, then random.
+    Auto,
+    Explicit(u16),
+    /// The last byte of `POD_IP`, IPv4 or IPv6.
+    PodIp,
+    /// The ordinal of a StatefulSet style `HOSTNAME` (or /etc/hostname) such as `analytics-3`, a hash
+    /// of any other hostname.
+    Hostname,
+    /// Picked at startup, only unlikely to collide across a handful of replicas.
+    Random,
+}
+
+impl TryFrom<&str> for NodeIdSource {
+    type Error = Box<dyn Error>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "auto" => Ok(NodeIdSource::Auto),
+            "pod_ip" => Ok(NodeIdSource::PodIp),
+            "hostname" => Ok(NodeIdSource::Hostname),
+            "random" => Ok(NodeIdSource::Random),
+            _ => match value.parse::<u16>() {
+                Ok(node_id) if node_id <= MAX_NODE_ID => Ok(NodeIdSource::Explicit(node_id)),
+                Ok(node_id) => Err(format!("node id {node_id} is larger than {MAX_NODE_ID}").into()),
+                Err(_) => Err(format!("unknown node id source: {value}").into()),
+            },
         }
-        node_id
+    }
+}
+
+fn hostname() -> Option<String> {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+}
+
+fn pod_ip_node_id() -> Result<u16, Box<dyn Error>> {
+    let ip: IpAddr = env::var("POD_IP").map_err(|_| "POD_IP isn't set")?.parse()?;
+    Ok(match ip {
+        IpAddr::V4(ip) => ip.octets()[3] as u16,
+        IpAddr::V6(ip) => ip.octets()[15] as u16,
     })
 }
 
+/// The ordinal of a StatefulSet style name (`analytics-3`), otherwise a byte of its hash.
+fn hostname_node_id() -> Result<u16, Box<dyn Error>> {
+    let hostname = hostname().ok_or("no hostname available")?;
+    let ordinal = hostname
+        .rsplit_once('-')
+        .and_then(|(_, ordinal)| ordinal.parse::<u16>().ok())
+        .filter(|ordinal| *ordinal <= MAX_NODE_ID);
+    if let Some(ordinal) = ordinal {
+        return Ok(ordinal);
+    }
+    warn!(target: "bosca", "hostname {hostname} has no ordinal, hashing it for the node_id, which can collide with other replicas");
+    Ok(Sha256::digest(hostname.as_bytes())[0] as u16)
+}
+
+fn random_node_id() -> u16 {
+    thread_rng().gen_range(0..=MAX_NODE_ID)
+}
+
+fn warned_random_node_id() -> u16 {
+    warn!(target: "bosca", "using a random node_id, which can collide with other replicas, set node_id to be sure it doesn't");
+    random_node_id()
+}
+
+impl NodeIdSource {
+    fn resolve(&self) -> Result<u16, Box<dyn Error>> {
+        match self {
+            NodeIdSource::Auto => {
+                if env::var("POD_IP").is_ok() {
+                    return pod_ip_node_id();
+                }
+                if let Ok(node_id) = hostname_node_id() {
+                    return Ok(node_id);
+                }
+                warn!(target: "bosca", "no POD_IP or hostname");
+                Ok(warned_random_node_id())
+            }
+            NodeIdSource::Explicit(node_id) => Ok(*node_id),
+            NodeIdSource::PodIp => pod_ip_node_id(),
+            NodeIdSource::Hostname => hostname_node_id(),
+            NodeIdSource::Random => Ok(warned_random_node_id()),
+        }
+    }
+}
+
+static NODE_ID: OnceLock<u16> = OnceLock::new();
+
+/// Resolves the node id at startup so a misconfigured source fails before any id is issued.
+pub fn init_node_id(source: &NodeIdSource) -> Result<u16, Box<dyn Error>> {
+    let node_id = source.resolve()?;
+    let node_id = *NODE_ID.get_or_init(|| node_id);
+    info!(target: "bosca", "node_id: {node_id} ({source:?})");
+    Ok(node_id)
+}
+
+fn node_id() -> u16 {
+    *NODE_ID.get_or_init(random_node_id)
+}
+
+#[derive(Clone)]
+pub struct InstallationConfig {
+    pub node_id: NodeIdSource,
+    /// Signs the installation tokens `/register` returns, no tokens are issued or checked without it.
+    pub token_secret: Option<String>,
+    /// How long a token is accepted for, forever when not set.
+    pub token_ttl: Option<Duration>,
+    /// Rejects events without a valid token for their installation id.
+    pub require_token: bool,
+}
+
+impl InstallationConfig {
+    /// `NODE_ID` (a number) takes precedence over `NODE_ID_SOURCE`, which defaults to auto.
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let node_id = match env::var("NODE_ID").or_else(|_| env::var("NODE_ID_SOURCE")) {
+            Ok(source) => NodeIdSource::try_from(source.as_str())?,
+            Err(_) => NodeIdSource::Auto,
+        };
+        Ok(Self {
+            node_id,
+            token_secret: env::var("INSTALLATION_TOKEN_SECRET").ok().filter(|s| !s.is_empty()),
+            token_ttl: env::var("INSTALLATION_TOKEN_TTL_DAYS")
+                .ok()
+                .and_then(|d| d.parse::<u64>().ok())
+                .map(|d| Duration::from_secs(d * 86400)),
+            require_token: env::var("INSTALLATION_TOKEN_REQUIRED").map(|r| r == "true").unwrap_or(false),
+        })
+    }
+}
+
+impl TryFrom<&Yaml> for InstallationConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        let config = Self {
+            node_id: match &yaml["node_id"] {
+                Yaml::Integer(node_id) => NodeIdSource::try_from(node_id.to_string().as_str())?,
+                Yaml::String(source) => NodeIdSource::try_from(source.as_str())?,
+                _ => NodeIdSource::Auto,
+            },
+            token_secret: yaml["token_secret"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .or_else(|| env::var("INSTALLATION_TOKEN_SECRET").ok())
+                .filter(|s| !s.is_empty()),
+            token_ttl: yaml["token_ttl_days"].as_i64().map(|d| Duration::from_secs(d as u64 * 86400)),
+            require_token: yaml["require_token"].as_bool().unwrap_or(false),
+        };
+        if config.require_token && config.token_secret.is_none() {
+            return Err("installation require_token needs a token_secret (or INSTALLATION_TOKEN_SECRET)".into());
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct InstallationClaims {
+    sub: String,
+    iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+}
+
+/// Issues and checks the HS256 tokens that tie an installation id to this service.
+pub struct InstallationTokens {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+    ttl: Option<Duration>,
+    required: bool,
+}
+
+impl InstallationTokens {
+    pub fn new(config: &InstallationConfig) -> Option<Self> {
+        let secret = config.token_secret.as_ref()?;
+        let mut validation = Validation::new(Algorithm::HS256);
+        if config.token_ttl.is_some() {
+            validation.set_required_spec_claims(&["exp", "sub"]);
+        } else {
+            validation.set_required_spec_claims(&["sub"]);
+            validation.validate_exp = false;
+        }
+        Some(Self {
+            encoding: EncodingKey::from_secret(secret.as_bytes()),
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+            ttl: config.token_ttl,
+            required: config.require_token,
+        })
+    }
+
+    pub fn issue(&self, installation_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+        let now = Utc::now().timestamp();
+        let claims = InstallationClaims {
+            sub: installation_id.to_owned(),
+            iat: now,
+            exp: self.ttl.map(|ttl| now + ttl.as_secs() as i64),
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+    }
+
+    fn is_valid(&self, installation_id: &str, token: &str) -> bool {
+        match decode::<InstallationClaims>(token, &self.decoding, &self.validation) {
+            Ok(token) => token.claims.sub == installation_id,
+            Err(_) => false,
+        }
+    }
+
+    /// Records in the context whether the token matches the installation id, or rejects the
+    /// payload when tokens are required and it doesn't.
+    pub fn verify(&self, events: &mut Events, token: Option<String>) -> Result<(), Rejection> {
+        let device = &events.context.device;
+        let verified = token.map(|t| self.is_valid(&device.installation_id, &t)).unwrap_or(false);
+        if !verified && self.required {
+            return Err(Rejection {
+                index: None,
+                line: None,
+                field: "context.device.installation_token".to_owned(),
+                reason: "missing, expired or not issued for this installation id".to_owned(),
+                event_type: None,
+            });
+        }
+        events.context.installation_verified = Some(verified);
+        Ok(())
+    }
+}
+
 impl Installation {
 
     pub fn new() -> Installation {
@@ -54,7 +285,7 @@ impl Installation {
             let mut source = thread_rng();
             let random_msb = source.gen::<u16>();
             let lsb = source.gen::<u64>();
-            let node_id = *node_id();
+            let node_id = node_id();
             let cleared_msb = random_msb & !(((1 << NODE_BITS) - 1) << (16 - NODE_BITS));
             let node_msb = cleared_msb | (node_id << (16 - NODE_BITS));
             let msb = (timebits << 16) | u64::from(node_msb);
@@ -69,7 +300,8 @@ impl Installation {
                 last_creation.created.insert(id);
             }
             return Installation {
-                id: id.to_string()
+                id: id.to_string(),
+                token: None,
             }
         }
     }
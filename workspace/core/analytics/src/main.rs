@@ -1,22 +1,30 @@
+mod compaction;
 mod events;
 mod events_sink;
 pub mod events_transform;
+mod ingest;
 mod installation;
+mod metrics;
+mod pipeline;
+mod query;
+mod replay;
+mod rollup;
 mod transforms;
+mod validation;
 mod writers;
 
-use axum::extract::{DefaultBodyLimit, State};
+use axum::extract::DefaultBodyLimit;
 use axum::routing::post;
-use axum::{extract, response::IntoResponse, routing::get, Router};
-use chrono::Utc;
+use axum::{routing::get, Router};
 use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
-use log::{info, warn};
+use log::{error, info, warn};
 use opentelemetry::{global, KeyValue};
 use serde_json::json;
 use std::env;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::atomic::{AtomicBool, AtomicI64};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
@@ -29,16 +37,22 @@ use opentelemetry_sdk::{runtime, Resource};
 use tokio::signal::unix::{signal, SignalKind};
 #[cfg(windows)]
 use tokio::signal::windows::ctrl_c;
+use tower_http::decompression::RequestDecompressionLayer;
+use tower::ServiceBuilder;
 use tower_http::timeout::TimeoutLayer;
 
-use crate::events::Events;
-use crate::events_sink::EventPipelineContext;
-use crate::installation::Installation;
-use crate::transforms::cloudflare_geo::CloudflareGeoTransform;
-use crate::writers::arrow::json::sink::JsonSink;
+use crate::compaction::compact;
+use crate::installation::{init_node_id, Installation, InstallationTokens};
+use crate::ingest::{events, EventsContext};
+use crate::metrics::METRICS;
+use crate::pipeline::{PipelineConfig, SinkConfig, TransformConfig};
+use crate::query::{is_authorized, query, QueryContext};
+use crate::replay::{replay, ReplayArgs};
+use crate::rollup::{rollup, RollupArgs};
+use crate::transforms::maxmind_geo::watch_geoip_database;
 use crate::writers::arrow::schema::SchemaDefinition;
-use crate::writers::files::{find_file, watch_files, watch_files_hourly, Config};
-use crate::writers::http::sink::HttpSink;
+use crate::writers::files::{is_writable, watch_files, watch_files_hourly};
+use crate::writers::http::dead_letter::watch_dead_letters;
 use crate::writers::writer::EventsWriter;
 use mimalloc::MiMalloc;
 use tower_http::cors::{Any, CorsLayer};
@@ -94,42 +108,60 @@ async fn shutdown_hook(writer: Arc<EventsWriter>, watching: Arc<AtomicBool>) {
     }
 }
 
-async fn register() -> impl IntoResponse {
+async fn register(tokens: Option<Arc<InstallationTokens>>) -> Result<(HeaderMap, String), (StatusCode, String)> {
     let mut headers = HeaderMap::new();
     headers.insert(
         HeaderName::from_str("Content-Type").unwrap(),
         HeaderValue::from_str("application/json").unwrap(),
     );
-    (headers, json!(Installation::new()).to_string())
+    let mut installation = Installation::new();
+    if let Some(tokens) = tokens {
+        match tokens.issue(&installation.id) {
+            Ok(token) => installation.token = Some(token),
+            Err(e) => {
+                error!(target: "bosca", "error issuing installation token: {e:?}");
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error issuing installation token".to_owned()));
+            }
+        }
+    }
+    Ok((headers, json!(installation).to_string()))
 }
 
-async fn events(
-    State(writer): State<Arc<EventsWriter>>,
-    headers: HeaderMap,
-    extract::Json(payload): extract::Json<Events>,
-) -> Result<(StatusCode, String), (StatusCode, String)> {
-    let context = EventPipelineContext::new(headers);
-    let mut payload = payload;
-    let now = Utc::now();
-    payload.received = Some(now.timestamp_millis());
-    payload.received_micros = Some(now.timestamp_subsec_micros());
-    writer.write(context, payload).await.map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Error writing payload".to_owned(),
-        )
-    })?;
+async fn index() -> Result<(StatusCode, String), (StatusCode, String)> {
     Ok((StatusCode::OK, "OK".to_owned()))
 }
 
-async fn index() -> Result<(StatusCode, String), (StatusCode, String)> {
+async fn health() -> Result<(StatusCode, String), (StatusCode, String)> {
     Ok((StatusCode::OK, "OK".to_owned()))
 }
 
-async fn health() -> Result<(StatusCode, String), (StatusCode, String)> {
+async fn ready(writer: Arc<EventsWriter>, temp_dir: Option<String>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    if writer.is_stopped() {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "Stopped".to_owned()));
+    }
+    if let Some(temp_dir) = temp_dir {
+        if !is_writable(&temp_dir).await {
+            return Err((StatusCode::SERVICE_UNAVAILABLE, format!("{temp_dir} is not writable")));
+        }
+    }
     Ok((StatusCode::OK, "OK".to_owned()))
 }
 
+async fn metrics(token: Arc<String>, request_headers: HeaderMap) -> Result<(HeaderMap, String), (StatusCode, String)> {
+    if !is_authorized(&token, &request_headers) {
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_owned()));
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        HeaderName::from_str("Content-Type").unwrap(),
+        HeaderValue::from_str("text/plain; version=0.0.4").unwrap(),
+    );
+    let body = METRICS.encode().map_err(|e| {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("Error encoding metrics: {e}"))
+    })?;
+    Ok((headers, body))
+}
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
     structured_logger::Builder::with_level("info")
@@ -176,62 +208,96 @@ async fn main() {
     let _ = provider.tracer("Bosca Analytics");
     let _ = global::set_tracer_provider(provider);
 
-    let forward_url = env::var("FORWARD_URL").unwrap_or("".to_owned());
-    let config = if forward_url.is_empty() {
-        Some(Config {
-            batches_dir: if let Ok(batches_dir) = env::var("BATCHES_DIR") {
-                batches_dir
-            } else {
-                warn!("missing BATCHES_DIR, defaulting to ./analytics/batches");
-                "./analytics/batches".to_owned()
-            },
-            pending_objects_dir: if let Ok(objects_dir) = env::var("PENDING_OBJECTS_DIR") {
-                objects_dir
-            } else {
-                warn!("missing PENDING_OBJECTS_DIR, defaulting to ./analytics/objects");
-                "./analytics/objects".to_owned()
-            },
-            temp_dir: if let Ok(batches_dir) = env::var("TEMP_DIR") {
-                batches_dir
-            } else {
-                warn!("missing TEMP_DIR, defaulting to ./analytics/temp");
-                "./analytics/temp".to_owned()
-            },
-            max_file_size: if let Ok(size) = env::var("MAX_JSON_FILE_SIZE") {
-                if let Ok(size) = size.parse() {
-                    size
-                } else {
-                    warn!("invalid MAX_JSON_FILE_SIZE, defaulting to 250MB");
-                    262144000
-                }
-            } else {
-                warn!("missing MAX_JSON_FILE_SIZE, defaulting to 250MB");
-                262144000
-            },
-            last_full_sync: Arc::new(AtomicI64::new(0)),
-        })
-    } else {
-        None
+    let pipeline = match PipelineConfig::load() {
+        Ok(pipeline) => pipeline,
+        Err(e) => panic!("failed to load pipeline configuration: {e:?}"),
     };
+    let config = pipeline.files.clone();
+
+    if let Some(command) = env::args().nth(1) {
+        match command.as_str() {
+            "compact" => {
+                let prefix = env::args().nth(2);
+                if let Err(e) = compact(&pipeline.compaction, &pipeline.object_storage(), prefix).await {
+                    error!(target: "bosca", "compaction failed: {e:?}");
+                    std::process::exit(1);
+                }
+                info!(target: "bosca", "compaction finished");
+            }
+            "replay" => {
+                let schema = Arc::new(SchemaDefinition::new());
+                let result = match ReplayArgs::parse(env::args().skip(2)) {
+                    Ok(args) => replay(&pipeline, &schema, args).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!(target: "bosca", "replay failed: {e:?}");
+                    std::process::exit(1);
+                }
+                info!(target: "bosca", "replay finished");
+            }
+            "rollup" => {
+                let result = match RollupArgs::parse(env::args().skip(2)) {
+                    Ok(args) => rollup(&pipeline.rollup, &pipeline.object_storage(), args).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!(target: "bosca", "rollup failed: {e:?}");
+                    std::process::exit(1);
+                }
+                info!(target: "bosca", "rollup finished");
+            }
+            _ => {
+                error!(target: "bosca", "unknown command: {command}, expected: compact, replay or rollup");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Err(e) = init_node_id(&pipeline.installation.node_id) {
+        panic!("failed to resolve the installation node id: {e:?}");
+    }
+    let installation_tokens = InstallationTokens::new(&pipeline.installation).map(Arc::new);
 
     let schema = Arc::new(SchemaDefinition::new());
     let writer_schema = Arc::clone(&schema);
-    let writer_config = config.clone();
-    // TODO: make these configurable
-    let writer = Arc::new(
-        EventsWriter::new(8, 10000, move |index| {
-            Ok(if !forward_url.is_empty() {
-                Box::new(HttpSink::new(
-                    vec![Box::new(CloudflareGeoTransform {})],
-                    forward_url.clone(),
-                ))
-            } else {
-                let filepath = find_file(index, writer_config.as_ref().unwrap().clone())?;
-                Box::new(JsonSink::new(Arc::clone(&writer_schema), &filepath, 250).unwrap())
-            })
-        })
-        .await,
-    );
+    let writer_pipeline = pipeline.clone();
+    let writer = match EventsWriter::new(pipeline.pool_size, pipeline.queue_size, pipeline.backpressure.clone(), move |index| {
+        writer_pipeline.new_sink(index, &writer_schema)
+    })
+    .await
+    {
+        Ok(writer) => Arc::new(writer),
+        Err(e) => panic!("failed to create the sinks: {e:?}"),
+    };
+
+    for sink in &pipeline.sinks {
+        if let SinkConfig::Http { url, dead_letter: Some(dead_letter), .. } = sink {
+            let dead_letter_writer = Arc::clone(&writer);
+            let dead_letter_url = url.clone();
+            let dead_letter = dead_letter.clone();
+            tokio::spawn(async {
+                watch_dead_letters(dead_letter_writer, dead_letter_url, dead_letter).await;
+            });
+        }
+    }
+
+    for transform in &pipeline.transforms {
+        if let TransformConfig::Filter(filter) = transform {
+            METRICS.allow_app_ids(filter.app_ids.clone());
+        }
+    }
+
+    for transform in &pipeline.transforms {
+        if let TransformConfig::MaxMindGeo { database, reload_interval, .. } = transform {
+            let database = Arc::clone(database);
+            let reload_interval = *reload_interval;
+            tokio::spawn(async move {
+                watch_geoip_database(database, reload_interval).await;
+            });
+        }
+    }
 
     let watching = Arc::new(AtomicBool::new(false));
     if let Some(config) = config {
@@ -246,10 +312,11 @@ async fn main() {
         let hourly_watch_writer = Arc::clone(&writer);
         let hourly_watch_config = config.clone();
         let hourly_watch_watching = Arc::clone(&watching);
+        let hourly_watch_schema = Arc::clone(&schema);
         tokio::spawn(async {
             watch_files_hourly(
                 hourly_watch_writer,
-                schema,
+                hourly_watch_schema,
                 hourly_watch_config,
                 hourly_watch_watching,
             )
@@ -257,22 +324,59 @@ async fn main() {
         });
     }
 
-    let app = Router::new()
+    let register_tokens = installation_tokens.clone();
+    let ready_writer = Arc::clone(&writer);
+    let ready_temp_dir = pipeline.files.as_ref().map(|files| files.temp_dir.clone());
+    let mut app = Router::new()
         .route("/", get(index))
         .route("/health", get(health))
-        .route("/register", post(register))
-        .route("/events", post(events))
+        .route("/ready", get(move || ready(ready_writer, ready_temp_dir)))
+        .route("/register", post(move || register(register_tokens)))
+        .route(
+            "/events",
+            post(events)
+                .with_state(Arc::new(EventsContext {
+                    writer: Arc::clone(&writer),
+                    validation: pipeline.validation.clone(),
+                    installation_tokens,
+                }))
+                .layer(
+                    ServiceBuilder::new()
+                        .layer(RequestDecompressionLayer::new())
+                        // checked as the handler reads the body, which is after it's decompressed
+                        .layer(DefaultBodyLimit::max(pipeline.validation.max_body_size)),
+                ),
+        );
+    // the counts and queue depths aren't for whoever can post events
+    if let Some(metrics_token) = pipeline.metrics_token.clone() {
+        let metrics_token = Arc::new(metrics_token);
+        app = app.route("/metrics", get(move |headers| metrics(metrics_token, headers)));
+    } else {
+        info!(target: "bosca", "/metrics is disabled, no metrics token is configured");
+    }
+    if let Some(query_config) = pipeline.query.clone() {
+        let context = Arc::new(QueryContext {
+            config: query_config,
+            files: pipeline.files.clone(),
+            object_storage: pipeline.object_storage(),
+            schema: Arc::clone(&schema),
+        });
+        app = app.route("/query", post(query).with_state(context));
+    }
+    let app = app
         .layer(CorsLayer::new()
             .allow_methods([Method::GET, Method::POST])
             .allow_headers(Any)
             .allow_origin(Any))
         .layer(DefaultBodyLimit::disable())
-        .layer(TimeoutLayer::new(Duration::from_secs(600)))
-        .with_state(Arc::clone(&writer));
+        .layer(TimeoutLayer::new(Duration::from_secs(600)));
 
     info!(target: "bosca", "Listening on http://0.0.0.0:8009");
 
-    axum::serve(TcpListener::bind("0.0.0.0:8009").await.unwrap(), app)
+    axum::serve(
+        TcpListener::bind("0.0.0.0:8009").await.unwrap(),
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
         .with_graceful_shutdown(shutdown_hook(writer, watching))
         .await
         .unwrap();
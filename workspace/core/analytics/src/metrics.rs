@@ -0,0 +1,134 @@
+use std::error::Error;
+use std::sync::{LazyLock, OnceLock};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use crate::events::Events;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics::new().expect("failed to register metrics"));
+
+/// The `app_id` label of apps that aren't allowed, so a client can't add a series per app id it makes up.
+const OTHER_APP_ID: &str = "other";
+
+/// The labels of a payload, taken before it is handed off to the writer.
+pub struct EventLabels {
+    app_id: String,
+    event_types: Vec<String>,
+}
+
+impl From<&Events> for EventLabels {
+    fn from(events: &Events) -> Self {
+        Self {
+            app_id: METRICS.app_id_label(&events.context.app_id).to_owned(),
+            event_types: events.events.iter().map(|e| format!("{:?}", e.event_type)).collect(),
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// App ids that get a label of their own, the filter transform's `app_ids`.
+    app_ids: OnceLock<Vec<String>>,
+    pub events_received: IntCounterVec,
+    pub events_accepted: IntCounterVec,
+    pub events_rejected: IntCounterVec,
+    pub events_duplicate: IntCounterVec,
+    pub events_filtered: IntCounterVec,
+    pub sink_add_seconds: HistogramVec,
+    pub sink_flush_seconds: HistogramVec,
+    pub sink_errors: IntCounterVec,
+    pub worker_queue_depth: IntGaugeVec,
+    pub json_pending_bytes: IntGauge,
+    pub objects_pending_upload: IntGauge,
+    pub upload_failures: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("bosca_analytics".to_owned()), None)?;
+        let metrics = Self {
+            events_received: IntCounterVec::new(
+                Opts::new("events_received_total", "Events received on /events"),
+                &["event_type", "app_id"],
+            )?,
+            events_accepted: IntCounterVec::new(
+                Opts::new("events_accepted_total", "Events queued for the sinks"),
+                &["event_type", "app_id"],
+            )?,
+            events_rejected: IntCounterVec::new(
+                Opts::new("events_rejected_total", "Events that were rejected"),
+                &["event_type", "app_id", "reason"],
+            )?,
+            events_duplicate: IntCounterVec::new(
+                Opts::new("events_duplicate_total", "Duplicate events that were dropped"),
+                &["event_type", "app_id"],
+            )?,
+            events_filtered: IntCounterVec::new(
+                Opts::new("events_filtered_total", "Events matched by the filter transform"),
+                &["reason", "app_id"],
+            )?,
+            sink_add_seconds: HistogramVec::new(
+                HistogramOpts::new("sink_add_seconds", "Time spent adding events to a sink"),
+                &["sink"],
+            )?,
+            sink_flush_seconds: HistogramVec::new(
+                HistogramOpts::new("sink_flush_seconds", "Time spent flushing a sink"),
+                &["sink"],
+            )?,
+            sink_errors: IntCounterVec::new(
+                Opts::new("sink_errors_total", "Errors returned by a sink"),
+                &["sink", "operation"],
+            )?,
+            worker_queue_depth: IntGaugeVec::new(
+                Opts::new("worker_queue_depth", "Payloads waiting in a writer worker's queue"),
+                &["worker"],
+            )?,
+            json_pending_bytes: IntGauge::new("json_pending_bytes", "Bytes of JSON waiting to be converted to parquet")?,
+            objects_pending_upload: IntGauge::new("objects_pending_upload", "Parquet files waiting to be uploaded")?,
+            upload_failures: IntCounter::new("upload_failures_total", "Parquet uploads that failed")?,
+            registry,
+            app_ids: OnceLock::new(),
+        };
+        metrics.registry.register(Box::new(metrics.events_received.clone()))?;
+        metrics.registry.register(Box::new(metrics.events_accepted.clone()))?;
+        metrics.registry.register(Box::new(metrics.events_rejected.clone()))?;
+        metrics.registry.register(Box::new(metrics.events_duplicate.clone()))?;
+        metrics.registry.register(Box::new(metrics.events_filtered.clone()))?;
+        metrics.registry.register(Box::new(metrics.sink_add_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.sink_flush_seconds.clone()))?;
+        metrics.registry.register(Box::new(metrics.sink_errors.clone()))?;
+        metrics.registry.register(Box::new(metrics.worker_queue_depth.clone()))?;
+        metrics.registry.register(Box::new(metrics.json_pending_bytes.clone()))?;
+        metrics.registry.register(Box::new(metrics.objects_pending_upload.clone()))?;
+        metrics.registry.register(Box::new(metrics.upload_failures.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Sets the app ids that are labelled as themselves, every other app id is labelled `other`.
+    pub fn allow_app_ids(&self, app_ids: Vec<String>) {
+        let _ = self.app_ids.set(app_ids);
+    }
+
+    pub fn app_id_label<'a>(&self, app_id: &'a str) -> &'a str {
+        match self.app_ids.get() {
+            Some(app_ids) if app_ids.iter().any(|allowed| allowed == app_id) => app_id,
+            _ => OTHER_APP_ID,
+        }
+    }
+
+    /// Counts every event against a counter labelled by event type and app id (plus any extra labels).
+    pub fn count_events(&self, counter: &IntCounterVec, events: &EventLabels, labels: &[&str]) {
+        for event_type in &events.event_types {
+            let mut values = vec![event_type.as_str(), events.app_id.as_str()];
+            values.extend_from_slice(labels);
+            counter.with_label_values(&values).inc();
+        }
+    }
+
+    pub fn encode(&self) -> Result<String, Box<dyn Error>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
@@ -0,0 +1,469 @@
+use std::env;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use ipnetwork::IpNetwork;
+use log::info;
+use yaml_rust2::{Yaml, YamlLoader};
+use crate::compaction::CompactionConfig;
+use crate::events_sink::EventSink;
+use crate::events_transform::EventTransform;
+use crate::installation::InstallationConfig;
+use crate::query::QueryConfig;
+use crate::rollup::RollupConfig;
+use crate::transforms::cloudflare_geo::CloudflareGeoTransform;
+use crate::transforms::filter::{FilterConfig, FilterTransform};
+use crate::transforms::maxmind_geo::{GeoIpDatabase, MaxMindGeoTransform};
+use crate::transforms::privacy::{PrivacyConfig, PrivacyTransform};
+use crate::transforms::user_agent::UserAgentTransform;
+use crate::writers::arrow::json::sink::JsonSink;
+use crate::writers::arrow::partition::PartitionKey;
+use crate::writers::arrow::schema::SchemaDefinition;
+use crate::writers::dedup_sink::{DedupConfig, DedupSink};
+use crate::writers::files::{find_file, Config};
+use crate::writers::http::dead_letter::DeadLetterConfig;
+use crate::writers::http::sink::{HttpRetryConfig, HttpSink};
+use crate::writers::metered_sink::MeteredSink;
+use crate::writers::multi_sink::MultiSink;
+use crate::writers::object_storage::{ObjectStorageConfig, DEFAULT_OBJECT_KEY_TEMPLATE};
+use crate::writers::state::WatcherStateStore;
+use crate::validation::ValidationConfig;
+use crate::writers::writer::BackpressureConfig;
+
+const DEFAULT_POOL_SIZE: usize = 8;
+const DEFAULT_QUEUE_SIZE: usize = 10000;
+const DEFAULT_JSON_BATCH_SIZE: usize = 250;
+const DEFAULT_MAX_FILE_SIZE: u64 = 262144000;
+const DEFAULT_REPLAY_INTERVAL_SECS: u64 = 60;
+const DEFAULT_GEOIP_RELOAD_INTERVAL_SECS: u64 = 300;
+
+fn metrics_token_from_env() -> Option<String> {
+    env::var("METRICS_TOKEN").ok().filter(|t| !t.is_empty())
+}
+
+/// A whole number setting of at least `min`, none when it isn't set. Negative values are rejected
+/// rather than wrapping around.
+fn number<T: TryFrom<i64>>(yaml: &Yaml, name: &str, min: i64) -> Result<Option<T>, Box<dyn Error>> {
+    let value = &yaml[name];
+    if value.is_badvalue() || value.is_null() {
+        return Ok(None);
+    }
+    let Some(number) = value.as_i64() else {
+        return Err(format!("{name} must be a whole number").into());
+    };
+    if number < min {
+        return Err(format!("{name} must be at least {min}, not {number}").into());
+    }
+    match T::try_from(number) {
+        Ok(number) => Ok(Some(number)),
+        Err(_) => Err(format!("{name} is too large: {number}").into()),
+    }
+}
+
+#[derive(Clone)]
+pub enum TransformConfig {
+    CloudflareGeo,
+    Filter(FilterConfig),
+    MaxMindGeo {
+        database: Arc<GeoIpDatabase>,
+        trusted_proxies: Vec<IpNetwork>,
+        reload_interval: Duration,
+    },
+    Privacy(PrivacyConfig),
+    UserAgent,
+}
+
+#[derive(Clone)]
+pub enum SinkConfig {
+    Http {
+        url: String,
+        retry: HttpRetryConfig,
+        dead_letter: Option<DeadLetterConfig>,
+    },
+    Json { batch_size: usize },
+}
+
+#[derive(Clone)]
+pub struct PipelineConfig {
+    pub pool_size: usize,
+    pub queue_size: usize,
+    pub backpressure: BackpressureConfig,
+    pub transforms: Vec<TransformConfig>,
+    pub sinks: Vec<SinkConfig>,
+    pub files: Option<Config>,
+    pub compaction: CompactionConfig,
+    pub query: Option<QueryConfig>,
+    pub validation: ValidationConfig,
+    pub dedup: Option<DedupConfig>,
+    pub installation: InstallationConfig,
+    pub rollup: RollupConfig,
+    /// GET /metrics is only served when a token is configured, it's checked like the /query one.
+    pub metrics_token: Option<String>,
+}
+
+impl TransformConfig {
+    /// The `type` the transform is configured with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransformConfig::CloudflareGeo => "cloudflare_geo",
+            TransformConfig::Filter(_) => "filter",
+            TransformConfig::MaxMindGeo { .. } => "maxmind_geo",
+            TransformConfig::Privacy(_) => "privacy",
+            TransformConfig::UserAgent => "user_agent",
+        }
+    }
+}
+
+impl TryFrom<&Yaml> for TransformConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        match yaml["type"].as_str() {
+            Some("cloudflare_geo") => Ok(TransformConfig::CloudflareGeo),
+            Some("filter") => Ok(TransformConfig::Filter(FilterConfig::try_from(yaml)?)),
+            Some("maxmind_geo") => {
+                let Some(path) = yaml["database"].as_str() else {
+                    return Err("maxmind_geo transform is missing a database".into());
+                };
+                let mut trusted_proxies = Vec::new();
+                if let Some(proxies) = yaml["trusted_proxies"].as_vec() {
+                    for proxy in proxies {
+                        let Some(proxy) = proxy.as_str() else {
+                            return Err("trusted proxies must be strings".into());
+                        };
+                        trusted_proxies.push(IpNetwork::from_str(proxy)?);
+                    }
+                }
+                Ok(TransformConfig::MaxMindGeo {
+                    database: Arc::new(GeoIpDatabase::open(path)?),
+                    trusted_proxies,
+                    reload_interval: Duration::from_secs(
+                        number(yaml, "reload_interval_secs", 1)?.unwrap_or(DEFAULT_GEOIP_RELOAD_INTERVAL_SECS),
+                    ),
+                })
+            }
+            Some("privacy") => Ok(TransformConfig::Privacy(PrivacyConfig::try_from(yaml)?)),
+            Some("user_agent") => Ok(TransformConfig::UserAgent),
+            Some(name) => Err(format!("unknown transform type: {name}").into()),
+            None => Err("transform is missing a type".into()),
+        }
+    }
+}
+
+impl TryFrom<&Yaml> for SinkConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        match yaml["type"].as_str() {
+            Some("http") => {
+                let Some(url) = yaml["url"].as_str() else {
+                    return Err("http sink is missing a url".into());
+                };
+                let defaults = HttpRetryConfig::default();
+                let retry = &yaml["retry"];
+                let dead_letter = &yaml["dead_letter"];
+                Ok(SinkConfig::Http {
+                    url: url.to_owned(),
+                    retry: HttpRetryConfig {
+                        max_attempts: number(retry, "max_attempts", 1)?.unwrap_or(defaults.max_attempts),
+                        initial_backoff: number(retry, "initial_backoff_ms", 0)?
+                            .map(Duration::from_millis)
+                            .unwrap_or(defaults.initial_backoff),
+                        max_backoff: number(retry, "max_backoff_ms", 0)?
+                            .map(Duration::from_millis)
+                            .unwrap_or(defaults.max_backoff),
+                    },
+                    dead_letter: match dead_letter["dir"].as_str() {
+                        Some(dir) => Some(DeadLetterConfig {
+                            dir: dir.to_owned(),
+                            replay_interval: Duration::from_secs(
+                                number(dead_letter, "replay_interval_secs", 1)?.unwrap_or(DEFAULT_REPLAY_INTERVAL_SECS),
+                            ),
+                        }),
+                        None => None,
+                    },
+                })
+            }
+            Some("json") => Ok(SinkConfig::Json {
+                batch_size: number(yaml, "batch_size", 1)?.unwrap_or(DEFAULT_JSON_BATCH_SIZE),
+            }),
+            Some(name) => Err(format!("unknown sink type: {name}").into()),
+            None => Err("sink is missing a type".into()),
+        }
+    }
+}
+
+fn files_from_yaml(yaml: &Yaml) -> Result<Config, Box<dyn Error>> {
+    Ok(Config {
+        temp_dir: yaml["temp_dir"]
+            .as_str()
+            .unwrap_or("./analytics/temp")
+            .to_owned(),
+        batches_dir: yaml["batches_dir"]
+            .as_str()
+            .unwrap_or("./analytics/batches")
+            .to_owned(),
+        pending_objects_dir: yaml["pending_objects_dir"]
+            .as_str()
+            .unwrap_or("./analytics/objects")
+            .to_owned(),
+        max_file_size: number(yaml, "max_file_size", 1)?.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+        object_storage: if yaml["object_storage"].is_badvalue() {
+            ObjectStorageConfig::from_env()
+        } else {
+            ObjectStorageConfig::try_from(&yaml["object_storage"])?
+        },
+        object_key_template: yaml["object_key_template"]
+            .as_str()
+            .unwrap_or(DEFAULT_OBJECT_KEY_TEMPLATE)
+            .to_owned(),
+        partition_keys: match yaml["partition_keys"].as_vec() {
+            Some(keys) => {
+                let mut partition_keys = Vec::new();
+                for key in keys {
+                    let Some(key) = key.as_str() else {
+                        return Err("partition keys must be strings".into());
+                    };
+                    partition_keys.push(PartitionKey::try_from(key)?);
+                }
+                partition_keys
+            }
+            None => Vec::new(),
+        },
+        state: Arc::new(WatcherStateStore::load(
+            yaml["state_file"].as_str().unwrap_or("./analytics/state.json"),
+        )),
+    })
+}
+
+impl PipelineConfig {
+    /// Loads the pipeline from the file named by `PIPELINE_CONFIG`, falling back to
+    /// the environment based configuration when it isn't set.
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        match env::var("PIPELINE_CONFIG") {
+            Ok(path) => {
+                info!(target: "bosca", "loading pipeline configuration: {path}");
+                let contents = std::fs::read_to_string(&path)?;
+                Self::parse(&contents)
+            }
+            Err(_) => Self::from_env(),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn Error>> {
+        let docs = YamlLoader::load_from_str(contents)?;
+        let Some(yaml) = docs.first() else {
+            return Err("empty pipeline configuration".into());
+        };
+        let mut transforms = Vec::new();
+        if let Some(items) = yaml["transforms"].as_vec() {
+            for item in items {
+                transforms.push(TransformConfig::try_from(item)?);
+            }
+        }
+        let mut sinks = Vec::new();
+        if let Some(items) = yaml["sinks"].as_vec() {
+            for item in items {
+                sinks.push(SinkConfig::try_from(item)?);
+            }
+        }
+        if sinks.is_empty() {
+            return Err("pipeline configuration must have at least one sink".into());
+        }
+        let backpressure = BackpressureConfig::default();
+        let workers = &yaml["workers"];
+        let has_json = sinks.iter().any(|s| matches!(s, SinkConfig::Json { .. }));
+        let files = if !has_json {
+            None
+        } else if yaml["files"].is_badvalue() {
+            Some(Config::from_env())
+        } else {
+            Some(files_from_yaml(&yaml["files"])?)
+        };
+        Ok(Self {
+            pool_size: number(workers, "pool_size", 1)?.unwrap_or(DEFAULT_POOL_SIZE),
+            queue_size: number(workers, "queue_size", 1)?.unwrap_or(DEFAULT_QUEUE_SIZE),
+            backpressure: BackpressureConfig {
+                max_in_flight: number(workers, "max_in_flight", 1)?.unwrap_or(backpressure.max_in_flight),
+                acquire_timeout: number(workers, "acquire_timeout_ms", 0)?
+                    .map(Duration::from_millis)
+                    .unwrap_or(backpressure.acquire_timeout),
+                retry_after: number(workers, "retry_after_secs", 0)?
+                    .map(Duration::from_secs)
+                    .unwrap_or(backpressure.retry_after),
+            },
+            transforms,
+            sinks,
+            files,
+            compaction: if yaml["compaction"].is_badvalue() {
+                CompactionConfig::from_env()
+            } else {
+                CompactionConfig::from(&yaml["compaction"])
+            },
+            query: if yaml["query"].is_badvalue() {
+                QueryConfig::from_env()
+            } else {
+                QueryConfig::from_yaml(&yaml["query"])
+            },
+            validation: if yaml["validation"].is_badvalue() {
+                ValidationConfig::default()
+            } else {
+                ValidationConfig::try_from(&yaml["validation"])?
+            },
+            dedup: if yaml["dedup"].is_badvalue() {
+                None
+            } else {
+                Some(DedupConfig::try_from(&yaml["dedup"])?)
+            },
+            installation: if yaml["installation"].is_badvalue() {
+                InstallationConfig::from_env()?
+            } else {
+                InstallationConfig::try_from(&yaml["installation"])?
+            },
+            rollup: if yaml["rollup"].is_badvalue() {
+                RollupConfig::from_env()
+            } else {
+                RollupConfig::from(&yaml["rollup"])
+            },
+            metrics_token: if yaml["metrics"].is_badvalue() {
+                metrics_token_from_env()
+            } else {
+                yaml["metrics"]["token"].as_str().filter(|t| !t.is_empty()).map(|t| t.to_owned())
+            },
+        })
+    }
+
+    /// The original topology: forward to `FORWARD_URL` when set, otherwise archive to JSON.
+    /// Geo comes from the Cloudflare headers unless `GEOIP_DATABASE` points at a local database.
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let forward_url = env::var("FORWARD_URL").unwrap_or("".to_owned());
+        let geo = match env::var("GEOIP_DATABASE") {
+            Ok(path) => {
+                let mut trusted_proxies = Vec::new();
+                for proxy in env::var("GEOIP_TRUSTED_PROXIES").unwrap_or_default().split(',') {
+                    let proxy = proxy.trim();
+                    if !proxy.is_empty() {
+                        trusted_proxies.push(IpNetwork::from_str(proxy)?);
+                    }
+                }
+                Some(TransformConfig::MaxMindGeo {
+                    database: Arc::new(GeoIpDatabase::open(&path)?),
+                    trusted_proxies,
+                    reload_interval: Duration::from_secs(
+                        env::var("GEOIP_RELOAD_INTERVAL_SECS")
+                            .ok()
+                            .and_then(|s| s.parse().ok())
+                            .unwrap_or(DEFAULT_GEOIP_RELOAD_INTERVAL_SECS),
+                    ),
+                })
+            }
+            Err(_) => None,
+        };
+        if forward_url.is_empty() {
+            Ok(Self {
+                pool_size: DEFAULT_POOL_SIZE,
+                queue_size: DEFAULT_QUEUE_SIZE,
+                backpressure: BackpressureConfig::default(),
+                transforms: geo.into_iter().collect(),
+                sinks: vec![SinkConfig::Json {
+                    batch_size: DEFAULT_JSON_BATCH_SIZE,
+                }],
+                files: Some(Config::from_env()),
+                compaction: CompactionConfig::from_env(),
+                query: QueryConfig::from_env(),
+                validation: ValidationConfig::default(),
+                dedup: None,
+                installation: InstallationConfig::from_env()?,
+                rollup: RollupConfig::from_env(),
+                metrics_token: metrics_token_from_env(),
+            })
+        } else {
+            Ok(Self {
+                pool_size: DEFAULT_POOL_SIZE,
+                queue_size: DEFAULT_QUEUE_SIZE,
+                backpressure: BackpressureConfig::default(),
+                transforms: vec![geo.unwrap_or(TransformConfig::CloudflareGeo)],
+                sinks: vec![SinkConfig::Http {
+                    url: forward_url,
+                    retry: HttpRetryConfig::default(),
+                    dead_letter: env::var("FORWARD_DEAD_LETTER_DIR")
+                        .ok()
+                        .map(|dir| DeadLetterConfig {
+                            dir,
+                            replay_interval: Duration::from_secs(DEFAULT_REPLAY_INTERVAL_SECS),
+                        }),
+                }],
+                files: None,
+                compaction: CompactionConfig::from_env(),
+                query: QueryConfig::from_env(),
+                validation: ValidationConfig::default(),
+                dedup: None,
+                installation: InstallationConfig::from_env()?,
+                rollup: RollupConfig::from_env(),
+                metrics_token: metrics_token_from_env(),
+            })
+        }
+    }
+
+    /// The object storage uploads go to, used by the batch jobs that read them back.
+    pub fn object_storage(&self) -> ObjectStorageConfig {
+        match &self.files {
+            Some(files) => files.object_storage.clone(),
+            None => ObjectStorageConfig::from_env(),
+        }
+    }
+
+    pub fn new_transforms(&self) -> Vec<Box<dyn EventTransform + Send + Sync + 'static>> {
+        self.transforms
+            .iter()
+            .map(|transform| -> Box<dyn EventTransform + Send + Sync + 'static> {
+                match transform {
+                    TransformConfig::CloudflareGeo => Box::new(CloudflareGeoTransform {}),
+                    TransformConfig::Filter(config) => Box::new(FilterTransform::new(config.clone())),
+                    TransformConfig::MaxMindGeo { database, trusted_proxies, .. } => Box::new(MaxMindGeoTransform {
+                        database: Arc::clone(database),
+                        trusted_proxies: trusted_proxies.clone(),
+                    }),
+                    TransformConfig::Privacy(config) => Box::new(PrivacyTransform::new(config.clone())),
+                    TransformConfig::UserAgent => Box::new(UserAgentTransform::new()),
+                }
+            })
+            .collect()
+    }
+
+    pub fn new_sink(
+        &self,
+        index: usize,
+        schema: &Arc<SchemaDefinition>,
+    ) -> Result<Box<dyn EventSink + Send + Sync>, Box<dyn Error>> {
+        let mut sinks: Vec<Box<dyn EventSink + Send + Sync + 'static>> = Vec::new();
+        for (position, sink) in self.sinks.iter().enumerate() {
+            // metrics are labelled by sink type and position, e.g. json-1
+            match sink {
+                SinkConfig::Http { url, retry, dead_letter } => {
+                    let sink = HttpSink::new(vec![], url.clone(), retry.clone(), dead_letter.clone());
+                    sinks.push(Box::new(MeteredSink::new(format!("http-{position}"), Box::new(sink))));
+                }
+                SinkConfig::Json { batch_size } => {
+                    let Some(files) = &self.files else {
+                        return Err("json sink requires a files configuration".into());
+                    };
+                    let filepath = find_file(index, files.clone())?;
+                    let sink = JsonSink::new(Arc::clone(schema), &filepath, *batch_size)?;
+                    sinks.push(Box::new(MeteredSink::new(format!("json-{position}"), Box::new(sink))));
+                }
+            }
+        }
+        let sink: Box<dyn EventSink + Send + Sync> = if sinks.len() == 1 && self.transforms.is_empty() {
+            sinks.pop().unwrap()
+        } else {
+            Box::new(MultiSink::new(self.new_transforms(), sinks))
+        };
+        // dedup runs first so the keys are built from the ids as they were sent
+        match &self.dedup {
+            Some(dedup) => Ok(Box::new(DedupSink::new(dedup.clone(), sink))),
+            None => Ok(sink),
+        }
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use arrow::array::{Array, AsArray, RecordBatch};
+use arrow::datatypes::TimestampMillisecondType;
+use arrow::json::ReaderBuilder;
+use axum::body::to_bytes;
+use axum::extract::{Request, State};
+use axum::Json;
+use bytes::Bytes;
+use chrono::DateTime;
+use http::{HeaderMap, StatusCode};
+use log::{error, warn};
+use object_store::path::Path;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
+use parquet::file::reader::ChunkReader;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use yaml_rust2::Yaml;
+use crate::writers::arrow::partition::may_contain;
+use crate::writers::arrow::schema::SchemaDefinition;
+use crate::writers::files::Config;
+use crate::writers::object_storage::{new_object_storage, ObjectStorageConfig};
+
+const QUERY_COLUMNS: [&str; 5] = ["client_id", "context", "created", "type", "element"];
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_MAX_ROWS: u64 = 50_000_000;
+
+#[derive(Clone)]
+pub struct QueryConfig {
+    pub token: String,
+    pub prefix: String,
+    /// Bytes of files a query may read, queries over more are rejected before anything is read.
+    pub max_bytes: u64,
+    /// Rows a query may scan before it's stopped.
+    pub max_rows: u64,
+}
+
+impl QueryConfig {
+    /// The query endpoint is only enabled when a token is configured.
+    pub fn from_env() -> Option<Self> {
+        env::var("QUERY_TOKEN").ok().filter(|t| !t.is_empty()).map(|token| Self {
+            token,
+            prefix: env::var("QUERY_PREFIX").unwrap_or("ingest/raw".to_owned()),
+            max_bytes: env::var("QUERY_MAX_BYTES")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_MAX_BYTES),
+            max_rows: env::var("QUERY_MAX_ROWS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_MAX_ROWS),
+        })
+    }
+
+    pub fn from_yaml(yaml: &Yaml) -> Option<Self> {
+        yaml["token"].as_str().filter(|t| !t.is_empty()).map(|token| Self {
+            token: token.to_owned(),
+            prefix: yaml["prefix"].as_str().unwrap_or("ingest/raw").to_owned(),
+            max_bytes: yaml["max_bytes"].as_i64().map(|b| b.max(0) as u64).unwrap_or(DEFAULT_MAX_BYTES),
+            max_rows: yaml["max_rows"].as_i64().map(|r| r.max(0) as u64).unwrap_or(DEFAULT_MAX_ROWS),
+        })
+    }
+}
+
+/// The query would read more than the configured limits, it's reported back as a bad request.
+#[derive(Debug)]
+struct QueryLimitExceeded(String);
+
+impl Display for QueryLimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, narrow it with from and to", self.0)
+    }
+}
+
+impl Error for QueryLimitExceeded {}
+
+pub struct QueryContext {
+    pub config: QueryConfig,
+    pub files: Option<Config>,
+    pub object_storage: ObjectStorageConfig,
+    pub schema: Arc<SchemaDefinition>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryGroup {
+    AppId,
+    ContentId,
+    ContentType,
+    Day,
+    ElementId,
+    EventType,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuerySource {
+    Local,
+    Archive,
+}
+
+#[derive(Deserialize, Default, Clone)]
+pub struct QueryFilters {
+    pub app_id: Option<String>,
+    pub content_id: Option<String>,
+    pub element_id: Option<String>,
+    pub event_type: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct QueryRequest {
+    #[serde(default)]
+    pub group_by: Vec<QueryGroup>,
+    #[serde(default)]
+    pub filters: QueryFilters,
+    /// Inclusive lower bound on `created`, in milliseconds.
+    pub from: Option<i64>,
+    /// Exclusive upper bound on `created`, in milliseconds.
+    pub to: Option<i64>,
+    pub sources: Option<Vec<QuerySource>>,
+}
+
+#[derive(Serialize)]
+pub struct QueryRow {
+    pub group: BTreeMap<QueryGroup, String>,
+    pub count: u64,
+    pub distinct_clients: u64,
+}
+
+#[derive(Serialize)]
+pub struct QueryResponse {
+    pub rows: Vec<QueryRow>,
+    pub files: usize,
+}
+
+#[derive(Default)]
+struct Aggregate {
+    count: u64,
+    clients: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Aggregates {
+    groups: BTreeMap<Vec<String>, Aggregate>,
+    files: usize,
+    rows: u64,
+    max_rows: u64,
+}
+
+impl Aggregates {
+    fn is_full(&self) -> bool {
+        self.rows > self.max_rows
+    }
+
+    fn add_batch(&mut self, request: &QueryRequest, batch: &RecordBatch) -> Result<(), Box<dyn Error>> {
+        self.rows += batch.num_rows() as u64;
+        if self.is_full() {
+            return Ok(());
+        }
+        let (Some(client_ids), Some(context), Some(created), Some(event_types), Some(element)) = (
+            batch.column_by_name("client_id"),
+            batch.column_by_name("context"),
+            batch.column_by_name("created"),
+            batch.column_by_name("type"),
+            batch.column_by_name("element"),
+        ) else {
+            return Err("batch is missing query columns".into());
+        };
+        let client_ids = client_ids.as_string::<i32>();
+        let created = created.as_primitive::<TimestampMillisecondType>();
+        let event_types = event_types.as_string::<i32>();
+        let context = context.as_struct();
+        let Some(app_ids) = context.column_by_name("app_id") else {
+            return Err("batch is missing context.app_id".into());
+        };
+        let app_ids = app_ids.as_string::<i32>();
+        // files written before events could be tagged have no filter_reason
+        let filter_reasons = context.column_by_name("filter_reason").map(|reasons| reasons.as_string::<i32>());
+        let element = element.as_struct();
+        let (Some(element_ids), Some(contents)) = (element.column_by_name("id"), element.column_by_name("content")) else {
+            return Err("batch is missing element columns".into());
+        };
+        let element_ids = element_ids.as_string::<i32>();
+        let contents = contents.as_list::<i32>();
+        let filters = &request.filters;
+        let by_content = request.group_by.iter().any(|g| *g == QueryGroup::ContentId || *g == QueryGroup::ContentType);
+
+        for row in 0..batch.num_rows() {
+            let created = created.value(row);
+            if request.from.map(|from| created < from).unwrap_or(false) || request.to.map(|to| created >= to).unwrap_or(false) {
+                continue;
+            }
+            // events the filter tagged rather than dropped are left out, as they are from the rollups
+            if filter_reasons.map(|reasons| reasons.is_valid(row)).unwrap_or(false) {
+                continue;
+            }
+            let event_type = event_types.value(row);
+            let app_id = app_ids.value(row);
+            let element_id = element_ids.value(row);
+            if filters.event_type.as_ref().map(|f| !f.eq_ignore_ascii_case(event_type)).unwrap_or(false)
+                || filters.app_id.as_ref().map(|f| f != app_id).unwrap_or(false)
+                || filters.element_id.as_ref().map(|f| f != element_id).unwrap_or(false)
+            {
+                continue;
+            }
+            let mut content: Vec<(String, String)> = Vec::new();
+            if contents.is_valid(row) {
+                let items = contents.value(row);
+                let items = items.as_struct();
+                if let (Some(ids), Some(types)) = (items.column_by_name("id"), items.column_by_name("type")) {
+                    let ids = ids.as_string::<i32>();
+                    let types = types.as_string::<i32>();
+                    for i in 0..items.len() {
+                        content.push((ids.value(i).to_owned(), types.value(i).to_owned()));
+                    }
+                }
+            }
+            if let Some(content_id) = &filters.content_id {
+                content.retain(|(id, _)| id == content_id);
+                if content.is_empty() {
+                    continue;
+                }
+            }
+            let day = DateTime::from_timestamp_millis(created)
+                .map(|d| d.format("%Y-%m-%d").to_string())
+                .unwrap_or_default();
+            let client_id = client_ids.value(row);
+            // grouping by content counts the event once for every piece of content it references
+            let items = if by_content { content } else { vec![(String::new(), String::new())] };
+            for (content_id, content_type) in items {
+                let key = request
+                    .group_by
+                    .iter()
+                    .map(|group| match group {
+                        QueryGroup::AppId => app_id.to_owned(),
+                        QueryGroup::ContentId => content_id.clone(),
+                        QueryGroup::ContentType => content_type.clone(),
+                        QueryGroup::Day => day.clone(),
+                        QueryGroup::ElementId => element_id.to_owned(),
+                        QueryGroup::EventType => event_type.to_owned(),
+                    })
+                    .collect();
+                let aggregate = self.groups.entry(key).or_default();
+                aggregate.count += 1;
+                if !aggregate.clients.contains(client_id) {
+                    aggregate.clients.insert(client_id.to_owned());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_parquet<T: ChunkReader + 'static>(&mut self, request: &QueryRequest, reader: T) -> Result<(), Box<dyn Error>> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(reader)?;
+        let indices: Vec<usize> = builder
+            .schema()
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| QUERY_COLUMNS.contains(&f.name().as_str()))
+            .map(|(i, _)| i)
+            .collect();
+        let mask = ProjectionMask::roots(builder.parquet_schema(), indices);
+        for batch in builder.with_projection(mask).build()? {
+            self.add_batch(request, &batch?)?;
+            if self.is_full() {
+                break;
+            }
+        }
+        self.files += 1;
+        Ok(())
+    }
+
+    fn add_json(&mut self, request: &QueryRequest, schema: &SchemaDefinition, file: File) -> Result<(), Box<dyn Error>> {
+        let reader = ReaderBuilder::new(Arc::clone(&schema.schema)).build(BufReader::new(file))?;
+        for batch in reader {
+            if self.is_full() {
+                break;
+            }
+            match batch {
+                Ok(batch) => self.add_batch(request, &batch)?,
+                Err(e) => {
+                    // the file is still being written, the last line may be incomplete
+                    warn!("stopping json query scan early: {e:?}");
+                    break;
+                }
+            }
+        }
+        self.files += 1;
+        Ok(())
+    }
+}
+
+fn local_files(dir: &str, extension: &str, files: &mut Vec<String>) {
+    let Ok(read) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in read.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = format!("{dir}/{}", entry.file_name().to_string_lossy());
+        if file_type.is_dir() {
+            local_files(&path, extension, files);
+        } else if file_type.is_file() && path.ends_with(extension) {
+            files.push(path);
+        }
+    }
+}
+
+/// None when the file is gone, converted or uploaded since it was listed, its events are read from
+/// where they went if that was listed too.
+fn open_local(file_name: &str) -> Result<Option<File>, String> {
+    match File::open(file_name) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("{file_name}: {e}")),
+    }
+}
+
+fn local_file_size(file_name: &str) -> u64 {
+    std::fs::metadata(file_name).map(|m| m.len()).unwrap_or(0)
+}
+
+async fn run_query(context: Arc<QueryContext>, request: QueryRequest) -> Result<QueryResponse, Box<dyn Error>> {
+    let sources = request.sources.clone().unwrap_or(vec![QuerySource::Local, QuerySource::Archive]);
+    let config = &context.config;
+    let mut aggregates = Aggregates {
+        max_rows: config.max_rows,
+        ..Default::default()
+    };
+
+    // everything to read is sized up front, so an oversized query is turned away before reading any of it
+    let mut json_files = Vec::new();
+    let mut parquet_files = Vec::new();
+    if sources.contains(&QuerySource::Local) {
+        if let Some(files) = &context.files {
+            local_files(&files.temp_dir, ".json", &mut json_files);
+            local_files(&files.pending_objects_dir, ".parquet", &mut parquet_files);
+            parquet_files.retain(|file_name| may_contain(file_name, request.from, request.to));
+        }
+    }
+    let mut objects = Vec::new();
+    let storage = if sources.contains(&QuerySource::Archive) {
+        Some(new_object_storage(&context.object_storage)?)
+    } else {
+        None
+    };
+    if let Some(storage) = &storage {
+        for object in storage.list(&Path::parse(&config.prefix)?).await? {
+            let location = object.location.to_string();
+            if location.ends_with(".parquet") && may_contain(&location, request.from, request.to) {
+                objects.push(object);
+            }
+        }
+    }
+    // the watcher keeps converting and uploading while the files are listed, a conversion that already
+    // moved its parquet into place or an upload that already reached the archive is only counted once
+    if let Some(files) = &context.files {
+        let state = files.state.get();
+        if let Some(conversion) = state.conversion {
+            let finished = &conversion.finished_parquet_file;
+            let partitioned = format!("{finished}/");
+            if parquet_files.iter().any(|file_name| file_name == finished || file_name.starts_with(&partitioned)) {
+                json_files.retain(|file_name| !conversion.json_files.contains(file_name));
+            }
+        }
+        let archived: HashSet<String> = objects.iter().map(|object| object.location.to_string()).collect();
+        parquet_files.retain(|file_name| {
+            !state.uploads.iter().any(|upload| upload.file == *file_name && archived.contains(&upload.key))
+        });
+    }
+    let bytes = json_files.iter().chain(parquet_files.iter()).map(|f| local_file_size(f)).sum::<u64>()
+        + objects.iter().map(|o| o.size).sum::<u64>();
+    if bytes > config.max_bytes {
+        return Err(QueryLimitExceeded(format!(
+            "query would read {bytes} bytes, more than the limit of {}",
+            config.max_bytes
+        ))
+        .into());
+    }
+
+    if !json_files.is_empty() || !parquet_files.is_empty() {
+        let local_request = request.clone();
+        let schema = Arc::clone(&context.schema);
+        aggregates = task::spawn_blocking(move || -> Result<Aggregates, String> {
+            let mut aggregates = aggregates;
+            for file_name in json_files {
+                let Some(file) = open_local(&file_name)? else {
+                    continue;
+                };
+                aggregates.add_json(&local_request, &schema, file).map_err(|e| e.to_string())?;
+            }
+            for file_name in parquet_files {
+                if aggregates.is_full() {
+                    break;
+                }
+                let Some(file) = open_local(&file_name)? else {
+                    continue;
+                };
+                aggregates.add_parquet(&local_request, file).map_err(|e| e.to_string())?;
+            }
+            Ok(aggregates)
+        })
+        .await??;
+    }
+
+    // objects are read one at a time, only the aggregates are kept between them
+    if let Some(storage) = &storage {
+        for object in objects {
+            if aggregates.is_full() {
+                break;
+            }
+            let bytes: Bytes = match storage.get_bytes(&object.location).await {
+                Ok(bytes) => bytes,
+                Err(object_store::Error::NotFound { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let archive_request = request.clone();
+            aggregates = task::spawn_blocking(move || -> Result<Aggregates, String> {
+                let mut aggregates = aggregates;
+                aggregates.add_parquet(&archive_request, bytes).map_err(|e| e.to_string())?;
+                Ok(aggregates)
+            })
+            .await??;
+        }
+    }
+    if aggregates.is_full() {
+        return Err(QueryLimitExceeded(format!(
+            "query scans more than the limit of {} rows",
+            config.max_rows
+        ))
+        .into());
+    }
+
+    Ok(QueryResponse {
+        rows: aggregates
+            .groups
+            .into_iter()
+            .map(|(key, aggregate)| QueryRow {
+                group: request.group_by.iter().cloned().zip(key).collect(),
+                count: aggregate.count,
+                distinct_clients: aggregate.clients.len() as u64,
+            })
+            .collect(),
+        files: aggregates.files,
+    })
+}
+
+pub(crate) fn is_authorized(token: &str, headers: &HeaderMap) -> bool {
+    let Some(value) = headers.get("Authorization").and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let Some(provided) = value.strip_prefix("Bearer ") else {
+        return false;
+    };
+    let expected = token.as_bytes();
+    let provided = provided.as_bytes();
+    // compare every byte so the response time doesn't leak how much of the token matched
+    expected.len() == provided.len() && expected.iter().zip(provided).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The token is checked before the body is read, the body is limited to `MAX_REQUEST_SIZE`.
+pub async fn query(
+    State(context): State<Arc<QueryContext>>,
+    request: Request,
+) -> Result<Json<QueryResponse>, (StatusCode, String)> {
+    if !is_authorized(&context.config.token, request.headers()) {
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_owned()));
+    }
+    let body = to_bytes(request.into_body(), MAX_REQUEST_SIZE)
+        .await
+        .map_err(|_| (StatusCode::PAYLOAD_TOO_LARGE, "Query is too large".to_owned()))?;
+    let request: QueryRequest = serde_json::from_slice(&body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid query: {e}")))?;
+    match run_query(context, request).await {
+        Ok(response) => Ok(Json(response)),
+        Err(e) => {
+            if let Some(e) = e.downcast_ref::<QueryLimitExceeded>() {
+                return Err((StatusCode::BAD_REQUEST, e.to_string()));
+            }
+            error!("error running query: {e:?}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Error running query".to_owned()))
+        }
+    }
+}
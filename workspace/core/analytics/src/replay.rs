@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use arrow::array::{ArrayRef, RecordBatch};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::json::{ArrayWriter, ReaderBuilder};
+use http::HeaderMap;
+use log::{error, info, warn};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use crate::events::Events;
+use crate::events_sink::EventPipelineContext;
+use crate::pipeline::PipelineConfig;
+use crate::writers::arrow::schema::SchemaDefinition;
+
+const REPLAY_BATCH_SIZE: usize = 8192;
+
+/// Arguments of `bosca-analytics replay [--transforms name,...] <file or directory>...`
+pub struct ReplayArgs {
+    /// Types of the configured transforms to run, none when not set.
+    pub transforms: Option<Vec<String>>,
+    pub paths: Vec<String>,
+}
+
+impl ReplayArgs {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut transforms = None;
+        let mut paths = Vec::new();
+        while let Some(arg) = args.next() {
+            if arg == "--transforms" {
+                let Some(names) = args.next() else {
+                    return Err("--transforms needs a comma separated list of transform types".into());
+                };
+                transforms = Some(
+                    names
+                        .split(',')
+                        .map(|n| n.trim().to_owned())
+                        .filter(|n| !n.is_empty())
+                        .collect(),
+                );
+            } else {
+                paths.push(arg);
+            }
+        }
+        if paths.is_empty() {
+            return Err("replay needs at least one json or parquet file or directory".into());
+        }
+        Ok(Self { transforms, paths })
+    }
+}
+
+/// Collects the `events-*.json` and `.parquet` files under the paths, in name order.
+fn find_replay_files(paths: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    let mut pending: Vec<std::path::PathBuf> = paths.iter().map(std::path::PathBuf::from).collect();
+    while let Some(path) = pending.pop() {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if (name.starts_with("events-") && name.ends_with(".json")) || name.ends_with(".parquet") {
+            files.push(path.to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Timestamps are read back as epoch millis, which is what `Events` holds.
+fn timestamps_to_millis(batch: &RecordBatch) -> Result<RecordBatch, Box<dyn Error>> {
+    let mut fields = Vec::with_capacity(batch.num_columns());
+    let mut columns: Vec<ArrayRef> = Vec::with_capacity(batch.num_columns());
+    for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+        if let DataType::Timestamp(_, _) = field.data_type() {
+            fields.push(Field::new(field.name(), DataType::Int64, field.is_nullable()));
+            columns.push(cast(column, &DataType::Int64)?);
+        } else {
+            fields.push(field.as_ref().clone());
+            columns.push(Arc::clone(column));
+        }
+    }
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?)
+}
+
+/// Turns a stored row (one event with its context) back into the payload it was received in.
+fn row_to_events(row: Value) -> Result<Events, Box<dyn Error>> {
+    let mut element = row["element"].clone();
+    if element["content"].is_null() {
+        element["content"] = json!([]);
+    }
+    element["extras"] = match element["extras"].as_str() {
+        Some(extras) => serde_json::from_str(extras).unwrap_or(Value::Null),
+        None => Value::Null,
+    };
+    let events = json!({
+        "context": row["context"],
+        "events": [{
+            "created": row["created"],
+            "created_micros": row["created_micros"],
+            "type": row["type"],
+            "element": element,
+            "client_id": row["client_id"],
+        }],
+        "sent": row["sent"],
+        "sent_micros": row["sent_micros"].as_u64().unwrap_or(0),
+        "received": row["received"],
+        "received_micros": row["received_micros"],
+    });
+    Ok(serde_json::from_value(events)?)
+}
+
+fn batch_to_events(batch: &RecordBatch) -> Result<Vec<Events>, Box<dyn Error>> {
+    let batch = timestamps_to_millis(batch)?;
+    let mut writer = ArrayWriter::new(Vec::new());
+    writer.write(&batch)?;
+    writer.finish()?;
+    let rows: Vec<Value> = serde_json::from_slice(&writer.into_inner())?;
+    rows.into_iter().map(row_to_events).collect()
+}
+
+/// Reads a file on a blocking thread and sends its events, a batch at a time, to the replay loop.
+fn read_file(
+    schema: Arc<SchemaDefinition>,
+    file_name: &str,
+    sender: mpsc::Sender<Vec<Events>>,
+) -> Result<(), Box<dyn Error>> {
+    let file = File::open(file_name)?;
+    let reader: Box<dyn Iterator<Item = Result<RecordBatch, arrow::error::ArrowError>>> =
+        if file_name.ends_with(".parquet") {
+            Box::new(ParquetRecordBatchReaderBuilder::try_new(file)?.with_batch_size(REPLAY_BATCH_SIZE).build()?)
+        } else {
+            Box::new(
+                ReaderBuilder::new(Arc::clone(&schema.schema))
+                    .with_batch_size(REPLAY_BATCH_SIZE)
+                    .build(BufReader::new(file))?,
+            )
+        };
+    for batch in reader {
+        let events = batch_to_events(&batch?)?;
+        if sender.blocking_send(events).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Sends previously stored events through the pipeline's sinks again, and through the transforms named
+/// with `--transforms`, e.g. to re-enrich them after a transform was fixed. Stored events were already
+/// transformed, so none are run unless named: privacy would hash the hashed ids again, and the geo
+/// transforms have no request headers or client address to work with, so they'd clear the stored geo.
+pub async fn replay(
+    pipeline: &PipelineConfig,
+    schema: &Arc<SchemaDefinition>,
+    args: ReplayArgs,
+) -> Result<(), Box<dyn Error>> {
+    let mut pipeline = pipeline.clone();
+    let names = args.transforms.unwrap_or_default();
+    for name in &names {
+        if !pipeline.transforms.iter().any(|t| t.name() == name) {
+            return Err(format!("transform {name} isn't configured in the pipeline").into());
+        }
+        match name.as_str() {
+            "privacy" => warn!("replaying through privacy hashes already hashed ids, they won't match live data"),
+            "cloudflare_geo" | "maxmind_geo" => warn!("replaying through {name} clears the stored geo"),
+            _ => {}
+        }
+    }
+    pipeline.transforms.retain(|t| names.iter().any(|n| n == t.name()));
+    info!(
+        "replaying through transforms: {:?}",
+        pipeline.transforms.iter().map(|t| t.name()).collect::<Vec<&str>>()
+    );
+    // an index past the workers' so the json sink never shares a file with a running service
+    let mut sink = pipeline.new_sink(pipeline.pool_size, schema)?;
+    let mut context = EventPipelineContext::new(HeaderMap::new(), None);
+    let mut replayed = 0;
+    let mut failed = 0;
+    for file_name in find_replay_files(&args.paths)? {
+        info!("replaying: {file_name}");
+        let (sender, mut receiver) = mpsc::channel(4);
+        let read_schema = Arc::clone(schema);
+        let read_file_name = file_name.clone();
+        let reader = tokio::task::spawn_blocking(move || {
+            read_file(read_schema, &read_file_name, sender).map_err(|e| e.to_string())
+        });
+        while let Some(batch) = receiver.recv().await {
+            for events in batch {
+                sink.add(&mut context, &events).await?;
+                replayed += 1;
+            }
+        }
+        match reader.await {
+            Ok(Err(e)) => {
+                error!("error reading {file_name}: {e}");
+                failed += 1;
+            }
+            Err(e) => {
+                error!("error reading {file_name}: {e:?}");
+                failed += 1;
+            }
+            _ => {}
+        }
+    }
+    sink.finish().await?;
+    info!("replayed {replayed} events");
+    if let Some(files) = &pipeline.files {
+        info!("replayed json is converted and uploaded from {} by the running service", files.temp_dir);
+    }
+    if failed > 0 {
+        return Err(format!("{failed} files couldn't be read, events read from them before the error were replayed").into());
+    }
+    Ok(())
+}
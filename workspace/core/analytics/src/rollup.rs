@@ -0,0 +1,428 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fs::{create_dir_all, File};
+use std::sync::Arc;
+use arrow::array::{Array, ArrayRef, AsArray, Date32Array, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow::datatypes::{DataType, Field, Float32Type, Schema, TimestampMillisecondType};
+use bytes::Bytes;
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use log::info;
+use object_store::path::Path;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::{ArrowWriter, ProjectionMask};
+use tokio::task;
+use ulid::Ulid;
+use yaml_rust2::Yaml;
+use crate::writers::arrow::parquet::writer::writer_properties;
+use crate::writers::arrow::partition::may_contain;
+use crate::writers::object_storage::{new_object_storage, ObjectStorage, ObjectStorageConfig};
+
+const ROLLUP_COLUMNS: [&str; 5] = ["client_id", "context", "created", "type", "element"];
+const DEFAULT_ROW_GROUP_SIZE: usize = 1048576;
+
+#[derive(Clone)]
+pub struct RollupConfig {
+    /// Where the raw event parquet files are read from.
+    pub prefix: String,
+    /// Rollup tables are written to `{output_prefix}/{table}/date={day}/rollup.parquet`.
+    pub output_prefix: String,
+    pub row_group_size: usize,
+    pub temp_dir: String,
+}
+
+impl RollupConfig {
+    pub fn from_env() -> Self {
+        Self {
+            prefix: env::var("ROLLUP_PREFIX").unwrap_or("ingest/raw".to_owned()),
+            output_prefix: env::var("ROLLUP_OUTPUT_PREFIX").unwrap_or("rollups".to_owned()),
+            row_group_size: env::var("ROLLUP_ROW_GROUP_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_ROW_GROUP_SIZE),
+            temp_dir: env::var("TEMP_DIR").unwrap_or("./analytics/temp".to_owned()),
+        }
+    }
+}
+
+impl From<&Yaml> for RollupConfig {
+    fn from(yaml: &Yaml) -> Self {
+        let defaults = Self::from_env();
+        Self {
+            prefix: yaml["prefix"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .unwrap_or(defaults.prefix),
+            output_prefix: yaml["output_prefix"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .unwrap_or(defaults.output_prefix),
+            row_group_size: yaml["row_group_size"]
+                .as_i64()
+                .map(|s| s as usize)
+                .unwrap_or(defaults.row_group_size),
+            temp_dir: yaml["temp_dir"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .unwrap_or(defaults.temp_dir),
+        }
+    }
+}
+
+/// Arguments of `bosca-analytics rollup [--from YYYY-MM-DD] [--to YYYY-MM-DD]`, `to` is exclusive.
+#[derive(Default)]
+pub struct RollupArgs {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl RollupArgs {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            let date = match arg.as_str() {
+                "--from" | "--to" => match args.next() {
+                    Some(date) => NaiveDate::parse_from_str(&date, "%Y-%m-%d")?,
+                    None => return Err(format!("{arg} needs a YYYY-MM-DD date").into()),
+                },
+                _ => return Err(format!("unknown rollup argument: {arg}").into()),
+            };
+            if arg == "--from" {
+                parsed.from = Some(date);
+            } else {
+                parsed.to = Some(date);
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Every day in the range, up to today when there's no `to`. Empty without a `from`, as the range
+    /// then starts at the first event.
+    fn days(&self) -> Vec<NaiveDate> {
+        let Some(from) = self.from else {
+            return Vec::new();
+        };
+        let to = self.to.unwrap_or_else(|| Utc::now().date_naive() + Days::new(1));
+        from.iter_days().take_while(|day| *day < to).collect()
+    }
+
+    fn millis(date: Option<NaiveDate>) -> Option<i64> {
+        date.and_then(|d| d.and_hms_opt(0, 0, 0)).map(|d| d.and_utc().timestamp_millis())
+    }
+}
+
+struct Session {
+    client_id: String,
+    started: i64,
+    ended: i64,
+    events: u64,
+}
+
+#[derive(Default)]
+struct DailySessions {
+    sessions: u64,
+    clients: HashSet<String>,
+    events: u64,
+    duration_millis: i64,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct ContentKey {
+    date: NaiveDate,
+    app_id: String,
+    content_id: String,
+    content_type: String,
+}
+
+#[derive(Default)]
+struct ContentFunnel {
+    impressions: u64,
+    completions: u64,
+    percent_sum: f64,
+    percent_count: u64,
+}
+
+#[derive(Default)]
+struct Rollups {
+    from: Option<i64>,
+    to: Option<i64>,
+    /// Keyed by app id and session id, a session is attributed to the day it started.
+    sessions: HashMap<(String, String), Session>,
+    content: BTreeMap<ContentKey, ContentFunnel>,
+    files: usize,
+}
+
+fn day(millis: i64) -> NaiveDate {
+    DateTime::from_timestamp_millis(millis).unwrap_or_default().date_naive()
+}
+
+impl Rollups {
+    fn add_batch(&mut self, batch: &RecordBatch) -> Result<(), Box<dyn Error>> {
+        let (Some(client_ids), Some(context), Some(created), Some(event_types), Some(element)) = (
+            batch.column_by_name("client_id"),
+            batch.column_by_name("context"),
+            batch.column_by_name("created"),
+            batch.column_by_name("type"),
+            batch.column_by_name("element"),
+        ) else {
+            return Err("batch is missing rollup columns".into());
+        };
+        let client_ids = client_ids.as_string::<i32>();
+        let created = created.as_primitive::<TimestampMillisecondType>();
+        let event_types = event_types.as_string::<i32>();
+        let context = context.as_struct();
+        let (Some(app_ids), Some(session_ids)) = (context.column_by_name("app_id"), context.column_by_name("session_id")) else {
+            return Err("batch is missing context columns".into());
+        };
+        let app_ids = app_ids.as_string::<i32>();
+        let session_ids = session_ids.as_string::<i32>();
+        // files written before events could be tagged have no filter_reason
+        let filter_reasons = context.column_by_name("filter_reason").map(|reasons| reasons.as_string::<i32>());
+        let Some(contents) = element.as_struct().column_by_name("content") else {
+            return Err("batch is missing element.content".into());
+        };
+        let contents = contents.as_list::<i32>();
+
+        for row in 0..batch.num_rows() {
+            let created = created.value(row);
+            if self.from.map(|from| created < from).unwrap_or(false) || self.to.map(|to| created >= to).unwrap_or(false) {
+                continue;
+            }
+            // events the filter tagged rather than dropped, e.g. from bots, aren't counted
+            if filter_reasons.map(|reasons| reasons.is_valid(row)).unwrap_or(false) {
+                continue;
+            }
+            let app_id = app_ids.value(row);
+            let session_id = session_ids.value(row);
+            if !session_id.is_empty() {
+                let session = self
+                    .sessions
+                    .entry((app_id.to_owned(), session_id.to_owned()))
+                    .or_insert_with(|| Session {
+                        client_id: client_ids.value(row).to_owned(),
+                        started: created,
+                        ended: created,
+                        events: 0,
+                    });
+                session.started = session.started.min(created);
+                session.ended = session.ended.max(created);
+                session.events += 1;
+            }
+            if !contents.is_valid(row) {
+                continue;
+            }
+            let event_type = event_types.value(row);
+            let items = contents.value(row);
+            let items = items.as_struct();
+            let (Some(ids), Some(types), Some(percents)) = (
+                items.column_by_name("id"),
+                items.column_by_name("type"),
+                items.column_by_name("percent"),
+            ) else {
+                continue;
+            };
+            let ids = ids.as_string::<i32>();
+            let types = types.as_string::<i32>();
+            let percents = percents.as_primitive::<Float32Type>();
+            for i in 0..items.len() {
+                let funnel = self
+                    .content
+                    .entry(ContentKey {
+                        date: day(created),
+                        app_id: app_id.to_owned(),
+                        content_id: ids.value(i).to_owned(),
+                        content_type: types.value(i).to_owned(),
+                    })
+                    .or_default();
+                match event_type {
+                    "Impression" => funnel.impressions += 1,
+                    "Completion" => funnel.completions += 1,
+                    _ => {}
+                }
+                if percents.is_valid(i) {
+                    funnel.percent_sum += percents.value(i) as f64;
+                    funnel.percent_count += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_parquet(&mut self, bytes: Bytes) -> Result<(), Box<dyn Error>> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(bytes)?;
+        let indices: Vec<usize> = builder
+            .schema()
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| ROLLUP_COLUMNS.contains(&f.name().as_str()))
+            .map(|(i, _)| i)
+            .collect();
+        let mask = ProjectionMask::roots(builder.parquet_schema(), indices);
+        for batch in builder.with_projection(mask).with_batch_size(8192).build()? {
+            self.add_batch(&batch?)?;
+        }
+        self.files += 1;
+        Ok(())
+    }
+
+    fn daily_sessions(&self) -> BTreeMap<(NaiveDate, String), DailySessions> {
+        let mut daily: BTreeMap<(NaiveDate, String), DailySessions> = BTreeMap::new();
+        for ((app_id, _), session) in &self.sessions {
+            let rollup = daily.entry((day(session.started), app_id.clone())).or_default();
+            rollup.sessions += 1;
+            rollup.events += session.events;
+            rollup.duration_millis += session.ended - session.started;
+            rollup.clients.insert(session.client_id.clone());
+        }
+        daily
+    }
+}
+
+fn date32(date: &NaiveDate) -> i32 {
+    (*date - DateTime::UNIX_EPOCH.date_naive()).num_days() as i32
+}
+
+/// Sessions, clients, events and the average session length per day and app, with an empty batch for
+/// each of `days` without any.
+fn sessions_batches(rollups: &Rollups, days: &[NaiveDate]) -> Result<BTreeMap<NaiveDate, RecordBatch>, Box<dyn Error>> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("date", DataType::Date32, false),
+        Field::new("app_id", DataType::Utf8, false),
+        Field::new("sessions", DataType::UInt64, false),
+        Field::new("clients", DataType::UInt64, false),
+        Field::new("events", DataType::UInt64, false),
+        Field::new("avg_duration_secs", DataType::Float64, false),
+        Field::new("avg_events_per_session", DataType::Float64, false),
+    ]));
+    let mut days: BTreeMap<NaiveDate, Vec<(String, DailySessions)>> =
+        days.iter().map(|day| (*day, Vec::new())).collect();
+    for ((date, app_id), rollup) in rollups.daily_sessions() {
+        days.entry(date).or_default().push((app_id, rollup));
+    }
+    let mut batches = BTreeMap::new();
+    for (date, rows) in days {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Date32Array::from(vec![date32(&date); rows.len()])),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|(app_id, _)| app_id))),
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|(_, r)| r.sessions))),
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|(_, r)| r.clients.len() as u64))),
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|(_, r)| r.events))),
+            Arc::new(Float64Array::from_iter_values(
+                rows.iter().map(|(_, r)| r.duration_millis as f64 / 1000.0 / r.sessions as f64),
+            )),
+            Arc::new(Float64Array::from_iter_values(
+                rows.iter().map(|(_, r)| r.events as f64 / r.sessions as f64),
+            )),
+        ];
+        batches.insert(date, RecordBatch::try_new(Arc::clone(&schema), columns)?);
+    }
+    Ok(batches)
+}
+
+/// Impressions and completions per day and piece of content, the conversion between them and the
+/// average `percent` reported. Conversion is empty when there were no impressions, and the batch of
+/// each of `days` without any content is empty.
+fn content_batches(rollups: &Rollups, days: &[NaiveDate]) -> Result<BTreeMap<NaiveDate, RecordBatch>, Box<dyn Error>> {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("date", DataType::Date32, false),
+        Field::new("app_id", DataType::Utf8, false),
+        Field::new("content_id", DataType::Utf8, false),
+        Field::new("content_type", DataType::Utf8, false),
+        Field::new("impressions", DataType::UInt64, false),
+        Field::new("completions", DataType::UInt64, false),
+        Field::new("conversion", DataType::Float64, true),
+        Field::new("avg_percent", DataType::Float64, true),
+    ]));
+    let mut days: BTreeMap<NaiveDate, Vec<(&ContentKey, &ContentFunnel)>> =
+        days.iter().map(|day| (*day, Vec::new())).collect();
+    for (key, funnel) in &rollups.content {
+        days.entry(key.date).or_default().push((key, funnel));
+    }
+    let mut batches = BTreeMap::new();
+    for (date, rows) in days {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Date32Array::from(vec![date32(&date); rows.len()])),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|(k, _)| &k.app_id))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|(k, _)| &k.content_id))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|(k, _)| &k.content_type))),
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|(_, f)| f.impressions))),
+            Arc::new(UInt64Array::from_iter_values(rows.iter().map(|(_, f)| f.completions))),
+            Arc::new(Float64Array::from_iter(rows.iter().map(|(_, f)| {
+                (f.impressions > 0).then(|| f.completions as f64 / f.impressions as f64)
+            }))),
+            Arc::new(Float64Array::from_iter(rows.iter().map(|(_, f)| {
+                (f.percent_count > 0).then(|| f.percent_sum / f.percent_count as f64)
+            }))),
+        ];
+        batches.insert(date, RecordBatch::try_new(Arc::clone(&schema), columns)?);
+    }
+    Ok(batches)
+}
+
+/// Each day is a single object so running the rollup for a day again replaces its previous output,
+/// an empty batch replaces it with an empty table.
+async fn write_table(
+    config: &RollupConfig,
+    storage: &ObjectStorage,
+    table: &str,
+    batches: BTreeMap<NaiveDate, RecordBatch>,
+) -> Result<(), Box<dyn Error>> {
+    for (date, batch) in batches {
+        let temp_file = format!("{}/rollup-{}.parquet", config.temp_dir, Ulid::new());
+        let file = File::create(&temp_file)?;
+        let mut writer = ArrowWriter::try_new(file, batch.schema(), Some(writer_properties(config.row_group_size)))?;
+        writer.write(&batch)?;
+        writer.close()?;
+        let location = Path::parse(format!(
+            "{}/{table}/date={}/rollup.parquet",
+            config.output_prefix,
+            date.format("%Y-%m-%d")
+        ))?;
+        info!("writing {} {table} rows to {location}", batch.num_rows());
+        let result = storage.put_file(&location, &temp_file).await;
+        tokio::fs::remove_file(&temp_file).await?;
+        result?;
+    }
+    Ok(())
+}
+
+/// Reads the raw event parquet files under the prefix and writes the `sessions_daily` and
+/// `content_daily` rollup tables, skipping files whose `date=` partition is out of range and events
+/// the filter tagged. Sessions are attributed to the day of their first event in range,
+/// so a session that began before `--from` is only counted from the events after it.
+pub async fn rollup(
+    config: &RollupConfig,
+    storage_config: &ObjectStorageConfig,
+    args: RollupArgs,
+) -> Result<(), Box<dyn Error>> {
+    let storage = new_object_storage(storage_config)?;
+    // days in range without events get empty tables so a re-run doesn't leave their old rollup behind
+    let days = args.days();
+    create_dir_all(&config.temp_dir)?;
+    let mut rollups = Rollups {
+        from: RollupArgs::millis(args.from),
+        to: RollupArgs::millis(args.to),
+        ..Default::default()
+    };
+    for object in storage.list(&Path::parse(&config.prefix)?).await? {
+        let location = object.location.to_string();
+        if !location.ends_with(".parquet")
+            || location.starts_with(&format!("{}/", config.output_prefix))
+            || !may_contain(&location, rollups.from, rollups.to)
+        {
+            continue;
+        }
+        let bytes = storage.get_bytes(&object.location).await?;
+        rollups = task::spawn_blocking(move || -> Result<Rollups, String> {
+            let mut rollups = rollups;
+            rollups.add_parquet(bytes).map_err(|e| e.to_string())?;
+            Ok(rollups)
+        })
+        .await??;
+    }
+    info!("rolling up {} sessions from {} files", rollups.sessions.len(), rollups.files);
+    write_table(config, &storage, "sessions_daily", sessions_batches(&rollups, &days)?).await?;
+    write_table(config, &storage, "content_daily", content_batches(&rollups, &days)?).await?;
+    Ok(())
+}
\ No newline at end of file
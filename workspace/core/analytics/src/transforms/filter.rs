@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Mutex;
+use chrono::Utc;
+use ipnetwork::IpNetwork;
+use yaml_rust2::Yaml;
+use crate::events::Events;
+use crate::events_sink::EventPipelineContext;
+use crate::events_transform::EventTransform;
+use crate::metrics::METRICS;
+
+const DEFAULT_USER_AGENTS: [&str; 8] = [
+    "bot", "crawler", "spider", "headlesschrome", "uptimerobot", "pingdom", "statuscake", "lighthouse",
+];
+const MAX_TRACKED_CLIENTS: usize = 100000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterAction {
+    Drop,
+    /// Keeps the events but records why they matched in `context.filter_reason`.
+    Tag,
+}
+
+#[derive(Clone)]
+pub struct FilterConfig {
+    pub action: FilterAction,
+    /// Matches browsers the user agent transform flagged as bots.
+    pub bots: bool,
+    /// Case-insensitive substrings of the user agent.
+    pub user_agents: Vec<String>,
+    pub ip_ranges: Vec<IpNetwork>,
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// When not empty, events from any other app are filtered.
+    pub app_ids: Vec<String>,
+    /// Events a client may send per minute (per worker) before the rest of the minute is filtered.
+    pub max_events_per_minute: Option<usize>,
+}
+
+fn strings(yaml: &Yaml, name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut values = Vec::new();
+    if let Some(items) = yaml[name].as_vec() {
+        for item in items {
+            let Some(item) = item.as_str() else {
+                return Err(format!("{name} must be strings").into());
+            };
+            values.push(item.to_owned());
+        }
+    }
+    Ok(values)
+}
+
+fn networks(yaml: &Yaml, name: &str) -> Result<Vec<IpNetwork>, Box<dyn Error>> {
+    let mut networks = Vec::new();
+    for network in strings(yaml, name)? {
+        networks.push(IpNetwork::from_str(&network)?);
+    }
+    Ok(networks)
+}
+
+impl TryFrom<&Yaml> for FilterConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        Ok(Self {
+            action: match yaml["action"].as_str() {
+                None | Some("drop") => FilterAction::Drop,
+                Some("tag") => FilterAction::Tag,
+                Some(action) => return Err(format!("unknown filter action: {action}").into()),
+            },
+            bots: yaml["bots"].as_bool().unwrap_or(true),
+            user_agents: if yaml["user_agents"].is_badvalue() {
+                DEFAULT_USER_AGENTS.iter().map(|a| a.to_string()).collect()
+            } else {
+                strings(yaml, "user_agents")?.iter().map(|a| a.to_lowercase()).collect()
+            },
+            ip_ranges: networks(yaml, "ip_ranges")?,
+            trusted_proxies: networks(yaml, "trusted_proxies")?,
+            app_ids: strings(yaml, "app_ids")?,
+            max_events_per_minute: yaml["max_events_per_minute"].as_i64().map(|m| m.max(1) as usize),
+        })
+    }
+}
+
+pub struct FilterTransform {
+    config: FilterConfig,
+    /// Start of the current minute and the events counted in it, by client.
+    rates: Mutex<HashMap<String, (i64, usize)>>,
+}
+
+impl FilterTransform {
+    pub fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            rates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn over_rate(&self, client_id: &str, events: usize) -> bool {
+        let Some(max) = self.config.max_events_per_minute else {
+            return false;
+        };
+        let now = Utc::now().timestamp_millis();
+        let mut rates = self.rates.lock().unwrap();
+        if rates.len() >= MAX_TRACKED_CLIENTS {
+            rates.retain(|_, (started, _)| *started > now - 60000);
+        }
+        let rate = rates.entry(client_id.to_owned()).or_insert((now, 0));
+        if rate.0 <= now - 60000 {
+            *rate = (now, 0);
+        }
+        rate.1 += events;
+        rate.1 > max
+    }
+
+    fn reason(&self, context: &EventPipelineContext, event: &Events) -> Option<&'static str> {
+        if !self.config.app_ids.is_empty() && !self.config.app_ids.contains(&event.context.app_id) {
+            return Some("app_id");
+        }
+        if let Some(browser) = &event.context.browser {
+            if self.config.bots && browser.bot == Some(true) {
+                return Some("bot");
+            }
+            let agent = browser.agent.to_lowercase();
+            if self.config.user_agents.iter().any(|a| agent.contains(a.as_str())) {
+                return Some("user_agent");
+            }
+        }
+        if !self.config.ip_ranges.is_empty() {
+            if let Some(ip) = context.client_ip(&self.config.trusted_proxies) {
+                if self.config.ip_ranges.iter().any(|range| range.contains(ip)) {
+                    return Some("ip_range");
+                }
+            }
+        }
+        if self.over_rate(&event.context.client_id, event.events.len()) {
+            return Some("rate");
+        }
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl EventTransform for FilterTransform {
+    async fn transform(&self, context: &mut EventPipelineContext, event: &mut Events) -> Result<(), Box<dyn Error>> {
+        // the reason is only ever this filter's, never one stored before
+        event.context.filter_reason = None;
+        let Some(reason) = self.reason(context, event) else {
+            return Ok(());
+        };
+        METRICS
+            .events_filtered
+            .with_label_values(&[reason, METRICS.app_id_label(&event.context.app_id)])
+            .inc_by(event.events.len() as u64);
+        match self.config.action {
+            FilterAction::Drop => event.events.clear(),
+            FilterAction::Tag => event.context.filter_reason = Some(reason.to_owned()),
+        }
+        Ok(())
+    }
+}
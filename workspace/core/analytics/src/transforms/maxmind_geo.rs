@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use ipnetwork::IpNetwork;
+use log::{error, info};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use crate::events::Events;
+use crate::events_sink::EventPipelineContext;
+use crate::events_transform::EventTransform;
+
+/// A MaxMind format (GeoIP2 / GeoLite2 City) database, shared by every worker and swapped in place when the file changes.
+pub struct GeoIpDatabase {
+    path: String,
+    reader: RwLock<Arc<Reader<Vec<u8>>>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl GeoIpDatabase {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let reader = Reader::open_readfile(path)?;
+        info!("loaded geoip database: {path} ({})", reader.metadata.database_type);
+        Ok(Self {
+            path: path.to_owned(),
+            reader: RwLock::new(Arc::new(reader)),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    fn reader(&self) -> Arc<Reader<Vec<u8>>> {
+        Arc::clone(&self.reader.read().unwrap())
+    }
+
+    /// Reloads the database when the file's modification time has changed, returns true when it was reloaded.
+    pub fn reload(&self) -> Result<bool, Box<dyn Error>> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        if modified == *self.modified.read().unwrap() {
+            return Ok(false);
+        }
+        let reader = Reader::open_readfile(&self.path)?;
+        *self.reader.write().unwrap() = Arc::new(reader);
+        *self.modified.write().unwrap() = modified;
+        info!("reloaded geoip database: {}", self.path);
+        Ok(true)
+    }
+}
+
+pub async fn watch_geoip_database(database: Arc<GeoIpDatabase>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let reload_database = Arc::clone(&database);
+        match tokio::task::spawn_blocking(move || reload_database.reload().map_err(|e| e.to_string())).await {
+            Ok(Err(e)) => error!("error reloading geoip database: {e}"),
+            Err(e) => error!("error reloading geoip database: {e:?}"),
+            _ => {}
+        }
+    }
+}
+
+pub struct MaxMindGeoTransform {
+    pub database: Arc<GeoIpDatabase>,
+    pub trusted_proxies: Vec<IpNetwork>,
+}
+
+fn english(names: &Option<BTreeMap<&str, &str>>) -> Option<String> {
+    names.as_ref().and_then(|names| names.get("en")).map(|name| (*name).to_owned())
+}
+
+#[async_trait::async_trait]
+impl EventTransform for MaxMindGeoTransform {
+    async fn transform(&self, context: &mut EventPipelineContext, event: &mut Events) -> Result<(), Box<dyn Error>> {
+        let Some(ip) = context.client_ip(&self.trusted_proxies) else {
+            return Ok(());
+        };
+        let reader = self.database.reader();
+        let city = match reader.lookup::<geoip2::City>(ip) {
+            Ok(city) => city,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let geo = &mut event.context.geo;
+        geo.city = city.city.as_ref().and_then(|c| english(&c.names));
+        geo.country = city.country.as_ref().and_then(|c| c.iso_code).map(|c| c.to_owned());
+        geo.continent = city.continent.as_ref().and_then(|c| c.code).map(|c| c.to_owned());
+        let subdivision = city.subdivisions.as_ref().and_then(|s| s.first());
+        geo.region = subdivision.and_then(|s| english(&s.names));
+        geo.region_code = subdivision.and_then(|s| s.iso_code).map(|c| c.to_owned());
+        geo.postal_code = city.postal.as_ref().and_then(|p| p.code).map(|c| c.to_owned());
+        geo.timezone = city.location.as_ref().and_then(|l| l.time_zone).map(|t| t.to_owned());
+        geo.latitude = city.location.as_ref().and_then(|l| l.latitude);
+        geo.longitude = city.location.as_ref().and_then(|l| l.longitude);
+        Ok(())
+    }
+}
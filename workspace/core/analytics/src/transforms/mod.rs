@@ -1 +1,5 @@
 pub mod cloudflare_geo;
+pub mod filter;
+pub mod maxmind_geo;
+pub mod privacy;
+pub mod user_agent;
\ No newline at end of file
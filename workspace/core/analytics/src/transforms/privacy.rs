@@ -0,0 +1,236 @@
+use std::env;
+use std::error::Error;
+use sha2::{Digest, Sha256};
+use yaml_rust2::Yaml;
+use crate::events::Events;
+use crate::events_sink::EventPipelineContext;
+use crate::events_transform::EventTransform;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrivacyField {
+    ClientId,
+    UserId,
+    InstallationId,
+    SessionId,
+    Coordinates,
+    Locale,
+    City,
+    PostalCode,
+    Timezone,
+    DeviceModel,
+    UserAgent,
+}
+
+impl PrivacyField {
+    fn is_identifier(&self) -> bool {
+        matches!(
+            self,
+            PrivacyField::ClientId | PrivacyField::UserId | PrivacyField::InstallationId | PrivacyField::SessionId
+        )
+    }
+}
+
+impl TryFrom<&str> for PrivacyField {
+    type Error = Box<dyn Error>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "client_id" => Ok(PrivacyField::ClientId),
+            "user_id" => Ok(PrivacyField::UserId),
+            "installation_id" => Ok(PrivacyField::InstallationId),
+            "session_id" => Ok(PrivacyField::SessionId),
+            "coordinates" => Ok(PrivacyField::Coordinates),
+            "locale" => Ok(PrivacyField::Locale),
+            "city" => Ok(PrivacyField::City),
+            "postal_code" => Ok(PrivacyField::PostalCode),
+            "timezone" => Ok(PrivacyField::Timezone),
+            "device_model" => Ok(PrivacyField::DeviceModel),
+            "user_agent" => Ok(PrivacyField::UserAgent),
+            _ => Err(format!("unknown privacy field: {value}").into()),
+        }
+    }
+}
+
+/// What happens to events whose context says analytics consent wasn't given.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConsentPolicy {
+    Keep,
+    Drop,
+    /// Hashes the identifiers, removes the user id and everything that locates the client more precisely than a region.
+    Anonymize,
+}
+
+#[derive(Clone)]
+pub struct PrivacyConfig {
+    pub salt: String,
+    pub hash: Vec<PrivacyField>,
+    pub drop: Vec<PrivacyField>,
+    /// Decimal places latitude and longitude are truncated to.
+    pub coordinate_precision: Option<u32>,
+    pub without_consent: ConsentPolicy,
+    /// When set, events without a consent flag are treated as not having consent.
+    pub require_consent: bool,
+}
+
+fn fields(yaml: &Yaml) -> Result<Vec<PrivacyField>, Box<dyn Error>> {
+    let mut fields = Vec::new();
+    if let Some(items) = yaml.as_vec() {
+        for item in items {
+            let Some(item) = item.as_str() else {
+                return Err("privacy fields must be strings".into());
+            };
+            fields.push(PrivacyField::try_from(item)?);
+        }
+    }
+    Ok(fields)
+}
+
+impl TryFrom<&Yaml> for PrivacyConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        let config = Self {
+            salt: yaml["salt"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .or_else(|| env::var("PRIVACY_SALT").ok())
+                .unwrap_or_default(),
+            hash: fields(&yaml["hash"])?,
+            drop: fields(&yaml["drop"])?,
+            coordinate_precision: yaml["coordinate_precision"].as_i64().map(|p| p.clamp(0, 8) as u32),
+            without_consent: match yaml["without_consent"].as_str() {
+                None | Some("keep") => ConsentPolicy::Keep,
+                Some("drop") => ConsentPolicy::Drop,
+                Some("anonymize") => ConsentPolicy::Anonymize,
+                Some(policy) => return Err(format!("unknown consent policy: {policy}").into()),
+            },
+            require_consent: yaml["require_consent"].as_bool().unwrap_or(false),
+        };
+        if let Some(field) = config.hash.iter().find(|f| !f.is_identifier()) {
+            return Err(format!("privacy field {field:?} can't be hashed, only dropped").into());
+        }
+        if config.salt.is_empty() && (!config.hash.is_empty() || config.without_consent == ConsentPolicy::Anonymize) {
+            return Err("privacy transform needs a salt (or PRIVACY_SALT) to hash identifiers".into());
+        }
+        Ok(config)
+    }
+}
+
+pub struct PrivacyTransform {
+    config: PrivacyConfig,
+}
+
+impl PrivacyTransform {
+    pub fn new(config: PrivacyConfig) -> Self {
+        Self { config }
+    }
+
+    fn hash_value(&self, value: &str) -> String {
+        if value.is_empty() {
+            return String::new();
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.config.salt.as_bytes());
+        hasher.update(b":");
+        hasher.update(value.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    fn hash(&self, event: &mut Events, field: PrivacyField) {
+        let context = &mut event.context;
+        match field {
+            PrivacyField::ClientId => {
+                context.client_id = self.hash_value(&context.client_id);
+                for e in event.events.iter_mut() {
+                    e.client_id = self.hash_value(&e.client_id);
+                }
+            }
+            PrivacyField::UserId => context.user_id = context.user_id.as_ref().map(|id| self.hash_value(id)),
+            PrivacyField::InstallationId => {
+                context.device.installation_id = self.hash_value(&context.device.installation_id)
+            }
+            PrivacyField::SessionId => context.session_id = self.hash_value(&context.session_id),
+            _ => {}
+        }
+    }
+
+    fn drop(event: &mut Events, field: PrivacyField) {
+        let context = &mut event.context;
+        match field {
+            PrivacyField::ClientId => {
+                context.client_id.clear();
+                for e in event.events.iter_mut() {
+                    e.client_id.clear();
+                }
+            }
+            PrivacyField::UserId => context.user_id = None,
+            PrivacyField::InstallationId => context.device.installation_id.clear(),
+            PrivacyField::SessionId => context.session_id.clear(),
+            PrivacyField::Coordinates => {
+                context.geo.latitude = None;
+                context.geo.longitude = None;
+            }
+            PrivacyField::Locale => context.device.primary_locale.clear(),
+            PrivacyField::City => context.geo.city = None,
+            PrivacyField::PostalCode => context.geo.postal_code = None,
+            PrivacyField::Timezone => {
+                context.device.timezone.clear();
+                context.geo.timezone = None;
+            }
+            PrivacyField::DeviceModel => context.device.model.clear(),
+            PrivacyField::UserAgent => {
+                if let Some(browser) = context.browser.as_mut() {
+                    browser.agent.clear();
+                }
+            }
+        }
+    }
+}
+
+fn truncate(value: Option<f64>, precision: u32) -> Option<f64> {
+    let factor = 10f64.powi(precision as i32);
+    value.map(|v| (v * factor).trunc() / factor)
+}
+
+#[async_trait::async_trait]
+impl EventTransform for PrivacyTransform {
+    async fn transform(&self, _: &mut EventPipelineContext, event: &mut Events) -> Result<(), Box<dyn Error>> {
+        let consented = event.context.analytics_consent.unwrap_or(!self.config.require_consent);
+        if !consented {
+            match self.config.without_consent {
+                ConsentPolicy::Keep => {}
+                ConsentPolicy::Drop => {
+                    event.events.clear();
+                    return Ok(());
+                }
+                ConsentPolicy::Anonymize => {
+                    for field in [PrivacyField::ClientId, PrivacyField::InstallationId, PrivacyField::SessionId] {
+                        if !self.config.hash.contains(&field) {
+                            self.hash(event, field);
+                        }
+                    }
+                    for field in [
+                        PrivacyField::UserId,
+                        PrivacyField::Coordinates,
+                        PrivacyField::City,
+                        PrivacyField::PostalCode,
+                        PrivacyField::UserAgent,
+                    ] {
+                        Self::drop(event, field);
+                    }
+                }
+            }
+        }
+        for field in &self.config.hash {
+            self.hash(event, *field);
+        }
+        for field in &self.config.drop {
+            Self::drop(event, *field);
+        }
+        if let Some(precision) = self.config.coordinate_precision {
+            event.context.geo.latitude = truncate(event.context.geo.latitude, precision);
+            event.context.geo.longitude = truncate(event.context.geo.longitude, precision);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+use std::error::Error;
+use woothee::parser::Parser;
+use crate::events::Events;
+use crate::events_sink::EventPipelineContext;
+use crate::events_transform::EventTransform;
+
+const UNKNOWN: &str = "UNKNOWN";
+
+pub struct UserAgentTransform {
+    parser: Parser,
+}
+
+impl UserAgentTransform {
+    pub fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+        }
+    }
+}
+
+fn known(value: &str) -> Option<String> {
+    if value.is_empty() || value == UNKNOWN {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+#[async_trait::async_trait]
+impl EventTransform for UserAgentTransform {
+    async fn transform(&self, _: &mut EventPipelineContext, event: &mut Events) -> Result<(), Box<dyn Error>> {
+        let Some(browser) = event.context.browser.as_mut() else {
+            return Ok(());
+        };
+        // only what's parsed from the agent is kept, never what was stored before
+        browser.clear_parsed();
+        let Some(result) = self.parser.parse(&browser.agent) else {
+            return Ok(());
+        };
+        browser.family = known(result.name);
+        browser.version = known(result.version);
+        browser.os = known(result.os);
+        browser.os_version = known(&result.os_version);
+        browser.device_class = Some(match result.category {
+            "pc" => "desktop",
+            "smartphone" | "mobilephone" => "mobile",
+            "appliance" => "appliance",
+            "crawler" => "bot",
+            _ => "unknown",
+        }.to_owned());
+        browser.bot = Some(result.category == "crawler");
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+use std::error::Error;
+use std::time::Duration;
+use serde::Serialize;
+use serde_json::Value;
+use yaml_rust2::Yaml;
+use crate::events::{Event, Events};
+
+const DEFAULT_MAX_STRING_LENGTH: usize = 1024;
+const DEFAULT_MAX_EXTRAS_LENGTH: usize = 16384;
+const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdField {
+    App,
+    Client,
+    Session,
+    Installation,
+    Element,
+}
+
+impl TryFrom<&str> for IdField {
+    type Error = Box<dyn Error>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "app_id" => Ok(IdField::App),
+            "client_id" => Ok(IdField::Client),
+            "session_id" => Ok(IdField::Session),
+            "installation_id" => Ok(IdField::Installation),
+            "element_id" => Ok(IdField::Element),
+            _ => Err(format!("unknown required id: {value}").into()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ValidationConfig {
+    /// Size of a request body once decompressed, larger bodies are rejected with 413.
+    pub max_body_size: usize,
+    /// How far `created` may be behind the time the events were received, unchecked when not set.
+    pub max_past_skew: Option<Duration>,
+    /// How far `created` may be ahead of the time the events were received, unchecked when not set.
+    pub max_future_skew: Option<Duration>,
+    pub max_string_length: usize,
+    /// Length of `element.extras` once serialized.
+    pub max_extras_length: usize,
+    /// Upper bound of `content.percent`, the lower bound is 0.
+    pub max_percent: f32,
+    /// Ids that must not be empty, none unless configured since the ids were never required before.
+    pub required_ids: Vec<IdField>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_past_skew: None,
+            max_future_skew: None,
+            max_string_length: DEFAULT_MAX_STRING_LENGTH,
+            max_extras_length: DEFAULT_MAX_EXTRAS_LENGTH,
+            max_percent: 1.0,
+            required_ids: Vec::new(),
+        }
+    }
+}
+
+impl TryFrom<&Yaml> for ValidationConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        let defaults = Self::default();
+        Ok(Self {
+            max_body_size: yaml["max_body_size"]
+                .as_i64()
+                .map(|s| s.max(1) as usize)
+                .unwrap_or(defaults.max_body_size),
+            max_past_skew: yaml["max_past_skew_secs"].as_i64().map(|s| Duration::from_secs(s as u64)),
+            max_future_skew: yaml["max_future_skew_secs"].as_i64().map(|s| Duration::from_secs(s as u64)),
+            max_string_length: yaml["max_string_length"]
+                .as_i64()
+                .map(|s| s as usize)
+                .unwrap_or(defaults.max_string_length),
+            max_extras_length: yaml["max_extras_length"]
+                .as_i64()
+                .map(|s| s as usize)
+                .unwrap_or(defaults.max_extras_length),
+            max_percent: yaml["max_percent"]
+                .as_f64()
+                .or_else(|| yaml["max_percent"].as_i64().map(|p| p as f64))
+                .map(|p| p as f32)
+                .unwrap_or(defaults.max_percent),
+            required_ids: match yaml["required_ids"].as_vec() {
+                Some(ids) => {
+                    let mut required_ids = Vec::new();
+                    for id in ids {
+                        let Some(id) = id.as_str() else {
+                            return Err("required ids must be strings".into());
+                        };
+                        required_ids.push(IdField::try_from(id)?);
+                    }
+                    required_ids
+                }
+                None => defaults.required_ids,
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct Rejection {
+    /// Position of the event in the payload, not set when the whole payload was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    /// Line of the document in an NDJSON body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub field: String,
+    pub reason: String,
+    #[serde(skip)]
+    pub event_type: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ValidationReport {
+    pub accepted: usize,
+    pub rejected: Vec<Rejection>,
+}
+
+impl Rejection {
+    /// A document that couldn't be read or written as a whole.
+    pub fn payload(line: Option<usize>, reason: String) -> Self {
+        Self {
+            index: None,
+            line,
+            field: "payload".to_owned(),
+            reason,
+            event_type: None,
+        }
+    }
+}
+
+fn rejection(index: Option<usize>, field: &str, reason: impl Into<String>) -> Rejection {
+    Rejection {
+        index,
+        line: None,
+        field: field.to_owned(),
+        reason: reason.into(),
+        event_type: None,
+    }
+}
+
+impl ValidationConfig {
+    fn check_string(&self, rejections: &mut Vec<Rejection>, index: Option<usize>, field: &str, value: &str) {
+        if value.len() > self.max_string_length {
+            rejections.push(rejection(
+                index,
+                field,
+                format!("longer than {} bytes", self.max_string_length),
+            ));
+        }
+    }
+
+    fn check_required(&self, rejections: &mut Vec<Rejection>, index: Option<usize>, id: IdField, field: &str, value: &str) {
+        if self.required_ids.contains(&id) && value.trim().is_empty() {
+            rejections.push(rejection(index, field, "required"));
+        }
+    }
+
+    fn validate_context(&self, events: &Events) -> Vec<Rejection> {
+        let mut rejections = Vec::new();
+        let context = &events.context;
+        self.check_required(&mut rejections, None, IdField::App, "context.app_id", &context.app_id);
+        self.check_required(&mut rejections, None, IdField::Client, "context.client_id", &context.client_id);
+        self.check_required(&mut rejections, None, IdField::Session, "context.session_id", &context.session_id);
+        self.check_required(
+            &mut rejections,
+            None,
+            IdField::Installation,
+            "context.device.installation_id",
+            &context.device.installation_id,
+        );
+        for (field, value) in [
+            ("context.app_id", &context.app_id),
+            ("context.app_version", &context.app_version),
+            ("context.client_id", &context.client_id),
+            ("context.session_id", &context.session_id),
+            ("context.device.installation_id", &context.device.installation_id),
+            ("context.device.manufacturer", &context.device.manufacturer),
+            ("context.device.model", &context.device.model),
+            ("context.device.platform", &context.device.platform),
+            ("context.device.primary_locale", &context.device.primary_locale),
+            ("context.device.system_name", &context.device.system_name),
+            ("context.device.timezone", &context.device.timezone),
+            ("context.device.type", &context.device.device_type),
+            ("context.device.version", &context.device.version),
+        ] {
+            self.check_string(&mut rejections, None, field, value);
+        }
+        if let Some(user_id) = &context.user_id {
+            self.check_string(&mut rejections, None, "context.user_id", user_id);
+        }
+        if let Some(browser) = &context.browser {
+            self.check_string(&mut rejections, None, "context.browser.agent", &browser.agent);
+        }
+        if let Some(batch_id) = &events.batch_id {
+            self.check_string(&mut rejections, None, "batch_id", batch_id);
+        }
+        rejections
+    }
+
+    fn validate_event(&self, index: usize, event: &Event, received: i64) -> Vec<Rejection> {
+        let mut rejections = Vec::new();
+        let index = Some(index);
+        if let Some(skew) = self.max_past_skew {
+            if event.created < received - skew.as_millis() as i64 {
+                rejections.push(rejection(index, "created", format!("more than {skew:?} in the past")));
+            }
+        }
+        if let Some(skew) = self.max_future_skew {
+            if event.created > received + skew.as_millis() as i64 {
+                rejections.push(rejection(index, "created", format!("more than {skew:?} in the future")));
+            }
+        }
+        if event.created_micros.map(|m| m >= 1_000_000).unwrap_or(false) {
+            rejections.push(rejection(index, "created_micros", "must be below 1000000"));
+        }
+        self.check_required(&mut rejections, index, IdField::Client, "client_id", &event.client_id);
+        self.check_required(&mut rejections, index, IdField::Element, "element.id", &event.element.id);
+        self.check_string(&mut rejections, index, "client_id", &event.client_id);
+        self.check_string(&mut rejections, index, "element.id", &event.element.id);
+        self.check_string(&mut rejections, index, "element.type", &event.element.element_type);
+        for content in &event.element.content {
+            self.check_string(&mut rejections, index, "element.content.id", &content.id);
+            self.check_string(&mut rejections, index, "element.content.type", &content.content_type);
+            if let Some(percent) = content.percent {
+                if !(0.0..=self.max_percent).contains(&percent) {
+                    rejections.push(rejection(
+                        index,
+                        "element.content.percent",
+                        format!("must be between 0 and {}", self.max_percent),
+                    ));
+                }
+            }
+        }
+        let extras_length = serde_json::to_string(&event.element.extras).map(|e| e.len()).unwrap_or(0);
+        if extras_length > self.max_extras_length {
+            rejections.push(rejection(
+                index,
+                "element.extras",
+                format!("longer than {} bytes", self.max_extras_length),
+            ));
+        }
+        let event_type = format!("{:?}", event.event_type);
+        for rejection in rejections.iter_mut() {
+            rejection.event_type = Some(event_type.clone());
+        }
+        rejections
+    }
+
+    /// Deserializes and validates a payload one event at a time. Events that fail are left out of the
+    /// returned payload and reported instead, an error means the payload as a whole was rejected.
+    pub fn validate(&self, mut payload: Value, received: i64) -> Result<(Events, Vec<Rejection>), Vec<Rejection>> {
+        let raw_events = match payload.get_mut("events").map(Value::take) {
+            Some(Value::Array(events)) => events,
+            _ => return Err(vec![rejection(None, "events", "must be an array")]),
+        };
+        payload["events"] = Value::Array(Vec::new());
+        let mut events: Events = match serde_json::from_value(payload) {
+            Ok(events) => events,
+            Err(e) => return Err(vec![rejection(None, "context", e.to_string())]),
+        };
+        let rejections = self.validate_context(&events);
+        if !rejections.is_empty() {
+            return Err(rejections);
+        }
+        let mut rejections = Vec::new();
+        for (index, raw_event) in raw_events.into_iter().enumerate() {
+            let event: Event = match serde_json::from_value(raw_event) {
+                Ok(event) => event,
+                Err(e) => {
+                    rejections.push(rejection(Some(index), "event", e.to_string()));
+                    continue;
+                }
+            };
+            let event_rejections = self.validate_event(index, &event, received);
+            if event_rejections.is_empty() {
+                events.events.push(event);
+            } else {
+                rejections.extend(event_rejections);
+            }
+        }
+        Ok((events, rejections))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const RECEIVED: i64 = 1_700_000_000_000;
+
+    fn payload(events: Value) -> Value {
+        json!({
+            "context": {
+                "app_id": "app",
+                "app_version": "1.0",
+                "client_id": "client",
+                "device": {
+                    "installation_id": "installation",
+                    "manufacturer": "",
+                    "model": "",
+                    "platform": "",
+                    "primary_locale": "",
+                    "system_name": "",
+                    "timezone": "",
+                    "type": "",
+                    "version": ""
+                },
+                "geo": {},
+                "session_id": "session"
+            },
+            "events": events,
+            "sent": RECEIVED,
+            "sent_micros": 0
+        })
+    }
+
+    fn event(created: i64, element_id: &str) -> Value {
+        json!({
+            "created": created,
+            "type": "impression",
+            "element": { "id": element_id, "type": "card", "content": [], "extras": null },
+            "client_id": "client"
+        })
+    }
+
+    fn fields(rejections: &[Rejection]) -> Vec<(Option<usize>, &str)> {
+        rejections.iter().map(|r| (r.index, r.field.as_str())).collect()
+    }
+
+    #[test]
+    fn default_accepts_empty_ids() {
+        let mut payload = payload(json!([event(RECEIVED, "")]));
+        payload["context"]["app_id"] = json!("");
+        let (events, rejections) = ValidationConfig::default().validate(payload, RECEIVED).ok().unwrap();
+        assert_eq!(events.events.len(), 1);
+        assert!(rejections.is_empty());
+    }
+
+    #[test]
+    fn required_ids() {
+        let config = ValidationConfig {
+            required_ids: vec![IdField::Client, IdField::Element],
+            ..ValidationConfig::default()
+        };
+        let (events, rejections) = config
+            .validate(payload(json!([event(RECEIVED, "a"), event(RECEIVED, " ")])), RECEIVED)
+            .ok()
+            .unwrap();
+        assert_eq!(events.events.len(), 1);
+        assert_eq!(fields(&rejections), vec![(Some(1), "element.id")]);
+        assert_eq!(rejections[0].reason, "required");
+        assert_eq!(rejections[0].event_type.as_deref(), Some("Impression"));
+
+        let config = ValidationConfig {
+            required_ids: vec![IdField::App],
+            ..ValidationConfig::default()
+        };
+        let mut payload = payload(json!([event(RECEIVED, "a")]));
+        payload["context"]["app_id"] = json!("");
+        let rejections = config.validate(payload, RECEIVED).err().unwrap();
+        assert_eq!(fields(&rejections), vec![(None, "context.app_id")]);
+    }
+
+    #[test]
+    fn skew() {
+        let config = ValidationConfig {
+            max_past_skew: Some(Duration::from_secs(60)),
+            max_future_skew: Some(Duration::from_secs(10)),
+            ..ValidationConfig::default()
+        };
+        let events = json!([
+            event(RECEIVED - 60_000, "a"),
+            event(RECEIVED - 60_001, "b"),
+            event(RECEIVED + 10_000, "c"),
+            event(RECEIVED + 10_001, "d"),
+        ]);
+        let (events, rejections) = config.validate(payload(events), RECEIVED).ok().unwrap();
+        assert_eq!(events.events.len(), 2);
+        assert_eq!(fields(&rejections), vec![(Some(1), "created"), (Some(3), "created")]);
+    }
+
+    #[test]
+    fn lengths_and_ranges() {
+        let config = ValidationConfig {
+            max_string_length: 12,
+            max_extras_length: 16,
+            ..ValidationConfig::default()
+        };
+        let mut long_id = event(RECEIVED, "thirteen-long");
+        long_id["created_micros"] = json!(1_000_000);
+        let mut extras = event(RECEIVED, "a");
+        extras["element"]["extras"] = json!({ "key": "a longer value" });
+        let mut percent = event(RECEIVED, "a");
+        percent["element"]["content"] = json!([
+            { "id": "c", "type": "t", "percent": 1.0 },
+            { "id": "c", "type": "t", "percent": 1.5 },
+            { "id": "c", "type": "t", "percent": -0.1 },
+        ]);
+        let (events, rejections) = config
+            .validate(payload(json!([event(RECEIVED, "twelve-long!"), long_id, extras, percent])), RECEIVED)
+            .ok()
+            .unwrap();
+        assert_eq!(events.events.len(), 1);
+        assert_eq!(
+            fields(&rejections),
+            vec![
+                (Some(1), "created_micros"),
+                (Some(1), "element.id"),
+                (Some(2), "element.extras"),
+                (Some(3), "element.content.percent"),
+                (Some(3), "element.content.percent"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let config = ValidationConfig::default();
+        let (events, rejections) = config
+            .validate(payload(json!([event(RECEIVED, "a"), { "created": "now" }])), RECEIVED)
+            .ok()
+            .unwrap();
+        assert_eq!(events.events.len(), 1);
+        assert_eq!(fields(&rejections), vec![(Some(1), "event")]);
+
+        let rejections = config.validate(payload(json!({})), RECEIVED).err().unwrap();
+        assert_eq!(fields(&rejections), vec![(None, "events")]);
+
+        let mut payload = payload(json!([]));
+        payload["context"] = json!({});
+        let rejections = config.validate(payload, RECEIVED).err().unwrap();
+        assert_eq!(fields(&rejections), vec![(None, "context")]);
+    }
+
+    #[test]
+    fn from_yaml() {
+        let yaml = &yaml_rust2::YamlLoader::load_from_str("required_ids: [app_id, session_id]").unwrap()[0];
+        let config = ValidationConfig::try_from(yaml).unwrap();
+        assert_eq!(config.required_ids, vec![IdField::App, IdField::Session]);
+        assert_eq!(config.max_string_length, DEFAULT_MAX_STRING_LENGTH);
+
+        let yaml = &yaml_rust2::YamlLoader::load_from_str("max_percent: 100").unwrap()[0];
+        let config = ValidationConfig::try_from(yaml).unwrap();
+        assert!(config.required_ids.is_empty());
+        assert_eq!(config.max_percent, 100.0);
+
+        let yaml = &yaml_rust2::YamlLoader::load_from_str("required_ids: [device_id]").unwrap()[0];
+        assert!(ValidationConfig::try_from(yaml).is_err());
+    }
+}
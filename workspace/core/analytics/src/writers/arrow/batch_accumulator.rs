@@ -1,8 +1,8 @@
 use std::sync::Arc;
-use arrow::array::{ArrayRef, Float32Builder, Float64Builder, Int32Builder, ListBuilder, RecordBatch, StringBuilder, StructBuilder, TimestampMillisecondBuilder, UInt32Builder};
+use arrow::array::{ArrayRef, BooleanBuilder, Float32Builder, Float64Builder, Int32Builder, ListBuilder, RecordBatch, StringBuilder, StructBuilder, TimestampMillisecondBuilder, UInt32Builder};
 use ulid::Ulid;
 use crate::events::Events;
-use crate::writers::arrow::schema::SchemaDefinition;
+use crate::writers::arrow::schema::{SchemaDefinition, SCHEMA_VERSION};
 
 pub(crate) struct BatchAccumulator {
     schema: Arc<SchemaDefinition>,
@@ -41,12 +41,29 @@ impl BatchAccumulator {
         let context_client_id_builder = StringBuilder::new();
         let context_session_id_builder = StringBuilder::new();
         let context_user_id_builder = StringBuilder::new();
+        let context_analytics_consent_builder = BooleanBuilder::new();
+        let context_filter_reason_builder = StringBuilder::new();
+        let context_installation_verified_builder = BooleanBuilder::new();
 
         // Browser struct builders
         let browser_agent_builder = StringBuilder::new();
+        let browser_family_builder = StringBuilder::new();
+        let browser_version_builder = StringBuilder::new();
+        let browser_os_builder = StringBuilder::new();
+        let browser_os_version_builder = StringBuilder::new();
+        let browser_device_class_builder = StringBuilder::new();
+        let browser_bot_builder = BooleanBuilder::new();
         let browser_struct_builder = StructBuilder::new(
             self.schema.browser_struct.clone(),
-            vec![Box::new(browser_agent_builder)],
+            vec![
+                Box::new(browser_agent_builder),
+                Box::new(browser_family_builder),
+                Box::new(browser_version_builder),
+                Box::new(browser_os_builder),
+                Box::new(browser_os_version_builder),
+                Box::new(browser_device_class_builder),
+                Box::new(browser_bot_builder),
+            ],
         );
 
         // Device struct builders
@@ -112,6 +129,9 @@ impl BatchAccumulator {
                 Box::new(geo_struct_builder),
                 Box::new(context_session_id_builder),
                 Box::new(context_user_id_builder),
+                Box::new(context_analytics_consent_builder),
+                Box::new(context_filter_reason_builder),
+                Box::new(context_installation_verified_builder),
             ],
         );
 
@@ -154,6 +174,7 @@ impl BatchAccumulator {
         let mut sent_micros_builder = UInt32Builder::new();
         let mut received_builder = TimestampMillisecondBuilder::new().with_timezone("UTC");
         let mut received_micros_builder = UInt32Builder::new();
+        let mut schema_version_builder = UInt32Builder::new();
         let mut server_id_builder = StringBuilder::new();
         let mut client_id_builder = StringBuilder::new();
 
@@ -167,10 +188,22 @@ impl BatchAccumulator {
                 if let Some(browser) = &context.browser {
                     browser_struct_builder.field_builder::<StringBuilder>(0).unwrap()
                         .append_value(&browser.agent);
+                    browser_struct_builder.field_builder::<StringBuilder>(1).unwrap().append_option(browser.family.as_ref());
+                    browser_struct_builder.field_builder::<StringBuilder>(2).unwrap().append_option(browser.version.as_ref());
+                    browser_struct_builder.field_builder::<StringBuilder>(3).unwrap().append_option(browser.os.as_ref());
+                    browser_struct_builder.field_builder::<StringBuilder>(4).unwrap().append_option(browser.os_version.as_ref());
+                    browser_struct_builder.field_builder::<StringBuilder>(5).unwrap().append_option(browser.device_class.as_ref());
+                    browser_struct_builder.field_builder::<BooleanBuilder>(6).unwrap().append_option(browser.bot);
                     browser_struct_builder.append(true);
                 } else {
                     browser_struct_builder.field_builder::<StringBuilder>(0).unwrap()
                         .append_value("");
+                    browser_struct_builder.field_builder::<StringBuilder>(1).unwrap().append_null();
+                    browser_struct_builder.field_builder::<StringBuilder>(2).unwrap().append_null();
+                    browser_struct_builder.field_builder::<StringBuilder>(3).unwrap().append_null();
+                    browser_struct_builder.field_builder::<StringBuilder>(4).unwrap().append_null();
+                    browser_struct_builder.field_builder::<StringBuilder>(5).unwrap().append_null();
+                    browser_struct_builder.field_builder::<BooleanBuilder>(6).unwrap().append_null();
                     browser_struct_builder.append(false);
                 }
 
@@ -248,6 +281,9 @@ impl BatchAccumulator {
                 } else {
                     context_struct_builder.field_builder::<StringBuilder>(7).unwrap().append_null();
                 }
+                context_struct_builder.field_builder::<BooleanBuilder>(8).unwrap().append_option(context.analytics_consent);
+                context_struct_builder.field_builder::<StringBuilder>(9).unwrap().append_option(context.filter_reason.as_ref());
+                context_struct_builder.field_builder::<BooleanBuilder>(10).unwrap().append_option(context.installation_verified);
                 context_struct_builder.append(true);
 
                 // Element data
@@ -291,6 +327,7 @@ impl BatchAccumulator {
                 }
                 received_builder.append_value(events.received.unwrap_or(0));
                 received_micros_builder.append_value(events.received_micros.unwrap_or(0));
+                schema_version_builder.append_value(SCHEMA_VERSION);
                 type_builder.append_value(format!("{:?}", event.event_type));
 
                 // Sent timestamps
@@ -316,6 +353,7 @@ impl BatchAccumulator {
             Arc::new(sent_micros_builder.finish()),
             Arc::new(received_builder.finish()),
             Arc::new(received_micros_builder.finish()),
+            Arc::new(schema_version_builder.finish()),
         ];
 
         let batch = RecordBatch::try_new(self.schema.schema.clone(), arrays)?;
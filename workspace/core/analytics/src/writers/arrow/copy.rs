@@ -1,11 +1,24 @@
+use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
 use std::sync::{Arc, Mutex};
+use arrow::array::RecordBatch;
 use arrow::json::ReaderBuilder;
 use parquet::arrow::ArrowWriter;
 use crate::writers::arrow::schema::SchemaDefinition;
 
-pub fn copy_to_parquet(json_file: File, schema: Arc<SchemaDefinition>, writer: Arc<Mutex<ArrowWriter<File>>>) -> Result<bool, Box<dyn std::error::Error>> {
+pub trait BatchWriter {
+    fn write_batch(&mut self, batch: &RecordBatch) -> Result<(), Box<dyn Error>>;
+}
+
+impl BatchWriter for ArrowWriter<File> {
+    fn write_batch(&mut self, batch: &RecordBatch) -> Result<(), Box<dyn Error>> {
+        self.write(batch)?;
+        Ok(())
+    }
+}
+
+pub fn copy_to_parquet<W: BatchWriter>(json_file: File, schema: Arc<SchemaDefinition>, writer: Arc<Mutex<W>>) -> Result<bool, Box<dyn Error>> {
     let buf = BufReader::new(json_file);
     let mut writer = writer.lock().unwrap();
     let mut reader = ReaderBuilder::new(schema.schema.clone()).build(buf).unwrap();
@@ -14,7 +27,7 @@ pub fn copy_to_parquet(json_file: File, schema: Arc<SchemaDefinition>, writer: A
         match reader.next() {
             Some(batch) => {
                 let batch = batch?;
-                writer.write(&batch)?;
+                writer.write_batch(&batch)?;
                 has_records = true;
             }
             None => {
@@ -2,4 +2,5 @@ pub mod schema;
 pub mod batch_accumulator;
 pub mod parquet;
 pub mod json;
-pub mod copy;
\ No newline at end of file
+pub mod copy;
+pub mod partition;
\ No newline at end of file
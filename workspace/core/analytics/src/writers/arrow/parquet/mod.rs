@@ -1,2 +1,3 @@
 pub mod writer;
-pub mod sink;
\ No newline at end of file
+pub mod sink;
+pub mod partitioned;
\ No newline at end of file
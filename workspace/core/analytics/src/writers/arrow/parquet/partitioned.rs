@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{create_dir_all, File};
+use std::sync::Arc;
+use arrow::array::{RecordBatch, UInt32Array};
+use arrow::compute::take;
+use parquet::arrow::ArrowWriter;
+use crate::writers::arrow::copy::BatchWriter;
+use crate::writers::arrow::parquet::writer::new_arrow_writer;
+use crate::writers::arrow::partition::{partition_rows, PartitionKey};
+use crate::writers::arrow::schema::SchemaDefinition;
+
+/// Writes one parquet file per partition into `{dir}/{partition}/`.
+pub struct PartitionedWriter {
+    schema: Arc<SchemaDefinition>,
+    dir: String,
+    keys: Vec<PartitionKey>,
+    batch_size: usize,
+    writers: HashMap<String, ArrowWriter<File>>,
+}
+
+impl PartitionedWriter {
+    pub fn new(schema: Arc<SchemaDefinition>, dir: &str, keys: Vec<PartitionKey>, batch_size: usize) -> Result<Self, Box<dyn Error>> {
+        create_dir_all(dir)?;
+        Ok(Self {
+            schema,
+            dir: dir.to_owned(),
+            keys,
+            batch_size,
+            writers: HashMap::new(),
+        })
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        for writer in self.writers.values_mut() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl BatchWriter for PartitionedWriter {
+    fn write_batch(&mut self, batch: &RecordBatch) -> Result<(), Box<dyn Error>> {
+        for (partition, rows) in partition_rows(batch, &self.keys)? {
+            let indices = UInt32Array::from(rows);
+            let mut columns = Vec::with_capacity(batch.num_columns());
+            for column in batch.columns() {
+                columns.push(take(column, &indices, None)?);
+            }
+            let partition_batch = RecordBatch::try_new(batch.schema(), columns)?;
+            let writer = match self.writers.get_mut(&partition) {
+                Some(writer) => writer,
+                None => {
+                    let dir = format!("{}/{}", self.dir, partition);
+                    create_dir_all(&dir)?;
+                    let writer = new_arrow_writer(Arc::clone(&self.schema), &format!("{dir}/part.parquet"), self.batch_size)?;
+                    self.writers.entry(partition).or_insert(writer)
+                }
+            };
+            writer.write(&partition_batch)?;
+        }
+        Ok(())
+    }
+}
@@ -4,7 +4,7 @@ use std::sync::Arc;
 use log::info;
 use parquet::arrow::ArrowWriter;
 use parquet::basic::{Compression, ZstdLevel};
-use parquet::file::properties::WriterProperties;
+use parquet::file::properties::{EnabledStatistics, WriterProperties};
 use crate::events::Events;
 use crate::writers::arrow::batch_accumulator::BatchAccumulator;
 use crate::writers::arrow::schema::SchemaDefinition;
@@ -14,11 +14,16 @@ pub struct ParquetWriter {
     accumulator: BatchAccumulator,
 }
 
-pub fn new_arrow_writer(schema: Arc<SchemaDefinition>, path: &str, batch_size: usize) -> Result<ArrowWriter<File>, Box<dyn std::error::Error>> {
-    let props = WriterProperties::builder()
+pub fn writer_properties(row_group_size: usize) -> WriterProperties {
+    WriterProperties::builder()
         .set_compression(Compression::ZSTD(ZstdLevel::default()))
-        .set_max_row_group_size(batch_size)
-        .build();
+        .set_max_row_group_size(row_group_size)
+        .set_statistics_enabled(EnabledStatistics::Page)
+        .build()
+}
+
+pub fn new_arrow_writer(schema: Arc<SchemaDefinition>, path: &str, batch_size: usize) -> Result<ArrowWriter<File>, Box<dyn std::error::Error>> {
+    let props = writer_properties(batch_size);
     let file = OpenOptions::new()
         .read(true)
         .append(true)
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::error::Error;
+use arrow::array::{Array, AsArray, RecordBatch, StringArray};
+use arrow::datatypes::TimestampMillisecondType;
+use chrono::{DateTime, NaiveDate};
+
+/// Fields of an event that can be used as a hive style partition (`name=value`) in uploaded objects.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PartitionKey {
+    EventType,
+    AppId,
+    AppVersion,
+    Platform,
+    Date,
+    Hour,
+}
+
+impl PartitionKey {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PartitionKey::EventType => "event_type",
+            PartitionKey::AppId => "app_id",
+            PartitionKey::AppVersion => "app_version",
+            PartitionKey::Platform => "platform",
+            PartitionKey::Date => "date",
+            PartitionKey::Hour => "hour",
+        }
+    }
+
+    pub fn parse_list(keys: &str) -> Result<Vec<PartitionKey>, Box<dyn Error>> {
+        keys.split(',')
+            .map(|key| key.trim())
+            .filter(|key| !key.is_empty())
+            .map(PartitionKey::try_from)
+            .collect()
+    }
+}
+
+impl TryFrom<&str> for PartitionKey {
+    type Error = Box<dyn Error>;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "event_type" => Ok(PartitionKey::EventType),
+            "app_id" => Ok(PartitionKey::AppId),
+            "app_version" => Ok(PartitionKey::AppVersion),
+            "platform" => Ok(PartitionKey::Platform),
+            "date" => Ok(PartitionKey::Date),
+            "hour" => Ok(PartitionKey::Hour),
+            _ => Err(format!("unknown partition key: {value}").into()),
+        }
+    }
+}
+
+fn partition_value(value: Option<&str>) -> String {
+    match value {
+        Some(value) if !value.is_empty() => value
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+            .collect(),
+        _ => "__HIVE_DEFAULT_PARTITION__".to_owned(),
+    }
+}
+
+fn context_string<'a>(batch: &'a RecordBatch, path: &[&str]) -> Result<&'a StringArray, Box<dyn Error>> {
+    let Some(mut column) = batch.column_by_name("context") else {
+        return Err("missing context column".into());
+    };
+    for name in path {
+        let Some(next) = column.as_struct().column_by_name(name) else {
+            return Err(format!("missing context column: {name}").into());
+        };
+        column = next;
+    }
+    Ok(column.as_string::<i32>())
+}
+
+/// Groups the rows of a batch by their partition path, e.g. `event_type=Session/app_id=app/date=2025-01-31`.
+pub fn partition_rows(batch: &RecordBatch, keys: &[PartitionKey]) -> Result<HashMap<String, Vec<u32>>, Box<dyn Error>> {
+    let mut columns: Vec<Vec<String>> = Vec::with_capacity(keys.len());
+    for key in keys {
+        let values = match key {
+            PartitionKey::EventType => {
+                let Some(column) = batch.column_by_name("type") else {
+                    return Err("missing type column".into());
+                };
+                let column = column.as_string::<i32>();
+                (0..batch.num_rows())
+                    .map(|i| partition_value(column.is_valid(i).then(|| column.value(i))))
+                    .collect()
+            }
+            PartitionKey::AppId | PartitionKey::AppVersion | PartitionKey::Platform => {
+                let column = match key {
+                    PartitionKey::AppId => context_string(batch, &["app_id"])?,
+                    PartitionKey::AppVersion => context_string(batch, &["app_version"])?,
+                    _ => context_string(batch, &["device", "platform"])?,
+                };
+                (0..batch.num_rows())
+                    .map(|i| partition_value(column.is_valid(i).then(|| column.value(i))))
+                    .collect()
+            }
+            PartitionKey::Date | PartitionKey::Hour => {
+                let Some(column) = batch.column_by_name("created") else {
+                    return Err("missing created column".into());
+                };
+                let column = column.as_primitive::<TimestampMillisecondType>();
+                let format = if *key == PartitionKey::Date { "%Y-%m-%d" } else { "%H" };
+                (0..batch.num_rows())
+                    .map(|i| {
+                        let value = column
+                            .is_valid(i)
+                            .then(|| DateTime::from_timestamp_millis(column.value(i)))
+                            .flatten()
+                            .map(|created| created.format(format).to_string());
+                        partition_value(value.as_deref())
+                    })
+                    .collect()
+            }
+        };
+        columns.push(values);
+    }
+    let mut partitions: HashMap<String, Vec<u32>> = HashMap::new();
+    for row in 0..batch.num_rows() {
+        let partition = keys
+            .iter()
+            .zip(columns.iter())
+            .map(|(key, values)| format!("{}={}", key.name(), values[row]))
+            .collect::<Vec<String>>()
+            .join("/");
+        partitions.entry(partition).or_default().push(row as u32);
+    }
+    Ok(partitions)
+}
+
+/// Whether an object can hold events created within `[from, to)`, judged by its `date=` partition.
+/// Objects without one might hold anything.
+pub fn may_contain(location: &str, from: Option<i64>, to: Option<i64>) -> bool {
+    let Some(date) = location
+        .split('/')
+        .find_map(|segment| segment.strip_prefix("date="))
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+    else {
+        return true;
+    };
+    let day = |millis: i64| DateTime::from_timestamp_millis(millis).map(|d| d.date_naive());
+    if let Some(from) = from.and_then(day) {
+        if date < from {
+            return false;
+        }
+    }
+    if let Some(to) = to.and_then(|to| day(to - 1)) {
+        if date > to {
+            return false;
+        }
+    }
+    true
+}
@@ -1,6 +1,9 @@
 use std::sync::Arc;
 use arrow::datatypes::{DataType, Field, FieldRef, Fields, Schema, TimeUnit};
 
+/// Stamped into every row, bump it whenever the shape of the schema changes.
+pub const SCHEMA_VERSION: u32 = 4;
+
 pub struct SchemaDefinition {
     pub schema: Arc<Schema>,
     pub content_struct: Fields,
@@ -33,6 +36,12 @@ impl SchemaDefinition {
         // Browser schema
         let browser_struct: Fields = vec![
             Field::new("agent", DataType::Utf8, false),
+            Field::new("family", DataType::Utf8, true),
+            Field::new("version", DataType::Utf8, true),
+            Field::new("os", DataType::Utf8, true),
+            Field::new("os_version", DataType::Utf8, true),
+            Field::new("device_class", DataType::Utf8, true),
+            Field::new("bot", DataType::Boolean, true),
         ].into();
 
         // Device schema
@@ -71,6 +80,9 @@ impl SchemaDefinition {
             Field::new("geo", DataType::Struct(geo_struct.clone()), false),
             Field::new("session_id", DataType::Utf8, false),
             Field::new("user_id", DataType::Utf8, true),
+            Field::new("analytics_consent", DataType::Boolean, true),
+            Field::new("filter_reason", DataType::Utf8, true),
+            Field::new("installation_verified", DataType::Boolean, true),
         ].into();
 
         // Top level schema (flattened)
@@ -86,6 +98,8 @@ impl SchemaDefinition {
             Field::new("sent_micros", DataType::UInt32, true),
             Field::new("received", DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())), false),
             Field::new("received_micros", DataType::UInt32, false),
+            // nullable so JSON written before versioning still converts, those rows are version 1
+            Field::new("schema_version", DataType::UInt32, true),
         ]);
 
         SchemaDefinition {
@@ -0,0 +1,184 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::Utc;
+use yaml_rust2::Yaml;
+use crate::events::{Event, Events};
+use crate::events_sink::{EventPipelineContext, EventSink};
+use crate::metrics::METRICS;
+
+const DEFAULT_WINDOW_SECS: u64 = 600;
+const DEFAULT_MAX_ENTRIES: usize = 100000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DedupKey {
+    /// client_id, session_id, created, created_micros and element id of every event.
+    Event,
+    /// The `batch_id` sent with the payload, falling back to the event key when there isn't one.
+    Batch,
+}
+
+#[derive(Clone)]
+pub struct DedupConfig {
+    pub key: DedupKey,
+    /// Shared by every worker's sink so a retried post is caught whichever worker it reaches, and
+    /// kept when the workers are recycled.
+    seen: Arc<Mutex<SeenSet>>,
+}
+
+impl TryFrom<&Yaml> for DedupConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        let window = Duration::from_secs(
+            yaml["window_secs"].as_i64().map(|s| s as u64).unwrap_or(DEFAULT_WINDOW_SECS),
+        );
+        let max_entries = yaml["max_entries"]
+            .as_i64()
+            .map(|s| s.max(1) as usize)
+            .unwrap_or(DEFAULT_MAX_ENTRIES);
+        Ok(Self {
+            key: match yaml["key"].as_str() {
+                None | Some("event") => DedupKey::Event,
+                Some("batch") => DedupKey::Batch,
+                Some(key) => return Err(format!("unknown dedup key: {key}").into()),
+            },
+            seen: Arc::new(Mutex::new(SeenSet::new(window, max_entries))),
+        })
+    }
+}
+
+/// Keys seen within the window, bounded by `max_entries`, the oldest are forgotten first.
+struct SeenSet {
+    window: i64,
+    max_entries: usize,
+    seen: HashMap<u64, i64>,
+    order: VecDeque<(i64, u64)>,
+}
+
+impl SeenSet {
+    fn new(window: Duration, max_entries: usize) -> Self {
+        Self {
+            window: window.as_millis() as i64,
+            max_entries,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records the key, returns true when it was already seen within the window.
+    fn insert(&mut self, key: u64, now: i64) -> bool {
+        while let Some((seen_at, oldest)) = self.order.front().copied() {
+            if seen_at >= now - self.window && self.order.len() < self.max_entries {
+                break;
+            }
+            self.order.pop_front();
+            if self.seen.get(&oldest) == Some(&seen_at) {
+                self.seen.remove(&oldest);
+            }
+        }
+        if self.seen.contains_key(&key) {
+            return true;
+        }
+        self.seen.insert(key, now);
+        self.order.push_back((now, key));
+        false
+    }
+}
+
+/// Drops events that were already written within the window, e.g. from a client retrying a post.
+pub struct DedupSink {
+    key: DedupKey,
+    seen: Arc<Mutex<SeenSet>>,
+    sink: Box<dyn EventSink + Send + Sync + 'static>,
+}
+
+impl DedupSink {
+    pub fn new(config: DedupConfig, sink: Box<dyn EventSink + Send + Sync + 'static>) -> Self {
+        Self {
+            key: config.key,
+            seen: config.seen,
+            sink,
+        }
+    }
+}
+
+fn event_key(events: &Events, event: &Event) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    events.context.app_id.hash(&mut hasher);
+    event.client_id.hash(&mut hasher);
+    events.context.session_id.hash(&mut hasher);
+    event.created.hash(&mut hasher);
+    event.created_micros.hash(&mut hasher);
+    event.element.id.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn batch_key(events: &Events, batch_id: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    events.context.app_id.hash(&mut hasher);
+    events.context.client_id.hash(&mut hasher);
+    batch_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn count_duplicate(events: &Events, event: &Event) {
+    METRICS
+        .events_duplicate
+        .with_label_values(&[&format!("{:?}", event.event_type), METRICS.app_id_label(&events.context.app_id)])
+        .inc();
+}
+
+#[async_trait::async_trait]
+impl EventSink for DedupSink {
+    async fn add(&mut self, context: &mut EventPipelineContext, events: &Events) -> Result<(), Box<dyn Error>> {
+        let now = Utc::now().timestamp_millis();
+        if self.key == DedupKey::Batch {
+            if let Some(batch_id) = &events.batch_id {
+                let duplicate = self.seen.lock().unwrap().insert(batch_key(events, batch_id), now);
+                if duplicate {
+                    for event in &events.events {
+                        count_duplicate(events, event);
+                    }
+                    return Ok(());
+                }
+                return self.sink.add(context, events).await;
+            }
+        }
+        let duplicates: Vec<bool> = {
+            let mut seen = self.seen.lock().unwrap();
+            events
+                .events
+                .iter()
+                .map(|event| seen.insert(event_key(events, event), now))
+                .collect()
+        };
+        if !duplicates.contains(&true) {
+            return self.sink.add(context, events).await;
+        }
+        let mut deduped = events.clone();
+        deduped.events.clear();
+        for (event, duplicate) in events.events.iter().zip(duplicates) {
+            if duplicate {
+                count_duplicate(events, event);
+            } else {
+                deduped.events.push(event.clone());
+            }
+        }
+        if deduped.events.is_empty() {
+            return Ok(());
+        }
+        self.sink.add(context, &deduped).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sink.flush().await
+    }
+
+    async fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sink.finish().await
+    }
+}
@@ -1,13 +1,15 @@
-use crate::writers::arrow::copy::copy_to_parquet;
+use crate::metrics::METRICS;
+use crate::writers::arrow::copy::{copy_to_parquet, BatchWriter};
+use crate::writers::arrow::parquet::partitioned::PartitionedWriter;
 use crate::writers::arrow::parquet::writer::new_arrow_writer;
+use crate::writers::arrow::partition::PartitionKey;
 use crate::writers::arrow::schema::SchemaDefinition;
 use crate::writers::writer::EventsWriter;
-use bytes::{Buf, Bytes, BytesMut};
+use crate::writers::state::{Conversion, PendingUpload, WatcherStateStore};
+use crate::writers::object_storage::{new_object_storage, object_key, ObjectStorageConfig, DEFAULT_OBJECT_KEY_TEMPLATE};
 use chrono::Utc;
-use log::{error, info};
-use object_store::aws::{AmazonS3, AmazonS3Builder};
+use log::{error, info, warn};
 use object_store::path::Path;
-use object_store::{MultipartUpload, ObjectStore, PutPayload};
 use std::error::Error;
 use std::{env, fs};
 use std::fs::{create_dir_all, File};
@@ -18,17 +20,11 @@ use std::os::macos::fs::MetadataExt;
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
 use std::path::PathBuf;
-use std::str::from_utf8;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::atomic::{AtomicBool, AtomicI64};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use futures_util::stream::BoxStream;
-use object_store::gcp::{GoogleCloudStorage, GoogleCloudStorageBuilder};
-use object_store::local::LocalFileSystem;
-use tokio::io::AsyncReadExt;
 use tokio::task;
-use ulid::Ulid;
 
 #[derive(Clone)]
 pub struct Config {
@@ -36,11 +32,60 @@ pub struct Config {
     pub batches_dir: String,
     pub pending_objects_dir: String,
     pub max_file_size: u64,
-    // TODO: store this on disk so that it survives restarts
-    pub last_full_sync: Arc<AtomicI64>,
+    pub object_storage: ObjectStorageConfig,
+    pub object_key_template: String,
+    pub partition_keys: Vec<PartitionKey>,
+    pub state: Arc<WatcherStateStore>,
 }
 
-const MAX_UPLOAD_CHUNK_SIZE: usize = 5242880;
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            batches_dir: if let Ok(batches_dir) = env::var("BATCHES_DIR") {
+                batches_dir
+            } else {
+                warn!("missing BATCHES_DIR, defaulting to ./analytics/batches");
+                "./analytics/batches".to_owned()
+            },
+            pending_objects_dir: if let Ok(objects_dir) = env::var("PENDING_OBJECTS_DIR") {
+                objects_dir
+            } else {
+                warn!("missing PENDING_OBJECTS_DIR, defaulting to ./analytics/objects");
+                "./analytics/objects".to_owned()
+            },
+            temp_dir: if let Ok(batches_dir) = env::var("TEMP_DIR") {
+                batches_dir
+            } else {
+                warn!("missing TEMP_DIR, defaulting to ./analytics/temp");
+                "./analytics/temp".to_owned()
+            },
+            max_file_size: if let Ok(size) = env::var("MAX_JSON_FILE_SIZE") {
+                if let Ok(size) = size.parse() {
+                    size
+                } else {
+                    warn!("invalid MAX_JSON_FILE_SIZE, defaulting to 250MB");
+                    262144000
+                }
+            } else {
+                warn!("missing MAX_JSON_FILE_SIZE, defaulting to 250MB");
+                262144000
+            },
+            object_storage: ObjectStorageConfig::from_env(),
+            object_key_template: env::var("OBJECT_KEY_TEMPLATE")
+                .unwrap_or(DEFAULT_OBJECT_KEY_TEMPLATE.to_owned()),
+            partition_keys: match env::var("PARTITION_KEYS") {
+                Ok(keys) => PartitionKey::parse_list(&keys).unwrap_or_else(|e| {
+                    warn!("invalid PARTITION_KEYS, defaulting to no partitions: {e:?}");
+                    Vec::new()
+                }),
+                Err(_) => Vec::new(),
+            },
+            state: Arc::new(WatcherStateStore::load(
+                &env::var("STATE_FILE").unwrap_or("./analytics/state.json".to_owned()),
+            )),
+        }
+    }
+}
 
 pub fn find_file(index: usize, config: Config) -> Result<String, Box<dyn Error>> {
     if !PathBuf::from(&config.temp_dir).exists() {
@@ -80,6 +125,7 @@ pub async fn watch_files(
     config: Config,
     watching: Arc<AtomicBool>,
 ) {
+    let mut resumed = false;
     loop {
         if writer.is_stopped() {
             // TODO: is it necessary to interrupt the sleep if this happens?
@@ -105,11 +151,21 @@ pub async fn watch_files(
                     .unwrap();
             }
         }
-        if let Err(err) = watch_json(&writer, &schema, &config, false).await {
-            error!("error watching json: {err:?}");
-        }
-        if let Err(err) = watch_objects(&config).await {
-            error!("error watching objects: {err:?}");
+        {
+            let _running = config.state.running.lock().await;
+            if !resumed {
+                if let Err(err) = resume_conversion(&schema, &config).await {
+                    error!("error resuming conversion: {err:?}");
+                } else {
+                    resumed = true;
+                }
+            }
+            if let Err(err) = watch_json(&writer, &schema, &config, false).await {
+                error!("error watching json: {err:?}");
+            }
+            if let Err(err) = watch_objects(&config).await {
+                error!("error watching objects: {err:?}");
+            }
         }
         watching.store(false, Relaxed);
         tokio::time::sleep(Duration::from_secs(15)).await;
@@ -127,12 +183,10 @@ pub async fn watch_files_hourly(
             // TODO: is it necessary to interrupt the sleep if this happens?
             break;
         }
-        // TODO: once stored on disk, move this last
-        tokio::time::sleep(Duration::from_secs(3600)).await;
         let now = Utc::now().timestamp_millis();
-        if now > config.last_full_sync.load(Relaxed) + 3600000 {
-            error!("running hourly watch");
-            // TODO: Store on disk
+        if now > config.state.last_full_sync() + 3600000 {
+            info!("running hourly watch");
+            let _running = config.state.running.lock().await;
             watching.store(true, Relaxed);
             if let Err(err) = watch_json(&writer, &schema, &config, true).await {
                 error!("hourly error watching json: {err:?}");
@@ -140,76 +194,121 @@ pub async fn watch_files_hourly(
             if let Err(err) = watch_objects(&config).await {
                 error!("hourly error watching objects: {err:?}");
             }
+            if let Err(err) = config.state.update(|state| state.last_full_sync = now) {
+                error!("hourly error storing state: {err:?}");
+            }
             watching.store(false, Relaxed);
         }
+        let next = config.state.last_full_sync() + 3600000 - Utc::now().timestamp_millis();
+        tokio::time::sleep(Duration::from_millis(next.clamp(60000, 3600000) as u64)).await;
     }
 }
 
 async fn watch_objects(config: &Config) -> Result<(), Box<dyn Error>> {
-    if let Ok(mut read) = tokio::fs::read_dir(&config.pending_objects_dir).await {
-        // TODO: make this more generic for multiple object stores
-        let s3 = AmazonS3Builder::from_env().build()?;
-        while let Ok(Some(entry)) = read.next_entry().await {
-            if let Ok(file_type) = entry.file_type().await {
-                if file_type.is_file() {
-                    if let Ok(file_name) = entry.file_name().into_string() {
-                        if file_name.ends_with(".parquet") {
-                            info!(
-                                "processing upload for: {}/{}",
-                                config.pending_objects_dir, file_name
-                            );
-                            let metadata = entry.metadata().await?;
-                            let created = metadata.created()?;
-                            let utc = time::OffsetDateTime::UNIX_EPOCH
-                                + time::Duration::try_from(
-                                    created.duration_since(std::time::UNIX_EPOCH).unwrap(),
-                                )
-                                .unwrap();
-                            let path = Path::parse(format!(
-                                "ingest/raw/{}/{}/{}/events-{}.parquet",
-                                utc.year(),
-                                utc.month() as u8,
-                                utc.day(),
-                                Ulid::new().to_string(),
-                            ))?;
-                            let mut upload = s3.put_multipart(&path).await?;
-                            let mut buf = BytesMut::with_capacity(MAX_UPLOAD_CHUNK_SIZE);
-                            let file_name = format!("{}/{}", config.pending_objects_dir, file_name);
-                            let mut file = tokio::fs::File::open(&file_name).await?;
-                            let len = file.metadata().await?.len();
-                            let mut offset = 0;
-                            while offset < len {
-                                let chunk_len = file.read_buf(&mut buf).await?;
-                                let buf_len = buf.len();
-                                if buf_len >= MAX_UPLOAD_CHUNK_SIZE {
-                                    let copy = buf.copy_to_bytes(buf_len);
-                                    buf.clear();
-                                    upload.put_part(copy.into()).await?;
-                                }
-                                offset += chunk_len as u64;
-                            }
-                            if !buf.is_empty() {
-                                let copy = buf.copy_to_bytes(buf.len());
-                                buf.clear();
-                                upload.put_part(copy.into()).await?;
-                            }
-                            upload.complete().await?;
-                            if let Err(err) = tokio::fs::remove_file(&file_name).await {
-                                return Err(
-                                    format!("error deleting file: {file_name} {err:?}").into()
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+    // uploads that were recorded but whose file is gone finished before a restart
+    let mut finished = Vec::new();
+    for upload in config.state.get().uploads {
+        if !tokio::fs::try_exists(&upload.file).await.unwrap_or(true) {
+            finished.push(upload.file);
         }
-    } else {
+    }
+    if !finished.is_empty() {
+        config.state.update(|state| state.uploads.retain(|u| !finished.contains(&u.file)))?;
+    }
+    let Ok(objects) = find_pending_objects(&config.pending_objects_dir).await else {
         return Err("error processing object files".to_string().into());
+    };
+    METRICS.objects_pending_upload.set(objects.len() as i64);
+    if objects.is_empty() {
+        return Ok(());
+    }
+    let storage = new_object_storage(&config.object_storage)?;
+    for (file_name, partition) in objects {
+        info!("processing upload for: {file_name}");
+        // reuse the key of an interrupted upload so it's overwritten instead of duplicated
+        let pending = config.state.get().uploads.into_iter().find(|u| u.file == file_name);
+        let path = if let Some(pending) = pending {
+            info!("resuming upload for: {file_name} -> {}", pending.key);
+            Path::parse(pending.key)?
+        } else {
+            let metadata = tokio::fs::metadata(&file_name).await?;
+            let created = metadata.created()?;
+            let utc = time::OffsetDateTime::UNIX_EPOCH
+                + time::Duration::try_from(
+                    created.duration_since(std::time::UNIX_EPOCH).unwrap(),
+                )
+                .unwrap();
+            let path = object_key(&config.object_key_template, utc, &partition)?;
+            let upload = PendingUpload {
+                file: file_name.clone(),
+                key: path.to_string(),
+            };
+            config.state.update(|state| state.uploads.push(upload))?;
+            path
+        };
+        if let Err(err) = storage.put_file(&path, &file_name).await {
+            METRICS.upload_failures.inc();
+            return Err(err);
+        }
+        if let Err(err) = tokio::fs::remove_file(&file_name).await {
+            return Err(
+                format!("error deleting file: {file_name} {err:?}").into()
+            );
+        }
+        config.state.update(|state| state.uploads.retain(|u| u.file != file_name))?;
+        remove_empty_dirs(&config.pending_objects_dir, &file_name).await;
+        METRICS.objects_pending_upload.dec();
     }
     Ok(())
 }
 
+/// Checks that a probe file can be created and removed in the directory.
+pub async fn is_writable(dir: &str) -> bool {
+    let probe = format!("{dir}/.ready-{}", ulid::Ulid::new());
+    if tokio::fs::write(&probe, b"").await.is_err() {
+        return false;
+    }
+    tokio::fs::remove_file(&probe).await.is_ok()
+}
+
+/// Lists the parquet files waiting for upload along with their partition path, partitioned
+/// conversions are stored as `{pending_objects_dir}/batch-{millis}/{partition}/part.parquet`.
+async fn find_pending_objects(root: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut objects = Vec::new();
+    let mut dirs = vec![(root.to_owned(), Vec::<String>::new())];
+    while let Some((dir, parents)) = dirs.pop() {
+        let mut read = tokio::fs::read_dir(&dir).await?;
+        while let Ok(Some(entry)) = read.next_entry().await {
+            let Ok(file_type) = entry.file_type().await else {
+                continue;
+            };
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                let mut parents = parents.clone();
+                parents.push(file_name.clone());
+                dirs.push((format!("{dir}/{file_name}"), parents));
+            } else if file_type.is_file() && file_name.ends_with(".parquet") {
+                let partition = parents.iter().skip(1).cloned().collect::<Vec<String>>().join("/");
+                objects.push((format!("{dir}/{file_name}"), partition));
+            }
+        }
+    }
+    Ok(objects)
+}
+
+async fn remove_empty_dirs(root: &str, file_name: &str) {
+    let root = std::path::Path::new(root);
+    let mut dir = std::path::Path::new(file_name).parent();
+    while let Some(current) = dir {
+        if current == root || tokio::fs::remove_dir(current).await.is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
 async fn watch_json(
     writer: &Arc<EventsWriter>,
     schema: &Arc<SchemaDefinition>,
@@ -229,214 +328,164 @@ async fn watch_json(
                         let size = metadata.file_size();
                         file_sizes += size;
                     }
-                    files.push(entry);
-                }
-            }
-        }
-        if (ignore_file_size || file_sizes >= config.max_file_size) && file_sizes > 0 {
-            writer.recycle().await;
-            config
-                .last_full_sync
-                .store(Utc::now().timestamp_millis(), Relaxed);
-            let parquet_file = format!(
-                "{}/batch-{}.parquet",
-                &config.batches_dir,
-                Utc::now().timestamp_millis()
-            );
-            let finished_parquet_file = format!(
-                "{}/batch-{}.parquet",
-                &config.pending_objects_dir,
-                Utc::now().timestamp_millis()
-            );
-            let writer = Arc::new(Mutex::new(
-                new_arrow_writer(Arc::clone(schema), &parquet_file, 10000)?,
-            ));
-            let mut success = true;
-            for file in &files {
-                if let Ok(file_name) = file.file_name().into_string() {
-                    if file_name.starts_with("events-") && file_name.ends_with(".json") {
-                        info!("adding json file to parquet: {file_name}");
-                        let spawn_file = format!("{}/{}", &config.temp_dir, file_name);
-                        let spawn_writer = Arc::clone(&writer);
-                        let spawn_writer_schema = Arc::clone(schema);
-                        success = task::spawn_blocking(move || {
-                            match File::open(spawn_file) {
-                                Ok(file) => {
-                                    match copy_to_parquet(file, spawn_writer_schema, spawn_writer) {
-                                        Ok(has_records) => {
-                                            return has_records
-                                        }
-                                        Err(err) => {
-                                            error!("error copying file to parquet: {err:?}");
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("error opening file for parquet copy: {e:?}");
-                                    return false;
-                                }
-                            }
-                            true
-                        })
-                        .await
-                        .unwrap_or_else(|e| {
-                            error!("error copying file: {e:?}");
-                            false
-                        }) || success;
-                    }
-                }
-            }
-            if success {
-                let mut writer = writer.lock().unwrap();
-                if let Err(e) = writer.flush() {
-                    error!("error flushing parquet records: {e:?}");
-                    success = false;
-                } else if let Err(e) = writer.finish() {
-                    error!("error finishing parquet: {e:?}");
-                    success = false;
-                }
-            }
-            if success {
-                for file in files {
-                    if let Ok(file_name) = file.file_name().into_string() {
+                    if let Ok(file_name) = entry.file_name().into_string() {
                         if file_name.starts_with("events-") && file_name.ends_with(".json") {
-                            if let Err(err) = tokio::fs::remove_file(format!(
-                                "{}/{}",
-                                &config.temp_dir, file_name
-                            ))
-                            .await
-                            {
-                                return Err(format!("error deleting file: {err:?}").into());
-                            }
+                            files.push(format!("{}/{}", &config.temp_dir, file_name));
                         }
                     }
                 }
-                if let Err(err) = tokio::fs::rename(parquet_file, finished_parquet_file).await {
-                    return Err(format!("error deleting file: {err:?}").into());
-                }
-            } else if let Err(err) = tokio::fs::remove_file(parquet_file).await {
-                return Err(format!("error deleting file: {err:?}").into());
             }
         }
+        METRICS.json_pending_bytes.set(file_sizes as i64);
+        if (ignore_file_size || file_sizes >= config.max_file_size) && file_sizes > 0 {
+            writer.recycle().await;
+            let now = Utc::now().timestamp_millis();
+            // partitioned conversions write a directory of parquet files instead of a single file
+            let extension = if config.partition_keys.is_empty() { ".parquet" } else { "" };
+            let conversion = Conversion {
+                json_files: files,
+                parquet_file: format!("{}/batch-{}{}", &config.batches_dir, now, extension),
+                finished_parquet_file: format!(
+                    "{}/batch-{}{}",
+                    &config.pending_objects_dir, now, extension
+                ),
+            };
+            let state_conversion = conversion.clone();
+            config.state.update(|state| {
+                state.last_full_sync = now;
+                state.conversion = Some(state_conversion);
+            })?;
+            convert(schema, config, &conversion).await?;
+        }
     } else {
         return Err("error processing files".to_string().into());
     }
     Ok(())
 }
 
-fn new_filesystem_object_storage() -> ObjectStorage {
-    let current_dir = match env::var("STORAGE") {
-        Ok(dir) => std::path::Path::new(dir.as_str()).to_path_buf(),
-        _ => env::current_dir().unwrap().join(std::path::Path::new("files")),
+/// Finishes a conversion that was interrupted by a restart, using the same set of json files.
+async fn resume_conversion(schema: &Arc<SchemaDefinition>, config: &Config) -> Result<(), Box<dyn Error>> {
+    let Some(conversion) = config.state.get().conversion else {
+        return Ok(());
     };
-    let path = current_dir.as_path();
-    if !path.exists() {
-        create_dir_all(path).unwrap();
+    if tokio::fs::try_exists(&conversion.finished_parquet_file).await? {
+        info!("cleaning up finished conversion: {}", conversion.finished_parquet_file);
+        remove_json_files(&conversion).await?;
+        config.state.update(|state| state.conversion = None)?;
+        return Ok(());
     }
-    info!("Using file object storage at path: {path:?}");
-    ObjectStorage::new(ObjectStorageInterface::FileSystem(Arc::new(
-        LocalFileSystem::new_with_prefix(path).unwrap(),
-    )))
-}
-
-fn new_s3_object_storage() -> ObjectStorage {
-    info!("Using s3 object storage");
-    ObjectStorage::new(ObjectStorageInterface::S3(Arc::new(
-        AmazonS3Builder::from_env().build().unwrap(),
-    )))
-}
-
-fn new_gcp_object_storage() -> ObjectStorage {
-    info!("Using gcp object storage");
-    ObjectStorage::new(ObjectStorageInterface::GCP(Arc::new(
-        GoogleCloudStorageBuilder::from_env().build().unwrap(),
-    )))
-}
-
-pub fn new_object_storage() -> ObjectStorage {
-    match env::var("STORAGE") {
-        Ok(name) => match name.as_str() {
-            "s3" => new_s3_object_storage(),
-            "gcp" => new_gcp_object_storage(),
-            _ => new_filesystem_object_storage(),
-        },
-        _ => new_filesystem_object_storage(),
+    info!("resuming conversion: {}", conversion.parquet_file);
+    if tokio::fs::try_exists(&conversion.parquet_file).await? {
+        remove_parquet(&conversion.parquet_file).await?;
     }
+    convert(schema, config, &conversion).await
 }
 
-
-#[derive(Clone)]
-pub struct ObjectStorage {
-    interface: Arc<ObjectStorageInterface>,
-}
-
-pub enum ObjectStorageInterface {
-    FileSystem(Arc<LocalFileSystem>),
-    S3(Arc<AmazonS3>),
-    #[allow(clippy::upper_case_acronyms)]
-    GCP(Arc<GoogleCloudStorage>),
-}
-
-impl ObjectStorage {
-    pub fn new(interface: ObjectStorageInterface) -> Self {
-        Self {
-            interface: Arc::new(interface),
+async fn remove_json_files(conversion: &Conversion) -> Result<(), Box<dyn Error>> {
+    for file_name in &conversion.json_files {
+        if let Err(err) = tokio::fs::remove_file(file_name).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(format!("error deleting file: {err:?}").into());
+            }
         }
     }
+    Ok(())
+}
 
-    pub async fn get(&self, location: &Path) -> Result<String, object_store::Error> {
-        let result = match &self.interface.as_ref() {
-            ObjectStorageInterface::FileSystem(fs) => fs.get(location),
-            ObjectStorageInterface::S3(fs) => fs.get(location),
-            ObjectStorageInterface::GCP(fs) => fs.get(location),
-        }
-            .await?;
-        let bytes = result.bytes().await?;
-        Ok(from_utf8(&bytes).unwrap().to_string())
+async fn remove_parquet(path: &str) -> Result<(), std::io::Error> {
+    if tokio::fs::metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
     }
+}
 
-    pub async fn get_buffer(
-        &self,
-        location: &Path,
-    ) -> Result<BoxStream<'static, object_store::Result<Bytes>>, object_store::Error> {
-        let result = match &self.interface.as_ref() {
-            ObjectStorageInterface::FileSystem(fs) => fs.get(location),
-            ObjectStorageInterface::S3(fs) => fs.get(location),
-            ObjectStorageInterface::GCP(fs) => fs.get(location),
-        }
-            .await?;
-        let stream = result.into_stream();
-        Ok(stream)
+async fn copy_json_files<W: BatchWriter + Send + 'static>(
+    schema: &Arc<SchemaDefinition>,
+    json_files: &[String],
+    writer: Arc<Mutex<W>>,
+) -> bool {
+    let mut success = true;
+    for file_name in json_files {
+        info!("adding json file to parquet: {file_name}");
+        let spawn_file = file_name.clone();
+        let spawn_writer = Arc::clone(&writer);
+        let spawn_writer_schema = Arc::clone(schema);
+        success = task::spawn_blocking(move || {
+            match File::open(spawn_file) {
+                Ok(file) => {
+                    match copy_to_parquet(file, spawn_writer_schema, spawn_writer) {
+                        Ok(has_records) => {
+                            return has_records
+                        }
+                        Err(err) => {
+                            error!("error copying file to parquet: {err:?}");
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("error opening file for parquet copy: {e:?}");
+                    return false;
+                }
+            }
+            true
+        })
+        .await
+        .unwrap_or_else(|e| {
+            error!("error copying file: {e:?}");
+            false
+        }) || success;
     }
+    success
+}
 
-    pub async fn delete(&self, location: &Path) -> Result<(), object_store::Error> {
-        match &self.interface.as_ref() {
-            ObjectStorageInterface::FileSystem(fs) => fs.delete(location),
-            ObjectStorageInterface::S3(s3) => s3.delete(location),
-            ObjectStorageInterface::GCP(fs) => fs.delete(location),
+async fn convert(
+    schema: &Arc<SchemaDefinition>,
+    config: &Config,
+    conversion: &Conversion,
+) -> Result<(), Box<dyn Error>> {
+    let parquet_file = &conversion.parquet_file;
+    let success = if config.partition_keys.is_empty() {
+        let writer = Arc::new(Mutex::new(
+            new_arrow_writer(Arc::clone(schema), parquet_file, 10000)?,
+        ));
+        let mut success = copy_json_files(schema, &conversion.json_files, Arc::clone(&writer)).await;
+        if success {
+            let mut writer = writer.lock().unwrap();
+            if let Err(e) = writer.flush() {
+                error!("error flushing parquet records: {e:?}");
+                success = false;
+            } else if let Err(e) = writer.finish() {
+                error!("error finishing parquet: {e:?}");
+                success = false;
+            }
         }
-            .await?;
-        Ok(())
-    }
-
-    pub async fn put_multipart(&self, location: &Path) -> Result<Box<dyn MultipartUpload>, object_store::Error> {
-        match &self.interface.as_ref() {
-            ObjectStorageInterface::FileSystem(fs) => fs.put_multipart(location),
-            ObjectStorageInterface::S3(fs) => fs.put_multipart(location),
-            ObjectStorageInterface::GCP(fs) => fs.put_multipart(location),
+        success
+    } else {
+        let writer = Arc::new(Mutex::new(PartitionedWriter::new(
+            Arc::clone(schema),
+            parquet_file,
+            config.partition_keys.clone(),
+            10000,
+        )?));
+        let mut success = copy_json_files(schema, &conversion.json_files, Arc::clone(&writer)).await;
+        if success {
+            if let Err(e) = writer.lock().unwrap().finish() {
+                error!("error finishing parquet: {e:?}");
+                success = false;
+            }
         }
-            .await
-    }
-
-    pub async fn put(&self, location: &Path, bytes: Bytes) -> Result<(), object_store::Error> {
-        let payload = PutPayload::from(bytes);
-        match &self.interface.as_ref() {
-            ObjectStorageInterface::FileSystem(fs) => fs.put(location, payload),
-            ObjectStorageInterface::S3(fs) => fs.put(location, payload),
-            ObjectStorageInterface::GCP(fs) => fs.put(location, payload),
+        success
+    };
+    if success {
+        // move the parquet file first, a crash before the json files are removed is then finished by resume_conversion
+        if let Err(err) = tokio::fs::rename(parquet_file, &conversion.finished_parquet_file).await {
+            return Err(format!("error deleting file: {err:?}").into());
         }
-            .await?;
-        Ok(())
+        remove_json_files(conversion).await?;
+    } else if let Err(err) = remove_parquet(parquet_file).await {
+        return Err(format!("error deleting file: {err:?}").into());
     }
+    config.state.update(|state| state.conversion = None)?;
+    Ok(())
 }
-
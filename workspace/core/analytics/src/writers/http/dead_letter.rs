@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use http::header::CONTENT_TYPE;
+use log::{error, info, warn};
+use reqwest::Client;
+use ulid::Ulid;
+use crate::events::Events;
+use crate::writers::http::sink::is_retryable;
+use crate::writers::writer::EventsWriter;
+
+#[derive(Clone)]
+pub struct DeadLetterConfig {
+    pub dir: String,
+    pub replay_interval: Duration,
+}
+
+/// Spills a batch that couldn't be forwarded into the dead-letter directory as a single NDJSON line,
+/// it's replayed in the background.
+pub async fn write_dead_letter(dir: &str, events: &Events) -> Result<(), Box<dyn Error>> {
+    let name = format!("{dir}/events-{}.ndjson", Ulid::new());
+    write_events(dir, &name, events).await?;
+    warn!("wrote events to dead letter file: {name}");
+    Ok(())
+}
+
+/// Keeps a batch the upstream refused in the dead-letter directory without it ever being replayed.
+pub async fn write_rejected(dir: &str, events: &Events) -> Result<(), Box<dyn Error>> {
+    let name = rejected_file_name(dir);
+    write_events(dir, &name, events).await?;
+    error!("wrote rejected events to {name}");
+    Ok(())
+}
+
+async fn write_events(dir: &str, name: &str, events: &Events) -> Result<(), Box<dyn Error>> {
+    tokio::fs::create_dir_all(dir).await?;
+    let mut line = serde_json::to_string(events)?;
+    line.push('\n');
+    let temp_name = format!("{name}.tmp");
+    tokio::fs::write(&temp_name, line).await?;
+    tokio::fs::rename(&temp_name, name).await?;
+    Ok(())
+}
+
+pub async fn watch_dead_letters(writer: Arc<EventsWriter>, endpoint: String, config: DeadLetterConfig) {
+    let client = Client::new();
+    loop {
+        if writer.is_stopped() {
+            break;
+        }
+        if let Err(err) = replay_dead_letters(&client, &endpoint, &config.dir).await {
+            error!("error replaying dead letters: {err:?}");
+        }
+        tokio::time::sleep(config.replay_interval).await;
+    }
+}
+
+/// Lines the upstream refuses (4xx) are moved into a `rejected-*.ndjson` file, which isn't replayed,
+/// so they can't hold up the rest. Replay stops when the upstream is unavailable.
+async fn replay_dead_letters(client: &Client, endpoint: &str, dir: &str) -> Result<(), Box<dyn Error>> {
+    let Ok(mut read) = tokio::fs::read_dir(dir).await else {
+        return Ok(());
+    };
+    while let Ok(Some(entry)) = read.next_entry().await {
+        let Ok(file_name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !file_name.starts_with("events-") || !file_name.ends_with(".ndjson") {
+            continue;
+        }
+        let file_name = format!("{dir}/{file_name}");
+        let contents = match tokio::fs::read_to_string(&file_name).await {
+            Ok(contents) => contents,
+            Err(err) => {
+                let rejected_name = rejected_file_name(dir);
+                error!("can't read dead letter file {file_name}, moving it to {rejected_name}: {err:?}");
+                tokio::fs::rename(&file_name, &rejected_name).await?;
+                continue;
+            }
+        };
+        let lines: Vec<&str> = contents.lines().filter(|l| !l.is_empty()).collect();
+        let mut rejected = Vec::new();
+        let mut replayed = 0;
+        let mut unavailable = None;
+        for line in &lines {
+            let response = client
+                .post(endpoint)
+                .header(CONTENT_TYPE, "application/json")
+                .body(line.to_string())
+                .send()
+                .await;
+            match response {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) if !is_retryable(response.status()) => {
+                    warn!("dead letter replay rejected: {}", response.status());
+                    rejected.push(*line);
+                }
+                Ok(response) => {
+                    unavailable = Some(response.status().to_string());
+                    break;
+                }
+                Err(err) => {
+                    unavailable = Some(err.to_string());
+                    break;
+                }
+            }
+            replayed += 1;
+        }
+        if !rejected.is_empty() {
+            let rejected_name = rejected_file_name(dir);
+            let mut contents = rejected.join("\n");
+            contents.push('\n');
+            tokio::fs::write(&rejected_name, contents).await?;
+            error!("moved {} rejected dead letters to {rejected_name}", rejected.len());
+        }
+        if replayed == lines.len() {
+            info!("replayed dead letter file: {file_name}");
+            tokio::fs::remove_file(&file_name).await?;
+        } else {
+            if replayed > 0 {
+                let mut remaining = lines[replayed..].join("\n");
+                remaining.push('\n');
+                let temp_name = format!("{file_name}.tmp");
+                tokio::fs::write(&temp_name, remaining).await?;
+                tokio::fs::rename(&temp_name, &file_name).await?;
+            }
+            let error = unavailable.unwrap_or_default();
+            return Err(format!("upstream unavailable ({error}), {} dead letters remaining in {file_name}", lines.len() - replayed).into());
+        }
+    }
+    Ok(())
+}
+
+fn rejected_file_name(dir: &str) -> String {
+    format!("{dir}/rejected-{}.ndjson", Ulid::new())
+}
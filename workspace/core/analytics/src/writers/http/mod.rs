@@ -1 +1,2 @@
-pub mod sink;
\ No newline at end of file
+pub mod sink;
+pub mod dead_letter;
\ No newline at end of file
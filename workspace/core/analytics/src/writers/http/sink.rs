@@ -1,51 +1,143 @@
 use std::error::Error;
+use std::time::Duration;
+use http::header::RETRY_AFTER;
+use http::{HeaderMap, StatusCode};
+use log::warn;
 use reqwest::Client;
 use crate::events::Events;
 use crate::events_sink::{EventPipelineContext, EventSink};
 use crate::events_transform::EventTransform;
+use crate::writers::http::dead_letter::{write_dead_letter, write_rejected, DeadLetterConfig};
+
+#[derive(Clone)]
+/// Only used without a dead-letter directory, with one a failed batch is handed to it right away
+/// rather than holding up the worker while the upstream recovers.
+pub struct HttpRetryConfig {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for HttpRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
 
 pub struct HttpSink {
     transforms: Vec<Box<dyn EventTransform + Send + Sync + 'static>>,
     client: Client,
     endpoint: String,
+    retry: HttpRetryConfig,
+    dead_letter: Option<DeadLetterConfig>,
 }
 
 impl HttpSink {
-    pub fn new(transforms: Vec<Box<dyn EventTransform + Send + Sync + 'static>>, endpoint: String) -> Self {
+    pub fn new(
+        transforms: Vec<Box<dyn EventTransform + Send + Sync + 'static>>,
+        endpoint: String,
+        retry: HttpRetryConfig,
+        dead_letter: Option<DeadLetterConfig>,
+    ) -> Self {
         let client = Client::builder()
             .build()
             .unwrap();
-        Self { transforms, client, endpoint }
+        Self { transforms, client, endpoint, retry, dead_letter }
     }
+
+    async fn post(&self, events: &Events) -> Result<(), PostError> {
+        let response = self.client
+            .post(&self.endpoint)
+            .json(events)
+            .send()
+            .await
+            .map_err(|e| PostError::Retryable { error: e.to_string(), retry_after: None })?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let retry_after = retry_after(response.headers());
+        let txt = response.text().await.unwrap_or_default();
+        let error = format!("{status}: {txt}");
+        if is_retryable(status) {
+            Err(PostError::Retryable { error, retry_after })
+        } else {
+            Err(PostError::Rejected(error))
+        }
+    }
+
+    async fn send(&self, events: &Events) -> Result<(), Box<dyn Error>> {
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let (error, retry_after) = match self.post(events).await {
+                Ok(_) => return Ok(()),
+                Err(PostError::Retryable { error, retry_after }) => (error, retry_after),
+                Err(PostError::Rejected(error)) => {
+                    // sending the same batch again won't change the answer
+                    if let Some(dead_letter) = &self.dead_letter {
+                        warn!("events rejected by upstream: {error}");
+                        return write_rejected(&dead_letter.dir, events).await;
+                    }
+                    return Err(error.into());
+                }
+            };
+            // the replay loop retries it, so the worker can move on
+            if let Some(dead_letter) = &self.dead_letter {
+                warn!("error forwarding events, spilling them to the dead letters: {error}");
+                return write_dead_letter(&dead_letter.dir, events).await;
+            }
+            if attempt >= self.retry.max_attempts {
+                return Err(error.into());
+            }
+            // the upstream's Retry-After is honored, up to max_backoff
+            let delay = retry_after.map(|r| r.min(self.retry.max_backoff)).unwrap_or(backoff);
+            warn!("error forwarding events (attempt {attempt}), retrying in {delay:?}: {error}");
+            tokio::time::sleep(delay).await;
+            backoff = (backoff * 2).min(self.retry.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+enum PostError {
+    /// The upstream couldn't be reached, failed (5xx) or asked to slow down (429).
+    Retryable { error: String, retry_after: Option<Duration> },
+    /// The upstream refused the events (any other 4xx).
+    Rejected(String),
+}
+
+pub fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// `Retry-After` in seconds, the HTTP date form isn't supported.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
 }
 
 #[async_trait::async_trait]
 impl EventSink for HttpSink {
     async fn add(&mut self, context: &mut EventPipelineContext, events: &Events) -> Result<(), Box<dyn Error>> {
         if self.transforms.is_empty() {
-            let response = self.client
-                .post(&self.endpoint)
-                .json(events)
-                .send()
-                .await?;
-            if !response.status().is_success() {
-                let txt = response.text().await?;
-                return Err(txt.into())
-            }
+            self.send(events).await?;
         } else {
             let mut events = events.clone();
             for transform in self.transforms.iter() {
                 transform.transform(context, &mut events).await?;
             }
-            let response = self.client
-                .post(&self.endpoint)
-                .json(&events)
-                .send()
-                .await?;
-            if !response.status().is_success() {
-                let txt = response.text().await?;
-                return Err(txt.into())
+            if events.events.is_empty() {
+                return Ok(());
             }
+            self.send(&events).await?;
         }
         Ok(())
     }
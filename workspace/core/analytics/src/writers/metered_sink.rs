@@ -0,0 +1,47 @@
+use std::error::Error;
+use crate::events::Events;
+use crate::events_sink::{EventPipelineContext, EventSink};
+use crate::metrics::METRICS;
+
+/// Records latency and errors of the wrapped sink under its name.
+pub struct MeteredSink {
+    name: String,
+    sink: Box<dyn EventSink + Send + Sync + 'static>,
+}
+
+impl MeteredSink {
+    pub fn new(name: String, sink: Box<dyn EventSink + Send + Sync + 'static>) -> Self {
+        Self { name, sink }
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for MeteredSink {
+    async fn add(&mut self, context: &mut EventPipelineContext, events: &Events) -> Result<(), Box<dyn Error>> {
+        let timer = METRICS.sink_add_seconds.with_label_values(&[&self.name]).start_timer();
+        let result = self.sink.add(context, events).await;
+        timer.observe_duration();
+        if result.is_err() {
+            METRICS.sink_errors.with_label_values(&[&self.name, "add"]).inc();
+        }
+        result
+    }
+
+    async fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        let timer = METRICS.sink_flush_seconds.with_label_values(&[&self.name]).start_timer();
+        let result = self.sink.flush().await;
+        timer.observe_duration();
+        if result.is_err() {
+            METRICS.sink_errors.with_label_values(&[&self.name, "flush"]).inc();
+        }
+        result
+    }
+
+    async fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        let result = self.sink.finish().await;
+        if result.is_err() {
+            METRICS.sink_errors.with_label_values(&[&self.name, "finish"]).inc();
+        }
+        result
+    }
+}
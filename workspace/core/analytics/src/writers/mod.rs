@@ -1,6 +1,10 @@
 pub mod arrow;
 pub mod writer;
 mod worker;
+pub mod dedup_sink;
 pub mod files;
+pub mod metered_sink;
 pub mod multi_sink;
+pub mod object_storage;
+pub mod state;
 pub mod http;
\ No newline at end of file
@@ -9,7 +9,6 @@ pub struct MultiSink {
 }
 
 impl MultiSink {
-    #[allow(dead_code)]
     pub fn new(
         transforms: Vec<Box<dyn EventTransform + Send + Sync + 'static>>,
         sinks: Vec<Box<dyn EventSink + Send + Sync + 'static>>,
@@ -30,6 +29,10 @@ impl EventSink for MultiSink {
             for transform in self.transforms.iter() {
                 transform.transform(context, &mut events).await?;
             }
+            // a transform can drop every event, e.g. when consent is missing
+            if events.events.is_empty() {
+                return Ok(());
+            }
             for sink in self.sinks.iter_mut() {
                 sink.add(context, &events).await?;
             }
@@ -0,0 +1,257 @@
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::stream::BoxStream;
+use futures_util::TryStreamExt;
+use log::info;
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::gcp::{GoogleCloudStorage, GoogleCloudStorageBuilder};
+use object_store::local::LocalFileSystem;
+use object_store::memory::InMemory;
+use object_store::path::Path;
+use object_store::{MultipartUpload, ObjectMeta, ObjectStore, PutPayload};
+use std::env;
+use std::error::Error;
+use std::fs::create_dir_all;
+use std::str::from_utf8;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use ulid::Ulid;
+use yaml_rust2::Yaml;
+
+const MAX_UPLOAD_CHUNK_SIZE: usize = 5242880;
+
+pub const DEFAULT_OBJECT_KEY_TEMPLATE: &str = "ingest/raw/{year}/{month}/{day}/events-{ulid}.parquet";
+
+#[derive(Clone)]
+pub enum ObjectStorageConfig {
+    S3 { bucket: Option<String> },
+    #[allow(clippy::upper_case_acronyms)]
+    GCP { bucket: Option<String> },
+    FileSystem { path: String },
+    /// Kept in memory and shared by every clone of the configuration, only useful for tests.
+    Memory(Arc<InMemory>),
+}
+
+impl ObjectStorageConfig {
+    pub fn from_env() -> Self {
+        match env::var("OBJECT_STORAGE") {
+            Ok(name) => match name.as_str() {
+                "gcp" => ObjectStorageConfig::GCP { bucket: None },
+                "filesystem" => ObjectStorageConfig::FileSystem {
+                    path: env::var("OBJECT_STORAGE_PATH").unwrap_or("./analytics/uploads".to_owned()),
+                },
+                "memory" => ObjectStorageConfig::Memory(Arc::new(InMemory::new())),
+                _ => ObjectStorageConfig::S3 { bucket: None },
+            },
+            _ => ObjectStorageConfig::S3 { bucket: None },
+        }
+    }
+}
+
+impl TryFrom<&Yaml> for ObjectStorageConfig {
+    type Error = Box<dyn Error>;
+
+    fn try_from(yaml: &Yaml) -> Result<Self, Self::Error> {
+        let bucket = yaml["bucket"].as_str().map(|b| b.to_owned());
+        match yaml["type"].as_str() {
+            Some("s3") => Ok(ObjectStorageConfig::S3 { bucket }),
+            Some("gcp") => Ok(ObjectStorageConfig::GCP { bucket }),
+            Some("filesystem") => {
+                let Some(path) = yaml["path"].as_str() else {
+                    return Err("filesystem object storage is missing a path".into());
+                };
+                Ok(ObjectStorageConfig::FileSystem { path: path.to_owned() })
+            }
+            Some("memory") => Ok(ObjectStorageConfig::Memory(Arc::new(InMemory::new()))),
+            Some(name) => Err(format!("unknown object storage type: {name}").into()),
+            None => Err("object storage is missing a type".into()),
+        }
+    }
+}
+
+/// Expands `{year}`, `{month}`, `{day}`, `{hour}`, `{ulid}` and `{partition}` in an object key template.
+/// When the template has no `{partition}`, a non-empty partition is placed in front of the file name.
+/// An empty partition drops its segment from the key.
+pub fn object_key(template: &str, utc: time::OffsetDateTime, partition: &str) -> Result<Path, object_store::path::Error> {
+    let template = if partition.is_empty() || template.contains("{partition}") {
+        template.to_owned()
+    } else {
+        match template.rsplit_once('/') {
+            Some((dir, file)) => format!("{dir}/{{partition}}/{file}"),
+            None => format!("{{partition}}/{template}"),
+        }
+    };
+    let key = template
+        .replace("{partition}", partition)
+        .replace("{year}", &utc.year().to_string())
+        .replace("{month}", &(utc.month() as u8).to_string())
+        .replace("{day}", &utc.day().to_string())
+        .replace("{hour}", &utc.hour().to_string())
+        .replace("{ulid}", &Ulid::new().to_string());
+    // an empty partition leaves an empty segment behind, e.g. `raw//{ulid}.parquet`
+    let key = key.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>().join("/");
+    Path::parse(key)
+}
+
+pub fn new_object_storage(config: &ObjectStorageConfig) -> Result<ObjectStorage, Box<dyn Error>> {
+    Ok(match config {
+        ObjectStorageConfig::S3 { bucket } => {
+            info!("Using s3 object storage");
+            let mut builder = AmazonS3Builder::from_env();
+            if let Some(bucket) = bucket {
+                builder = builder.with_bucket_name(bucket);
+            }
+            ObjectStorage::new(ObjectStorageInterface::S3(Arc::new(builder.build()?)))
+        }
+        ObjectStorageConfig::GCP { bucket } => {
+            info!("Using gcp object storage");
+            let mut builder = GoogleCloudStorageBuilder::from_env();
+            if let Some(bucket) = bucket {
+                builder = builder.with_bucket_name(bucket);
+            }
+            ObjectStorage::new(ObjectStorageInterface::GCP(Arc::new(builder.build()?)))
+        }
+        ObjectStorageConfig::FileSystem { path } => {
+            let path = std::path::Path::new(path);
+            if !path.exists() {
+                create_dir_all(path)?;
+            }
+            info!("Using file object storage at path: {path:?}");
+            ObjectStorage::new(ObjectStorageInterface::FileSystem(Arc::new(
+                LocalFileSystem::new_with_prefix(path)?,
+            )))
+        }
+        ObjectStorageConfig::Memory(memory) => {
+            info!("Using in memory object storage");
+            ObjectStorage::new(ObjectStorageInterface::Memory(Arc::clone(memory)))
+        }
+    })
+}
+
+#[derive(Clone)]
+pub struct ObjectStorage {
+    interface: Arc<ObjectStorageInterface>,
+}
+
+pub enum ObjectStorageInterface {
+    FileSystem(Arc<LocalFileSystem>),
+    S3(Arc<AmazonS3>),
+    #[allow(clippy::upper_case_acronyms)]
+    GCP(Arc<GoogleCloudStorage>),
+    Memory(Arc<InMemory>),
+}
+
+impl ObjectStorage {
+    pub fn new(interface: ObjectStorageInterface) -> Self {
+        Self {
+            interface: Arc::new(interface),
+        }
+    }
+
+    pub async fn get(&self, location: &Path) -> Result<String, object_store::Error> {
+        let result = match &self.interface.as_ref() {
+            ObjectStorageInterface::FileSystem(fs) => fs.get(location),
+            ObjectStorageInterface::S3(fs) => fs.get(location),
+            ObjectStorageInterface::GCP(fs) => fs.get(location),
+            ObjectStorageInterface::Memory(fs) => fs.get(location),
+        }
+            .await?;
+        let bytes = result.bytes().await?;
+        Ok(from_utf8(&bytes).unwrap().to_string())
+    }
+
+    pub async fn get_bytes(&self, location: &Path) -> Result<Bytes, object_store::Error> {
+        let result = match &self.interface.as_ref() {
+            ObjectStorageInterface::FileSystem(fs) => fs.get(location),
+            ObjectStorageInterface::S3(fs) => fs.get(location),
+            ObjectStorageInterface::GCP(fs) => fs.get(location),
+            ObjectStorageInterface::Memory(fs) => fs.get(location),
+        }
+            .await?;
+        result.bytes().await
+    }
+
+    pub async fn list(&self, prefix: &Path) -> Result<Vec<ObjectMeta>, object_store::Error> {
+        match &self.interface.as_ref() {
+            ObjectStorageInterface::FileSystem(fs) => fs.list(Some(prefix)),
+            ObjectStorageInterface::S3(fs) => fs.list(Some(prefix)),
+            ObjectStorageInterface::GCP(fs) => fs.list(Some(prefix)),
+            ObjectStorageInterface::Memory(fs) => fs.list(Some(prefix)),
+        }
+            .try_collect()
+            .await
+    }
+
+    pub async fn get_buffer(
+        &self,
+        location: &Path,
+    ) -> Result<BoxStream<'static, object_store::Result<Bytes>>, object_store::Error> {
+        let result = match &self.interface.as_ref() {
+            ObjectStorageInterface::FileSystem(fs) => fs.get(location),
+            ObjectStorageInterface::S3(fs) => fs.get(location),
+            ObjectStorageInterface::GCP(fs) => fs.get(location),
+            ObjectStorageInterface::Memory(fs) => fs.get(location),
+        }
+            .await?;
+        let stream = result.into_stream();
+        Ok(stream)
+    }
+
+    pub async fn delete(&self, location: &Path) -> Result<(), object_store::Error> {
+        match &self.interface.as_ref() {
+            ObjectStorageInterface::FileSystem(fs) => fs.delete(location),
+            ObjectStorageInterface::S3(s3) => s3.delete(location),
+            ObjectStorageInterface::GCP(fs) => fs.delete(location),
+            ObjectStorageInterface::Memory(fs) => fs.delete(location),
+        }
+            .await?;
+        Ok(())
+    }
+
+    pub async fn put_multipart(&self, location: &Path) -> Result<Box<dyn MultipartUpload>, object_store::Error> {
+        match &self.interface.as_ref() {
+            ObjectStorageInterface::FileSystem(fs) => fs.put_multipart(location),
+            ObjectStorageInterface::S3(fs) => fs.put_multipart(location),
+            ObjectStorageInterface::GCP(fs) => fs.put_multipart(location),
+            ObjectStorageInterface::Memory(fs) => fs.put_multipart(location),
+        }
+            .await
+    }
+
+    pub async fn put(&self, location: &Path, bytes: Bytes) -> Result<(), object_store::Error> {
+        let payload = PutPayload::from(bytes);
+        match &self.interface.as_ref() {
+            ObjectStorageInterface::FileSystem(fs) => fs.put(location, payload),
+            ObjectStorageInterface::S3(fs) => fs.put(location, payload),
+            ObjectStorageInterface::GCP(fs) => fs.put(location, payload),
+            ObjectStorageInterface::Memory(fs) => fs.put(location, payload),
+        }
+            .await?;
+        Ok(())
+    }
+
+    /// Uploads a local file in chunks of at most `MAX_UPLOAD_CHUNK_SIZE`.
+    pub async fn put_file(&self, location: &Path, file_name: &str) -> Result<(), Box<dyn Error>> {
+        let mut upload = self.put_multipart(location).await?;
+        let mut buf = BytesMut::with_capacity(MAX_UPLOAD_CHUNK_SIZE);
+        let mut file = tokio::fs::File::open(file_name).await?;
+        let len = file.metadata().await?.len();
+        let mut offset = 0;
+        while offset < len {
+            let chunk_len = file.read_buf(&mut buf).await?;
+            let buf_len = buf.len();
+            if buf_len >= MAX_UPLOAD_CHUNK_SIZE {
+                let copy = buf.copy_to_bytes(buf_len);
+                buf.clear();
+                upload.put_part(copy.into()).await?;
+            }
+            offset += chunk_len as u64;
+        }
+        if !buf.is_empty() {
+            let copy = buf.copy_to_bytes(buf.len());
+            buf.clear();
+            upload.put_part(copy.into()).await?;
+        }
+        upload.complete().await?;
+        Ok(())
+    }
+}
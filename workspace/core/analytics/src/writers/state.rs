@@ -0,0 +1,79 @@
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Conversion {
+    pub json_files: Vec<String>,
+    pub parquet_file: String,
+    pub finished_parquet_file: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingUpload {
+    pub file: String,
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct WatcherState {
+    pub last_full_sync: i64,
+    pub conversion: Option<Conversion>,
+    pub uploads: Vec<PendingUpload>,
+}
+
+/// Watcher state that survives restarts, every update is written through to disk.
+pub struct WatcherStateStore {
+    path: String,
+    state: Mutex<WatcherState>,
+    /// Held while converting or uploading so the regular and hourly watchers don't interleave.
+    pub running: tokio::sync::Mutex<()>,
+}
+
+impl WatcherStateStore {
+    pub fn load(path: &str) -> Self {
+        let state = match fs::read_to_string(path) {
+            Ok(contents) => match serde_json::from_str::<WatcherState>(&contents) {
+                Ok(state) => {
+                    info!("loaded watcher state from: {path}");
+                    state
+                }
+                Err(e) => {
+                    error!("invalid watcher state in {path}, starting fresh: {e:?}");
+                    WatcherState::default()
+                }
+            },
+            Err(_) => WatcherState::default(),
+        };
+        Self {
+            path: path.to_owned(),
+            state: Mutex::new(state),
+            running: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn get(&self) -> WatcherState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn last_full_sync(&self) -> i64 {
+        self.state.lock().unwrap().last_full_sync
+    }
+
+    pub fn update(&self, update: impl FnOnce(&mut WatcherState)) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        update(&mut state);
+        if let Some(parent) = PathBuf::from(&self.path).parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let temp_path = format!("{}.tmp", self.path);
+        fs::write(&temp_path, serde_json::to_string(&*state)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
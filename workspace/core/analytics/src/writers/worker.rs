@@ -3,43 +3,95 @@ use std::sync::atomic::{AtomicBool, AtomicI32};
 use std::sync::atomic::Ordering::Relaxed;
 use std::time::Duration;
 use log::{error, info};
-use tokio::sync::mpsc;
+use prometheus::IntGauge;
+use tokio::sync::{mpsc, Mutex};
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::time::timeout;
 use crate::events::Events;
 use crate::events_sink::{EventSink, EventPipelineContext};
+use crate::metrics::METRICS;
+use crate::writers::writer::WriteError;
 
+/// Everything one request accepted, queued as a single payload so it's either all written or
+/// none of it is.
 pub struct WriterPayload {
     context: EventPipelineContext,
-    events: Events,
+    events: Vec<Events>,
 }
 
+/// Held by the worker's task while it runs, left behind for `retire` to finish if the task ends
+/// without finishing it.
+type SharedSink = Arc<Mutex<Option<Box<dyn EventSink + Send + Sync + 'static>>>>;
+
 pub struct WriterWorker {
     stopped: Arc<AtomicBool>,
     active: Arc<AtomicI32>,
-    sink: Option<Box<dyn EventSink + Send + Sync + 'static>>,
+    sink: SharedSink,
     sender: Option<Sender<WriterPayload>>,
     queue_size: usize,
+    queue_depth: IntGauge,
 }
 
 impl WriterWorker {
-    pub fn new(stopped: Arc<AtomicBool>, active: Arc<AtomicI32>, sink: Box<dyn EventSink + Send + Sync + 'static>, queue_size: usize) -> Self {
+    pub fn new(index: usize, stopped: Arc<AtomicBool>, active: Arc<AtomicI32>, sink: Box<dyn EventSink + Send + Sync + 'static>, queue_size: usize) -> Self {
+        Self {
+            stopped,
+            active,
+            sink: Arc::new(Mutex::new(Some(sink))),
+            sender: None,
+            queue_size,
+            queue_depth: METRICS.worker_queue_depth.with_label_values(&[&index.to_string()]),
+        }
+    }
+
+    /// A worker whose sink couldn't be built. It's never started and is unhealthy, so the pool builds
+    /// it again the next time it's acquired.
+    pub fn unavailable(index: usize, stopped: Arc<AtomicBool>, active: Arc<AtomicI32>, queue_size: usize) -> Self {
         Self {
             stopped,
             active,
-            sink: Some(sink),
+            sink: Arc::new(Mutex::new(None)),
             sender: None,
             queue_size,
+            queue_depth: METRICS.worker_queue_depth.with_label_values(&[&index.to_string()]),
         }
     }
 
-    pub async fn write(&self, context: EventPipelineContext, events: Events) -> Result<(), SendError<WriterPayload>> {
+    /// Queues the events without waiting, fails with `WriteError::Overloaded` when the queue is full.
+    pub fn write(&self, context: EventPipelineContext, events: Vec<Events>) -> Result<(), WriteError> {
         let payload = WriterPayload {
             context,
             events,
         };
-        self.sender.as_ref().unwrap().send(payload).await
+        let Some(sender) = self.sender.as_ref() else {
+            return Err(WriteError::Closed);
+        };
+        let result = sender.try_send(payload).map_err(|e| match e {
+            TrySendError::Full(_) => WriteError::Overloaded,
+            TrySendError::Closed(_) => WriteError::Closed,
+        });
+        self.queue_depth.set((sender.max_capacity() - sender.capacity()) as i64);
+        result
+    }
+
+    /// False once the worker's task has stopped taking payloads, e.g. after its sink panicked.
+    pub fn is_healthy(&self) -> bool {
+        self.sender.as_ref().map(|sender| !sender.is_closed()).unwrap_or(false)
+    }
+
+    /// Called as the pool drops the worker. A running task finishes the sink once the queue closes,
+    /// this finishes it when the task already ended without doing so, e.g. after its sink panicked.
+    pub fn retire(mut self) {
+        self.sender = None;
+        let sink = Arc::clone(&self.sink);
+        tokio::spawn(async move {
+            if let Some(mut sink) = sink.lock().await.take() {
+                if let Err(e) = sink.finish().await {
+                    error!("error finishing retired worker sink: {e:?}");
+                }
+            }
+        });
     }
 
     pub fn start(&mut self) {
@@ -48,20 +100,32 @@ impl WriterWorker {
 
         let active = Arc::clone(&self.active);
         let stopped = Arc::clone(&self.stopped);
-        let sink = std::mem::take(&mut self.sink).unwrap();
+        let sink = Arc::clone(&self.sink);
 
         active.fetch_add(1, Relaxed);
-        tokio::spawn(Self::process(stopped, active, recv, sink));
+        tokio::spawn(Self::process(self.queue_depth.clone(), stopped, active, recv, sink));
     }
 
-    async fn process(stopped: Arc<AtomicBool>, active: Arc<AtomicI32>, mut recv: Receiver<WriterPayload>, mut sink: Box<dyn EventSink + Send + Sync>) {
+    async fn process(queue_depth: IntGauge, stopped: Arc<AtomicBool>, active: Arc<AtomicI32>, mut recv: Receiver<WriterPayload>, sink: SharedSink) {
+        let mut shared = sink.lock().await;
+        let Some(sink) = shared.as_mut() else {
+            // retired before the task got to start
+            active.fetch_add(-1, Relaxed);
+            return;
+        };
         let mut done = false;
         while !done && !stopped.load(Relaxed) && !recv.is_closed() {
-            match timeout(Duration::from_millis(3000), recv.recv()).await {
-                Ok(Some(mut payload)) => {
-                    if let Err(error) = sink.add(&mut payload.context, &payload.events).await {
-                        error!("error adding events to sink: {error:?}");
+            let received = timeout(Duration::from_millis(3000), recv.recv()).await;
+            match received {
+                Ok(Some(payload)) => {
+                    for events in &payload.events {
+                        let mut context = payload.context.clone();
+                        if let Err(error) = sink.add(&mut context, events).await {
+                            error!("error adding events to sink: {error:?}");
+                        }
                     }
+                    // writes set the depth as they queue, this lets it drain back down once they stop
+                    queue_depth.set(recv.len() as i64);
                 }
                 Ok(None) => {
                     info!("shutting down worker");
@@ -77,8 +141,10 @@ impl WriterWorker {
                 }
             }
         }
-        if let Err(e) = sink.finish().await {
-            error!("error finishing sink: {e:?}");
+        if let Some(mut sink) = shared.take() {
+            if let Err(e) = sink.finish().await {
+                error!("error finishing sink: {e:?}");
+            }
         }
         active.fetch_add(-1, Relaxed);
     }
@@ -1,37 +1,106 @@
 use std::error::Error;
-use std::sync::Arc;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicI32;
 use std::sync::atomic::Ordering::Relaxed;
-use tokio::sync::mpsc::error::SendError;
+use std::time::Duration;
+use log::error;
+use tokio::sync::Semaphore;
 use bosca_pool::Pool;
 use crate::events::Events;
 use crate::events_sink::{EventSink, EventPipelineContext};
-use crate::writers::worker::{WriterPayload, WriterWorker};
+use crate::writers::worker::WriterWorker;
+
+#[derive(Clone)]
+pub struct BackpressureConfig {
+    /// Requests being written at once, anything beyond is rejected right away.
+    pub max_in_flight: usize,
+    /// How long a request waits for a free worker before it is rejected.
+    pub acquire_timeout: Duration,
+    /// Sent back as `Retry-After` on rejected requests.
+    pub retry_after: Duration,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 1024,
+            acquire_timeout: Duration::from_millis(250),
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    /// The writer is shutting down.
+    Stopped,
+    /// Too many requests in flight, or the worker queues are full.
+    Overloaded,
+    /// The worker stopped receiving events.
+    Closed,
+}
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::Stopped => write!(f, "writer is stopped"),
+            WriteError::Overloaded => write!(f, "writer is overloaded"),
+            WriteError::Closed => write!(f, "writer worker is closed"),
+        }
+    }
+}
+
+impl Error for WriteError {}
 
 pub struct EventsWriter {
     active: Arc<AtomicI32>,
     stopped: Arc<AtomicBool>,
     pool: Pool<WriterWorker>,
+    in_flight: Semaphore,
+    backpressure: BackpressureConfig,
 }
 
 impl EventsWriter {
-    pub async fn new(pool_size: usize, worker_queue_size: usize, sink_factory: impl Fn(usize) -> Result<Box<dyn EventSink + Send + Sync>, Box<dyn Error>> + Send + Sync + 'static) -> Self {
+    /// The first sinks are built up front, so a pipeline that can't build them fails here. A sink that
+    /// can't be built later on leaves its worker unavailable until the pool tries again.
+    pub async fn new(pool_size: usize, worker_queue_size: usize, backpressure: BackpressureConfig, sink_factory: impl Fn(usize) -> Result<Box<dyn EventSink + Send + Sync>, Box<dyn Error>> + Send + Sync + 'static) -> Result<Self, Box<dyn Error>> {
         let active = Arc::new(AtomicI32::new(0));
         let stopped = Arc::new(AtomicBool::new(false));
         let worker_active = Arc::clone(&active);
         let worker_stopped = Arc::clone(&stopped);
+        let mut first_sinks = Vec::new();
+        for index in 0..pool_size {
+            first_sinks.push(Some(sink_factory(index)?));
+        }
+        let first_sinks = Mutex::new(first_sinks);
         let pool = Pool::new(pool_size, move |index| {
-            let sink = sink_factory(index).unwrap();
-            let mut worker = WriterWorker::new(Arc::clone(&worker_stopped), Arc::clone(&worker_active), sink, worker_queue_size);
+            let first_sink = first_sinks.lock().unwrap().get_mut(index).and_then(Option::take);
+            let sink = match first_sink {
+                Some(sink) => sink,
+                None => match sink_factory(index) {
+                    Ok(sink) => sink,
+                    Err(e) => {
+                        error!("error creating the sink of writer worker {index}: {e:?}");
+                        return WriterWorker::unavailable(index, Arc::clone(&worker_stopped), Arc::clone(&worker_active), worker_queue_size);
+                    }
+                },
+            };
+            let mut worker = WriterWorker::new(index, Arc::clone(&worker_stopped), Arc::clone(&worker_active), sink, worker_queue_size);
             worker.start();
             worker
-        }).await;
-        Self {
+        })
+        .await
+        .with_health_check(|worker| worker.is_healthy())
+        .with_on_retire(|worker| worker.retire());
+        Ok(Self {
             active,
             stopped,
             pool,
-        }
+            in_flight: Semaphore::new(backpressure.max_in_flight),
+            backpressure,
+        })
     }
 
     pub async fn recycle(&self) {
@@ -51,18 +120,26 @@ impl EventsWriter {
         self.active.load(Relaxed) > 0
     }
 
-    pub async fn write(&self, context: EventPipelineContext, events: Events) -> Result<(), SendError<WriterPayload>> {
-        assert!(!self.stopped.load(Relaxed));
-        let worker = self.pool.acquire().await;
-        match worker.object.write(context, events).await {
-            Ok(_) => {
-                assert!(self.pool.release(worker).await.err().is_none());
-                Ok(())
-            },
-            Err(e) => {
-                assert!(self.pool.release(worker).await.err().is_none());
-                Err(e)
-            }
+    pub fn retry_after(&self) -> Duration {
+        self.backpressure.retry_after
+    }
+
+    /// Writes every `Events` document of a request through one worker, they're queued together so
+    /// a rejected write leaves none of them written.
+    pub async fn write(&self, context: EventPipelineContext, events: Vec<Events>) -> Result<(), WriteError> {
+        if self.is_stopped() {
+            return Err(WriteError::Stopped);
         }
+        let Ok(_permit) = self.in_flight.try_acquire() else {
+            return Err(WriteError::Overloaded);
+        };
+        let Ok(worker) = self.pool.acquire_timeout(self.backpressure.acquire_timeout).await else {
+            return Err(if self.is_stopped() { WriteError::Stopped } else { WriteError::Overloaded });
+        };
+        let result = worker.object.write(context, events);
+        if let Err(e) = self.pool.release(worker).await {
+            error!("error releasing writer worker: {e:?}");
+        }
+        result
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,22 @@
+create type workflow_schedule_catch_up as enum ('skip', 'once', 'all');
+
+alter table workflow_schedules
+    alter column ends drop not null;
+
+alter table workflow_schedules
+    alter column last_scheduled drop not null;
+
+alter table workflow_schedules
+    alter column last_run drop not null;
+
+alter table workflow_schedules
+    alter column next_run drop not null;
+
+-- existing schedules skip what they missed rather than all firing on the first pass
+alter table workflow_schedules
+    add column catch_up workflow_schedule_catch_up not null default 'skip';
+
+alter table workflow_schedules
+    alter column catch_up set default 'once';
+
+create index workflow_schedules_next_run on workflow_schedules (next_run) where enabled;
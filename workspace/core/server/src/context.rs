@@ -334,6 +334,7 @@ impl BoscaContext {
             workflow_schedule: WorkflowScheduleDataStore::new(
                 bosca_pool.clone(),
                 Arc::clone(&notifier),
+                redis_jobs_queue_client.clone(),
             ),
             configuration,
             profile: ProfileDataStore::new(bosca_pool.clone()),
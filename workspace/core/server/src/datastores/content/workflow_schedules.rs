@@ -1,21 +1,167 @@
+use crate::context::BoscaContext;
 use crate::datastores::notifier::Notifier;
+use crate::models::workflow::enqueue_request::EnqueueRequest;
+use crate::models::workflow::workflow_schedule::{WorkflowSchedule, WorkflowScheduleInput};
+use crate::models::workflow::workflow_schedule_catch_up::WorkflowScheduleCatchUp;
+use crate::redis::RedisClient;
+use crate::util::RUNNING_BACKGROUND;
 use async_graphql::*;
+use chrono::{DateTime, TimeDelta, Utc};
 use deadpool_postgres::GenericClient;
+use redis::Script;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
-use log::error;
+use std::time::Duration;
+use log::{error, info, warn};
+use tokio::time::sleep;
 use uuid::Uuid;
 use bosca_database::TracingPool;
-use crate::models::workflow::workflow_schedule::{WorkflowSchedule, WorkflowScheduleInput};
+
+const SCHEDULER_LEADER_KEY: &str = "workflow::schedules::leader";
+const SCHEDULER_LEASE_MILLIS: i64 = 30000;
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(10);
+/// How late an occurrence may be and still run when missed occurrences are skipped.
+const SKIP_GRACE_SECONDS: i64 = 60;
+/// Missed occurrences looked at per schedule and pass, any beyond it are dropped.
+const MAX_CATCH_UP: u16 = 1000;
 
 #[derive(Clone)]
 pub struct WorkflowScheduleDataStore {
     pool: TracingPool,
     notifier: Arc<Notifier>,
+    redis: RedisClient,
 }
 
 impl WorkflowScheduleDataStore {
-    pub fn new(pool: TracingPool, notifier: Arc<Notifier>) -> Self {
-        Self { pool, notifier }
+    pub fn new(pool: TracingPool, notifier: Arc<Notifier>, redis: RedisClient) -> Self {
+        Self { pool, notifier, redis }
+    }
+
+    /// Runs due schedules on whichever server holds the scheduler lease, the others stand by in case
+    /// it goes away.
+    pub fn start_scheduling(&self, ctx: &BoscaContext) {
+        let bosca_type = option_env!("BOSCA_TYPE").unwrap_or("").to_string();
+        if bosca_type == "frontend" {
+            return;
+        }
+
+        info!("starting background workflow scheduling");
+        let ds = self.clone();
+        let ctx = ctx.clone();
+        let instance_id = Uuid::new_v4().to_string();
+        tokio::task::spawn(async move {
+            loop {
+                RUNNING_BACKGROUND.fetch_add(1, Relaxed);
+                match ds.try_lead(&instance_id).await {
+                    Ok(true) => {
+                        if let Err(e) = ds.run_due_schedules(&ctx, Utc::now()).await {
+                            error!(target: "workflow", "failed to run workflow schedules: {e:?}");
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => error!(target: "workflow", "failed to acquire the scheduler lease: {e:?}"),
+                }
+                RUNNING_BACKGROUND.fetch_add(-1, Relaxed);
+                sleep(SCHEDULER_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Takes the scheduler lease when nobody holds it, or extends it when this instance does.
+    async fn try_lead(&self, instance_id: &str) -> Result<bool, Error> {
+        let pooled_connection = self.redis.get().await?;
+        let mut connection = pooled_connection.get_connection().await?;
+        let script = Script::new(
+            r"
+            local leader = redis.call('GET', KEYS[1])
+            if not leader then
+                redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+                return 1
+            end
+            if leader == ARGV[1] then
+                redis.call('PEXPIRE', KEYS[1], ARGV[2])
+                return 1
+            end
+            return 0
+        ",
+        );
+        let result: i32 = script
+            .key(SCHEDULER_LEADER_KEY)
+            .arg(instance_id)
+            .arg(SCHEDULER_LEASE_MILLIS)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok(result == 1)
+    }
+
+    #[tracing::instrument(skip(self, ctx, now))]
+    async fn run_due_schedules(&self, ctx: &BoscaContext, now: DateTime<Utc>) -> Result<(), Error> {
+        for schedule in self.get_due(&now).await? {
+            if let Err(e) = self.run_schedule(ctx, &schedule, now).await {
+                error!(target: "workflow", "failed to run workflow schedule {}: {e:?}", schedule.id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_schedule(&self, ctx: &BoscaContext, schedule: &WorkflowSchedule, now: DateTime<Utc>) -> Result<(), Error> {
+        let Some(next_run) = schedule.next_run else {
+            return Ok(());
+        };
+        let runs: Vec<DateTime<Utc>> = match schedule.catch_up {
+            WorkflowScheduleCatchUp::Skip => schedule
+                .latest_occurrence(next_run, now)
+                .filter(|occurrence| now - *occurrence <= TimeDelta::seconds(SKIP_GRACE_SECONDS))
+                .into_iter()
+                .collect(),
+            WorkflowScheduleCatchUp::Once => schedule.latest_occurrence(next_run, now).into_iter().collect(),
+            WorkflowScheduleCatchUp::All => {
+                let runs = schedule.occurrences_between(next_run, now, MAX_CATCH_UP);
+                if let Some(last) = runs.last().filter(|_| runs.len() == MAX_CATCH_UP as usize) {
+                    let skipped = schedule.count_occurrences_between(*last + TimeDelta::milliseconds(1), now);
+                    if skipped > 0 {
+                        warn!(target: "workflow", "workflow schedule {} missed more than {MAX_CATCH_UP} occurrences, skipping {skipped} of them", schedule.id);
+                    }
+                }
+                runs
+            }
+        };
+        let last_run = runs.last().copied().or(schedule.last_run);
+        let next = schedule.next_occurrence(now);
+        // the schedule is moved forward before anything is enqueued, so a lease that changed hands
+        // mid pass can't run an occurrence twice
+        if !self.set_run(&schedule.id, &next_run, last_run, next, now).await? {
+            return Ok(());
+        }
+        for occurrence in runs {
+            info!(target: "workflow", "running workflow schedule {} for {occurrence}", schedule.id);
+            if let Err(e) = self.enqueue(ctx, schedule).await {
+                // put the schedule back at the occurrence that failed so the next pass retries it
+                self.restore_run(&schedule.id, &next, &occurrence).await?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn enqueue(&self, ctx: &BoscaContext, schedule: &WorkflowSchedule) -> Result<(), Error> {
+        let metadata_version = match &schedule.metadata_id {
+            Some(metadata_id) => match ctx.content.metadata.get(metadata_id).await? {
+                Some(metadata) => Some(metadata.version),
+                None => return Err(Error::new("missing metadata")),
+            },
+            None => None,
+        };
+        let mut request = EnqueueRequest {
+            workflow_id: Some(schedule.workflow_id.clone()),
+            metadata_id: schedule.metadata_id,
+            metadata_version,
+            collection_id: schedule.collection_id,
+            configurations: schedule.configurations()?,
+            ..Default::default()
+        };
+        ctx.workflow.enqueue_workflow(ctx, &mut request).await?;
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, id))]
@@ -46,16 +192,62 @@ impl WorkflowScheduleDataStore {
         Ok(result.first().map(|c| c.into()))
     }
 
+    #[tracing::instrument(skip(self, now))]
+    pub async fn get_due(&self, now: &DateTime<Utc>) -> Result<Vec<WorkflowSchedule>, Error> {
+        let connection = self.pool.get().await?;
+        let stmt = connection
+            .prepare_cached("select * from workflow_schedules where enabled and next_run <= $1 order by next_run")
+            .await?;
+        let result = connection.query(&stmt, &[now]).await?;
+        Ok(result.iter().map(|c| c.into()).collect())
+    }
+
+    /// Moves the schedule on from `previous_next_run`, false when it already was.
+    #[tracing::instrument(skip(self, id, previous_next_run, last_run, next_run, scheduled))]
+    pub async fn set_run(
+        &self,
+        id: &Uuid,
+        previous_next_run: &DateTime<Utc>,
+        last_run: Option<DateTime<Utc>>,
+        next_run: Option<DateTime<Utc>>,
+        scheduled: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let connection = self.pool.get().await?;
+        let stmt = connection
+            .prepare_cached("update workflow_schedules set last_run = $2, next_run = $3, last_scheduled = $4 where id = $1 and next_run = $5")
+            .await?;
+        let count = connection.execute(&stmt, &[id, &last_run, &next_run, &scheduled, previous_next_run]).await?;
+        if count > 0 {
+            self.on_schedule_changed(id).await?;
+        }
+        Ok(count > 0)
+    }
+
+    /// Moves the schedule back to `occurrence` after it was moved on to `next_run`, unless it has
+    /// changed since.
+    #[tracing::instrument(skip(self, id, next_run, occurrence))]
+    async fn restore_run(&self, id: &Uuid, next_run: &Option<DateTime<Utc>>, occurrence: &DateTime<Utc>) -> Result<(), Error> {
+        let connection = self.pool.get().await?;
+        let stmt = connection
+            .prepare_cached("update workflow_schedules set next_run = $2 where id = $1 and next_run is not distinct from $3")
+            .await?;
+        let count = connection.execute(&stmt, &[id, occurrence, next_run]).await?;
+        if count > 0 {
+            self.on_schedule_changed(id).await?;
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self, metadata_id, collection_id, schedule))]
     pub async fn add(&self, metadata_id: Option<Uuid>, collection_id: Option<Uuid>, schedule: &WorkflowScheduleInput) -> Result<Uuid, Error> {
         let schedule = schedule.create_schedule(metadata_id, collection_id)?;
         let mut connection = self.pool.get().await?;
         let txn = connection.transaction().await?;
         let stmt = txn
-            .prepare_cached("insert into workflow_schedules (metadata_id, collection_id, workflow_id, attributes, configuration, rrule, starts, ends, enabled) values ($1, $2, $3, $4, $5, $6, $7, $8, $9) returning id")
+            .prepare_cached("insert into workflow_schedules (metadata_id, collection_id, workflow_id, attributes, configuration, rrule, starts, ends, enabled, next_run, catch_up) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) returning id")
             .await?;
         let rrule = schedule.rrule.to_string();
-        let result = txn.query(&stmt, &[&schedule.metadata_id, &schedule.collection_id, &schedule.workflow_id, &schedule.attributes, &schedule.configuration, &rrule, &schedule.starts, &schedule.ends, &schedule.enabled]).await?;
+        let result = txn.query(&stmt, &[&schedule.metadata_id, &schedule.collection_id, &schedule.workflow_id, &schedule.attributes, &schedule.configuration, &rrule, &schedule.starts, &schedule.ends, &schedule.enabled, &schedule.next_run, &schedule.catch_up]).await?;
         let id = result.first().unwrap().get("id");
         txn.commit().await?;
         self.on_schedule_changed(&id).await?;
@@ -8,11 +8,13 @@ use crate::models::workflow::activities::{
     WorkflowActivityModel, WorkflowActivityParameter, WorkflowActivityPrompt,
     WorkflowActivityStorageSystem,
 };
+use crate::models::workflow::dead_letter::{WorkflowDeadLetter, WorkflowDeadLetterFilter};
 use crate::models::workflow::enqueue_request::EnqueueRequest;
 use crate::models::workflow::execution_plan::{
     WorkflowExecutionId, WorkflowExecutionPlan, WorkflowJob, WorkflowJobId,
 };
 use crate::models::workflow::models::{Model, ModelInput};
+use crate::models::workflow::priority::WorkflowPriority;
 use crate::models::workflow::prompts::{Prompt, PromptInput};
 use crate::models::workflow::states::{WorkflowState, WorkflowStateInput};
 use crate::models::workflow::storage_system_models::{StorageSystemModel, StorageSystemModelInput};
@@ -1421,6 +1423,39 @@ impl WorkflowDataStore {
         Ok(())
     }
 
+    #[tracing::instrument(skip(self, filter, offset, limit))]
+    pub async fn get_dead_letters(
+        &self,
+        filter: &WorkflowDeadLetterFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<WorkflowDeadLetter>, Error> {
+        self.queues.get_dead_letters(filter, offset, limit).await
+    }
+
+    #[tracing::instrument(skip(self, filter))]
+    pub async fn get_dead_letter_count(&self, filter: &WorkflowDeadLetterFilter) -> Result<i64, Error> {
+        self.queues.get_dead_letter_count(filter).await
+    }
+
+    #[tracing::instrument(skip(self, ids, context, job_context))]
+    pub async fn retry_dead_letters(
+        &self,
+        ids: &[WorkflowJobId],
+        context: Option<&Value>,
+        job_context: Option<&Value>,
+    ) -> Result<(), Error> {
+        self.queues.retry_dead_letters(ids, context, job_context).await
+    }
+
+    #[tracing::instrument(skip(self, ids))]
+    pub async fn discard_dead_letters(&self, ids: &[WorkflowJobId]) -> Result<(), Error> {
+        for plan in self.queues.discard_dead_letters(ids).await? {
+            self.notifier.workflow_plan_failed(&plan.id).await?;
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self, job_id, workflow_ids, delay_until))]
     pub async fn enqueue_job_child_workflows(
         &self,
@@ -1448,6 +1483,7 @@ impl WorkflowDataStore {
                 .as_ref()
                 .map(|id| Uuid::parse_str(id).unwrap()),
             delay_until,
+            priority: Some(plan.priority),
             ..Default::default()
         };
         for workflow_id in workflow_ids {
@@ -1489,6 +1525,7 @@ impl WorkflowDataStore {
             metadata_version: job.metadata_version,
             configurations,
             delay_until,
+            priority: Some(plan.priority),
             ..Default::default()
         };
         let plan = self.get_new_execution_plan(&mut request).await?;
@@ -1639,6 +1676,8 @@ impl WorkflowDataStore {
                 complete: false,
                 finished: None,
                 failures: 0,
+                retry_at: None,
+                failed_at: None,
             };
             if job.workflow_activity.execution_group == 1 {
                 current_execution_group.push(id.index);
@@ -1675,6 +1714,10 @@ impl WorkflowDataStore {
             finished: None,
             failure: false,
             max_failures: 10,
+            priority: request
+                .priority
+                .or_else(|| WorkflowPriority::from_configuration(&workflow.configuration))
+                .unwrap_or_default(),
         })
     }
 
@@ -1810,6 +1853,14 @@ impl WorkflowDataStore {
         self.queues.set_execution_plan_job_checkin(job_id).await
     }
 
+    #[tracing::instrument(skip(self, job_id))]
+    pub async fn get_job_lease_expiration(
+        &self,
+        job_id: &WorkflowJobId,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        self.queues.get_job_lease_expiration(job_id).await
+    }
+
     #[tracing::instrument(skip(self, job_id))]
     pub async fn set_execution_plan_job_complete(
         &self,
@@ -1833,11 +1884,12 @@ impl WorkflowDataStore {
         &self,
         job_id: &WorkflowJobId,
         error: &str,
+        error_class: Option<&str>,
         try_again: bool
     ) -> Result<(), Error> {
         let plan = self
             .queues
-            .set_execution_plan_job_failed(job_id, error, try_again)
+            .set_execution_plan_job_failed(job_id, error, error_class, try_again)
             .await?;
         if plan.finished.is_some() {
             self.notifier.workflow_plan_failed(&plan.id).await?;
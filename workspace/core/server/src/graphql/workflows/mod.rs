@@ -21,6 +21,9 @@ pub mod workflow_activity_model;
 pub mod workflow_activity_parameter;
 pub mod workflow_activity_prompt;
 pub mod workflow_activity_storage_system;
+pub mod workflow_dead_letter;
+pub mod workflow_dead_letters;
+pub mod workflow_dead_letters_mutation;
 pub mod workflow_execution_id;
 pub mod workflow_execution_plan;
 pub mod workflow_job;
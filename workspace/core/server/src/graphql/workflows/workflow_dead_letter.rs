@@ -0,0 +1,54 @@
+use crate::graphql::workflows::workflow_execution_plan::WorkflowExecutionPlanObject;
+use crate::graphql::workflows::workflow_job::WorkflowJobObject;
+use crate::graphql::workflows::workflow_job_id::WorkflowJobIdObject;
+use crate::models::workflow::dead_letter::WorkflowDeadLetter;
+use async_graphql::Object;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+pub struct WorkflowDeadLetterObject {
+    dead_letter: WorkflowDeadLetter,
+}
+
+impl WorkflowDeadLetterObject {
+    pub fn new(dead_letter: WorkflowDeadLetter) -> Self {
+        Self { dead_letter }
+    }
+}
+
+#[Object(name = "WorkflowDeadLetter")]
+impl WorkflowDeadLetterObject {
+    async fn id(&self) -> WorkflowJobIdObject {
+        WorkflowJobIdObject::new(self.dead_letter.job_id.clone())
+    }
+
+    async fn job(&self) -> Option<WorkflowJobObject> {
+        self.dead_letter.job().map(|job| WorkflowJobObject::new(job.clone()))
+    }
+
+    async fn plan(&self) -> WorkflowExecutionPlanObject {
+        WorkflowExecutionPlanObject::new(self.dead_letter.plan.clone())
+    }
+
+    async fn error(&self) -> Option<&String> {
+        self.dead_letter.job().and_then(|job| job.error.as_ref())
+    }
+
+    async fn failures(&self) -> i32 {
+        self.dead_letter.job().map(|job| job.failures).unwrap_or(0)
+    }
+
+    async fn failed_at(&self) -> Option<DateTime<Utc>> {
+        self.dead_letter.job().and_then(|job| job.failed_at)
+    }
+
+    async fn context(&self) -> &Option<Value> {
+        &self.dead_letter.plan.context
+    }
+}
+
+impl From<WorkflowDeadLetter> for WorkflowDeadLetterObject {
+    fn from(dead_letter: WorkflowDeadLetter) -> Self {
+        Self::new(dead_letter)
+    }
+}
@@ -0,0 +1,43 @@
+use crate::context::BoscaContext;
+use crate::datastores::security::WORKFLOW_MANAGERS_GROUP;
+use crate::graphql::workflows::workflow_dead_letter::WorkflowDeadLetterObject;
+use crate::models::workflow::dead_letter::WorkflowDeadLetterFilter;
+use crate::security::util::check_has_group;
+use async_graphql::{Context, Error, Object};
+
+/// Dead letters returned by one page at most.
+const MAX_LIMIT: i64 = 100;
+
+pub struct WorkflowDeadLettersObject {}
+
+#[Object(name = "WorkflowDeadLetters")]
+impl WorkflowDeadLettersObject {
+    async fn all(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<WorkflowDeadLetterFilter>,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<WorkflowDeadLetterObject>, Error> {
+        check_has_group(ctx, WORKFLOW_MANAGERS_GROUP).await?;
+        if offset < 0 || limit < 0 {
+            return Err(Error::new("offset and limit can't be negative"));
+        }
+        let limit = limit.min(MAX_LIMIT);
+        let ctx = ctx.data::<BoscaContext>()?;
+        let filter = filter.unwrap_or_default();
+        let dead_letters = ctx.workflow.get_dead_letters(&filter, offset, limit).await?;
+        Ok(dead_letters.into_iter().map(WorkflowDeadLetterObject::from).collect())
+    }
+
+    async fn count(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<WorkflowDeadLetterFilter>,
+    ) -> Result<i64, Error> {
+        check_has_group(ctx, WORKFLOW_MANAGERS_GROUP).await?;
+        let ctx = ctx.data::<BoscaContext>()?;
+        let filter = filter.unwrap_or_default();
+        ctx.workflow.get_dead_letter_count(&filter).await
+    }
+}
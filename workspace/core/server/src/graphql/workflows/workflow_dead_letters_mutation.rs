@@ -0,0 +1,56 @@
+use crate::context::BoscaContext;
+use crate::datastores::security::WORKFLOW_MANAGERS_GROUP;
+use crate::models::workflow::execution_plan::{WorkflowJobId, WorkflowJobIdInput};
+use crate::security::util::check_has_group;
+use async_graphql::{Context, Error, Object};
+use serde_json::Value;
+use uuid::Uuid;
+
+pub(crate) struct WorkflowDeadLettersMutationObject {}
+
+fn to_job_ids(ids: Vec<WorkflowJobIdInput>) -> Result<Vec<WorkflowJobId>, Error> {
+    let mut job_ids = Vec::new();
+    for id in ids {
+        job_ids.push(WorkflowJobId {
+            id: Uuid::parse_str(&id.id)?,
+            queue: id.queue,
+            index: id.index,
+        });
+    }
+    Ok(job_ids)
+}
+
+#[Object(name = "WorkflowDeadLettersMutation")]
+impl WorkflowDeadLettersMutationObject {
+    async fn retry(&self, ctx: &Context<'_>, ids: Vec<WorkflowJobIdInput>) -> Result<bool, Error> {
+        check_has_group(ctx, WORKFLOW_MANAGERS_GROUP).await?;
+        let ctx = ctx.data::<BoscaContext>()?;
+        let ids = to_job_ids(ids)?;
+        ctx.workflow.retry_dead_letters(&ids, None, None).await?;
+        Ok(true)
+    }
+
+    async fn retry_with_context(
+        &self,
+        ctx: &Context<'_>,
+        id: WorkflowJobIdInput,
+        context: Option<Value>,
+        job_context: Option<Value>,
+    ) -> Result<bool, Error> {
+        check_has_group(ctx, WORKFLOW_MANAGERS_GROUP).await?;
+        let ctx = ctx.data::<BoscaContext>()?;
+        let ids = to_job_ids(vec![id])?;
+        ctx.workflow
+            .retry_dead_letters(&ids, context.as_ref(), job_context.as_ref())
+            .await?;
+        Ok(true)
+    }
+
+    async fn discard(&self, ctx: &Context<'_>, ids: Vec<WorkflowJobIdInput>) -> Result<bool, Error> {
+        check_has_group(ctx, WORKFLOW_MANAGERS_GROUP).await?;
+        let ctx = ctx.data::<BoscaContext>()?;
+        let ids = to_job_ids(ids)?;
+        ctx.workflow.discard_dead_letters(&ids).await?;
+        Ok(true)
+    }
+}
@@ -7,6 +7,7 @@ use crate::graphql::workflows::workflow_job::WorkflowJobObject;
 use crate::graphql::workflows::workflow_job_id::WorkflowJobIdObject;
 use crate::models::security::permission::PermissionAction;
 use crate::models::workflow::execution_plan::WorkflowExecutionPlan;
+use crate::models::workflow::priority::WorkflowPriority;
 use async_graphql::{Context, Error, Object};
 use chrono::{DateTime, Utc};
 use serde_json::Value;
@@ -125,6 +126,9 @@ impl WorkflowExecutionPlanObject {
     async fn max_failures(&self) -> i32 {
         self.plan.max_failures
     }
+    async fn priority(&self) -> WorkflowPriority {
+        self.plan.priority
+    }
 }
 
 impl From<WorkflowExecutionPlan> for WorkflowExecutionPlanObject {
@@ -13,6 +13,7 @@ use crate::graphql::workflows::workflow_execution_id::WorkflowExecutionIdObject;
 use crate::graphql::workflows::workflow_job_id::WorkflowJobIdObject;
 use crate::models::workflow::execution_plan::WorkflowJob;
 use async_graphql::{Context, Error, Object};
+use chrono::{DateTime, Utc};
 use serde_json::Value;
 use uuid::Uuid;
 
@@ -160,6 +161,19 @@ impl WorkflowJobObject {
         self.job.failures
     }
 
+    async fn retry_at(&self) -> &Option<DateTime<Utc>> {
+        &self.job.retry_at
+    }
+
+    async fn failed_at(&self) -> &Option<DateTime<Utc>> {
+        &self.job.failed_at
+    }
+
+    async fn lease_expires(&self, ctx: &Context<'_>) -> Result<Option<DateTime<Utc>>, Error> {
+        let ctx = ctx.data::<BoscaContext>()?;
+        ctx.workflow.get_job_lease_expiration(&self.job.id).await
+    }
+
     async fn models(&self) -> Vec<WorkflowActivityModelObject> {
         self.job.models.iter().map(|p| p.clone().into()).collect()
     }
@@ -1,4 +1,5 @@
 use crate::models::workflow::workflow_schedule::WorkflowSchedule;
+use crate::models::workflow::workflow_schedule_catch_up::WorkflowScheduleCatchUp;
 use async_graphql::{Context, Error, Object};
 use chrono::{DateTime, Utc};
 use serde_json::Value;
@@ -80,6 +81,10 @@ impl WorkflowScheduleObject {
         &self.schedule.last_scheduled
     }
 
+    async fn catch_up(&self) -> WorkflowScheduleCatchUp {
+        self.schedule.catch_up
+    }
+
     async fn enabled(&self) -> bool {
         self.schedule.enabled
     }
@@ -10,6 +10,7 @@ use crate::graphql::workflows::traits::TraitsObject;
 use crate::graphql::workflows::transitions::TransitionsObject;
 use crate::graphql::workflows::workflow::WorkflowObject;
 use crate::graphql::workflows::workflow_activity::WorkflowActivityObject;
+use crate::graphql::workflows::workflow_dead_letters::WorkflowDeadLettersObject;
 use crate::graphql::workflows::workflow_execution_plan::WorkflowExecutionPlanObject;
 use crate::graphql::workflows::workflow_job::WorkflowJobObject;
 use crate::graphql::workflows::workflow_schedules::WorkflowSchedulesObject;
@@ -64,6 +65,10 @@ impl WorkflowsObject {
         WorkflowSchedulesObject {}
     }
 
+    async fn dead_letters(&self) -> WorkflowDeadLettersObject {
+        WorkflowDeadLettersObject {}
+    }
+
     async fn activities(&self) -> ActivitiesObject {
         ActivitiesObject {}
     }
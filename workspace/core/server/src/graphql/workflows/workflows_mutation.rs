@@ -9,6 +9,7 @@ use crate::graphql::workflows::storage_systems_mutation::StorageSystemsMutationO
 use crate::graphql::workflows::traits_mutation::TraitsMutationObject;
 use crate::graphql::workflows::transitions_mutation::TransitionsMutationObject;
 use crate::graphql::workflows::workflow::WorkflowObject;
+use crate::graphql::workflows::workflow_dead_letters_mutation::WorkflowDeadLettersMutationObject;
 use crate::graphql::workflows::workflow_execution_id::WorkflowExecutionIdObject;
 use crate::graphql::workflows::workflow_schedules_mutation::WorkflowSchedulesMutationObject;
 use crate::models::content::find_query::FindQueryInput;
@@ -17,6 +18,7 @@ use crate::models::workflow::enqueue_request::EnqueueRequest;
 use crate::models::workflow::execution_plan::{
     WorkflowExecutionIdInput, WorkflowJobId, WorkflowJobIdInput,
 };
+use crate::models::workflow::priority::WorkflowPriority;
 use crate::models::workflow::states::PENDING;
 use crate::models::workflow::transitions::BeginTransitionInput;
 use crate::models::workflow::workflows::WorkflowInput;
@@ -112,6 +114,10 @@ impl WorkflowsMutationObject {
         WorkflowSchedulesMutationObject {}
     }
 
+    async fn dead_letters(&self) -> WorkflowDeadLettersMutationObject {
+        WorkflowDeadLettersMutationObject {}
+    }
+
     async fn expire_all(&self, ctx: &Context<'_>) -> Result<bool, Error> {
         let ctx = ctx.data::<BoscaContext>()?;
         ctx.check_has_service_account().await?;
@@ -346,6 +352,7 @@ impl WorkflowsMutationObject {
         version: Option<i32>,
         configurations: Option<Vec<WorkflowConfigurationInput>>,
         delay_until: Option<DateTime<Utc>>,
+        priority: Option<WorkflowPriority>,
     ) -> Result<WorkflowExecutionIdObject, Error> {
         let ctx = ctx.data::<BoscaContext>()?;
         let metadata_id = metadata_id.map(|id| Uuid::parse_str(&id).unwrap());
@@ -374,6 +381,7 @@ impl WorkflowsMutationObject {
             profile_id,
             configurations,
             delay_until,
+            priority,
             ..Default::default()
         };
         let workflow = ctx.workflow.enqueue_workflow(ctx, &mut request).await?;
@@ -455,6 +463,7 @@ impl WorkflowsMutationObject {
         ctx: &Context<'_>,
         job_id: WorkflowJobIdInput,
         error: String,
+        error_class: Option<String>,
         try_again: bool,
     ) -> Result<bool, Error> {
         let ctx = ctx.data::<BoscaContext>()?;
@@ -464,7 +473,7 @@ impl WorkflowsMutationObject {
             job_id.queue, job_id.id, job_id.index, error
         );
         ctx.workflow
-            .set_execution_plan_job_failed(&job_id.into(), &error, try_again)
+            .set_execution_plan_job_failed(&job_id.into(), &error, error_class.as_deref(), try_again)
             .await?;
         Ok(true)
     }
@@ -79,6 +79,7 @@ async fn main() {
 
     ctx.workflow.start_monitoring_expirations();
     ctx.content.metadata.start_monitoring_storage_updates(&ctx);
+    ctx.workflow_schedule.start_scheduling(&ctx);
 
     let persisted_queries = ApolloPersistedQueries::new(ctx.queries.cache.clone());
     let schema = new_schema(ctx.clone(), persisted_queries);
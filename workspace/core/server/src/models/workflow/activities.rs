@@ -1,3 +1,4 @@
+use crate::models::workflow::retry_policy::RetryPolicy;
 use async_graphql::*;
 use bytes::{BufMut, BytesMut};
 use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
@@ -119,6 +120,28 @@ pub struct WorkflowActivityParameterInput {
     pub value: String,
 }
 
+/// How long a dequeued job may go without checking in before it's handed to another worker.
+pub const DEFAULT_LEASE_TIMEOUT_SECONDS: i64 = 1800;
+
+impl WorkflowActivity {
+    /// `leaseTimeout` in the activity's configuration, in seconds. Runners check in every minute, so
+    /// it needs to be comfortably longer than that.
+    pub fn lease_timeout(&self) -> i64 {
+        self.configuration
+            .as_ref()
+            .and_then(|c| c.get("leaseTimeout"))
+            .and_then(|t| t.as_i64())
+            .filter(|t| *t > 0)
+            .unwrap_or(DEFAULT_LEASE_TIMEOUT_SECONDS)
+    }
+
+    /// `retryPolicy` in the activity's configuration, retrying up to `default_max_attempts` times
+    /// when it doesn't say.
+    pub fn retry_policy(&self, default_max_attempts: i32) -> RetryPolicy {
+        RetryPolicy::from_configuration(&self.configuration, default_max_attempts)
+    }
+}
+
 impl From<&Row> for WorkflowActivity {
     fn from(row: &Row) -> Self {
         Self {
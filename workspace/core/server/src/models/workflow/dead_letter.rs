@@ -0,0 +1,26 @@
+use crate::models::workflow::execution_plan::{WorkflowExecutionPlan, WorkflowJob, WorkflowJobId};
+use async_graphql::InputObject;
+use chrono::{DateTime, Utc};
+
+/// A job that failed for good, its plan waits for it to be retried or discarded.
+pub struct WorkflowDeadLetter {
+    pub job_id: WorkflowJobId,
+    pub plan: WorkflowExecutionPlan,
+}
+
+impl WorkflowDeadLetter {
+    pub fn job(&self) -> Option<&WorkflowJob> {
+        self.plan.jobs.get(self.job_id.index as usize)
+    }
+}
+
+#[derive(InputObject, Default)]
+pub struct WorkflowDeadLetterFilter {
+    pub queue: Option<String>,
+    pub workflow_id: Option<String>,
+    pub activity_id: Option<String>,
+    /// Matched anywhere in the error, ignoring case.
+    pub error: Option<String>,
+    pub failed_after: Option<DateTime<Utc>>,
+    pub failed_before: Option<DateTime<Utc>>,
+}
@@ -1,4 +1,5 @@
 use crate::graphql::content::metadata_mutation::WorkflowConfigurationInput;
+use crate::models::workflow::priority::WorkflowPriority;
 use crate::models::workflow::workflows::Workflow;
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
@@ -23,6 +24,7 @@ pub struct EnqueueRequest {
 
     pub configurations: Option<Vec<WorkflowConfigurationInput>>,
     pub delay_until: Option<DateTime<Utc>>,
+    pub priority: Option<WorkflowPriority>,
     pub wait_for_completion: bool
 }
 
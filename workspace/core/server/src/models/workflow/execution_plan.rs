@@ -2,11 +2,12 @@ use crate::models::workflow::activities::{
     Activity, ActivityParameter, WorkflowActivity, WorkflowActivityModel,
     WorkflowActivityParameter, WorkflowActivityPrompt, WorkflowActivityStorageSystem,
 };
+use crate::models::workflow::priority::WorkflowPriority;
 use crate::models::workflow::workflows::Workflow;
 use crate::workflow::queue::JobQueues;
 use crate::workflow::transaction::{RedisTransaction, RedisTransactionOp};
 use async_graphql::{Error, InputObject};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use deadpool_postgres::Transaction;
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
@@ -100,6 +101,8 @@ pub struct WorkflowExecutionPlan {
     #[serde(default)]
     pub failure: bool,
     pub max_failures: i32,
+    #[serde(default)]
+    pub priority: WorkflowPriority,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -131,6 +134,9 @@ pub struct WorkflowJob {
     pub complete: bool,
     pub finished: Option<DateTime<Utc>>,
     pub failures: i32,
+    /// When the last failure is retried, set from the failure until the retry completes or fails.
+    pub retry_at: Option<DateTime<Utc>>,
+    pub failed_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -171,6 +177,7 @@ impl WorkflowExecutionPlan {
             None
         };
         if let Some(current_execution_group) = current_execution_group {
+            let priority = self.priority;
             for job_index in current_execution_group {
                 debug!(target: "workflow", "removing job from current list and queueing as next: {}", self.id);
                 if !self.complete.contains(&job_index) {
@@ -180,12 +187,12 @@ impl WorkflowExecutionPlan {
                         let now = Utc::now();
                         if now < *delay_until {
                             let diff = delay_until.timestamp() - now.timestamp();
-                            redis_txn.add_op(RedisTransactionOp::QueueJobLater(job.id.clone(), diff));
+                            redis_txn.add_op(RedisTransactionOp::QueueJobLater(job.id.clone(), diff, priority));
                         } else {
-                            redis_txn.add_op(RedisTransactionOp::QueueJob(job.id.clone()));
+                            redis_txn.add_op(RedisTransactionOp::QueueJob(job.id.clone(), priority));
                         }
                     } else {
-                        redis_txn.add_op(RedisTransactionOp::QueueJob(job.id.clone()));
+                        redis_txn.add_op(RedisTransactionOp::QueueJob(job.id.clone(), priority));
                     }
                 }
             }
@@ -247,6 +254,7 @@ impl WorkflowExecutionPlan {
         let job = self.jobs.get_mut(job_id.index as usize).unwrap();
         job.error = None;
         job.failures = 0;
+        job.retry_at = None;
         job.complete = job.children.len() == job.completed_children.len();
         if job.complete {
             job.finished = Some(Utc::now());
@@ -288,11 +296,12 @@ impl WorkflowExecutionPlan {
         self.delay_until = Some(delayed_until);
         queues.set_plan(db_txn, self, false).await?;
         redis_txn.add_op(RedisTransactionOp::RemoveJobRunning(job_id.clone()));
-        redis_txn.add_op(RedisTransactionOp::QueueJobLater(job_id.clone(), diff));
+        redis_txn.add_op(RedisTransactionOp::QueueJobLater(job_id.clone(), diff, self.priority));
         redis_txn.add_op(RedisTransactionOp::PlanCheckin(self.id.clone()));
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn set_job_failed(
         &mut self,
         job_id: &WorkflowJobId,
@@ -300,27 +309,38 @@ impl WorkflowExecutionPlan {
         redis_txn: &mut RedisTransaction,
         queues: &JobQueues,
         error: &str,
+        error_class: Option<&str>,
         try_again: bool
     ) -> Result<(), Error> {
         self.failed.insert(job_id.index);
         self.active.remove(&job_id.index);
+        let max_failures = self.max_failures;
+        let priority = self.priority;
+        let finished = self.finished.is_some();
         let job = self.jobs.get_mut(job_id.index as usize).unwrap();
         job.failures += 1;
         job.error = Some(error.to_owned());
+        job.failed_at = Some(Utc::now());
 
         info!("job failures: {} {} {}", self.id, job_id, job.failures);
 
-        let timeout: i64 = (job.failures * 30) as i64;
-        let max_failures = self.max_failures;
+        let policy = job.workflow_activity.retry_policy(max_failures);
         let job_failures = job.failures;
+        let retryable = policy.is_retryable(error, error_class);
+        let attempts_left = policy.has_attempts_left(job_failures);
 
         redis_txn.add_op(RedisTransactionOp::RemoveJobRunning(job_id.clone()));
-        if try_again && self.finished.is_none() && job_failures < max_failures {
+        if try_again && retryable && attempts_left && !finished {
+            let delay = policy.delay(job_failures);
+            job.retry_at = Some(Utc::now() + TimeDelta::seconds(delay));
             redis_txn.add_op(RedisTransactionOp::PlanCheckin(self.id.clone()));
-            redis_txn.add_op(RedisTransactionOp::QueueJobLater(job_id.clone(), timeout));
+            redis_txn.add_op(RedisTransactionOp::QueueJobLater(job_id.clone(), delay, priority));
         } else {
+            job.retry_at = None;
             self.failure = true;
-            if job_failures >= max_failures {
+            if !retryable {
+                error!(target: "workflow", "job failed with a non-retryable error, marking as failed: {}", self.id);
+            } else if !attempts_left {
                 error!(target: "workflow", "job failed too many times, marking as failed: {}", self.id);
             }
             redis_txn.add_op(RedisTransactionOp::RemovePlanRunning(self.id.clone()));
@@ -334,4 +354,86 @@ impl WorkflowExecutionPlan {
         queues.set_plan(db_txn, self, false).await?;
         Ok(())
     }
+
+    fn has_dead_letters(&self) -> bool {
+        self.failed.iter().any(|index| {
+            self.jobs
+                .get(*index as usize)
+                .map(|job| job.retry_at.is_none())
+                .unwrap_or(false)
+        })
+    }
+
+    /// Queues a dead lettered job again with its failures reset, replacing the plan's and the job's
+    /// context first when they're given.
+    pub fn retry_dead_letter(
+        &mut self,
+        job_id: &WorkflowJobId,
+        redis_txn: &mut RedisTransaction,
+        context: Option<&Value>,
+        job_context: Option<&Value>,
+    ) -> Result<(), Error> {
+        if self.finished.is_some() {
+            return Err(Error::new("can't retry a job of a finished plan"));
+        }
+        if !self.failed.contains(&job_id.index) {
+            return Err(Error::new("can't retry a job that hasn't failed"));
+        }
+        let Some(job) = self.jobs.get_mut(job_id.index as usize) else {
+            return Err(Error::new("can't retry, missing job"));
+        };
+        if job.retry_at.is_some() {
+            return Err(Error::new("can't retry a job that's already waiting to be retried"));
+        }
+        if let Some(job_context) = job_context {
+            job.context = if job_context.is_null() { None } else { Some(job_context.clone()) };
+        }
+        job.failures = 0;
+        job.retry_at = None;
+        if let Some(context) = context {
+            self.context = if context.is_null() { None } else { Some(context.clone()) };
+        }
+        self.failed.remove(&job_id.index);
+        self.active.insert(job_id.index);
+        if self.failure && !self.has_dead_letters() {
+            self.failure = false;
+            if let Some(metadata_id) = self.metadata_id {
+                redis_txn.add_op(RedisTransactionOp::AddMetadataRunning(metadata_id));
+            }
+            if let Some(collection_id) = self.collection_id {
+                redis_txn.add_op(RedisTransactionOp::AddCollectionRunning(collection_id));
+            }
+        }
+        redis_txn.add_op(RedisTransactionOp::PlanCheckin(self.id.clone()));
+        redis_txn.add_op(RedisTransactionOp::QueueJob(job_id.clone(), self.priority));
+        Ok(())
+    }
+
+    /// Gives up on a dead lettered job. The plan can't go on without it, so it's finished as failed.
+    pub fn discard_dead_letter(
+        &mut self,
+        job_id: &WorkflowJobId,
+        redis_txn: &mut RedisTransaction,
+    ) -> Result<(), Error> {
+        if self.finished.is_some() {
+            return Ok(());
+        }
+        if !self.failed.contains(&job_id.index) {
+            return Err(Error::new("can't discard a job that hasn't failed"));
+        }
+        let now = Utc::now();
+        for job in self.jobs.iter_mut() {
+            if !job.complete {
+                job.finished = Some(now);
+                job.retry_at = None;
+                redis_txn.add_op(RedisTransactionOp::CancelQueueJob(job.id.clone()));
+                redis_txn.add_op(RedisTransactionOp::RemoveJobRunning(job.id.clone()));
+            }
+        }
+        self.active.clear();
+        self.finished = Some(now);
+        self.failure = true;
+        redis_txn.add_op(RedisTransactionOp::RemovePlanRunning(self.id.clone()));
+        Ok(())
+    }
 }
@@ -10,3 +10,7 @@ pub mod transitions;
 pub mod workflows;
 pub mod workflow_schedule;
 pub mod enqueue_request;
+pub mod workflow_schedule_catch_up;
+pub mod retry_policy;
+pub mod dead_letter;
+pub mod priority;
@@ -0,0 +1,43 @@
+use async_graphql::Enum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Which lane of its queue a plan's jobs wait in. Lanes are dequeued in a weighted round robin,
+/// so high priority jobs are preferred without starving low priority ones.
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Default)]
+pub enum WorkflowPriority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl WorkflowPriority {
+    /// The priority in a workflow's configuration, e.g. `{"priority": "low"}`
+    pub fn from_configuration(configuration: &Value) -> Option<Self> {
+        let priority = configuration.get("priority")?.as_str()?;
+        match priority.to_lowercase().as_str() {
+            "high" => Some(WorkflowPriority::High),
+            "normal" => Some(WorkflowPriority::Normal),
+            "low" => Some(WorkflowPriority::Low),
+            _ => None,
+        }
+    }
+
+    pub fn lane(&self) -> &'static str {
+        match self {
+            WorkflowPriority::High => "high",
+            WorkflowPriority::Normal => "normal",
+            WorkflowPriority::Low => "low",
+        }
+    }
+
+    /// How many dequeues out of every round prefer this lane.
+    pub fn weight(&self) -> i64 {
+        match self {
+            WorkflowPriority::High => 6,
+            WorkflowPriority::Normal => 3,
+            WorkflowPriority::Low => 1,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use rand::Rng;
+use serde_json::Value;
+
+const DEFAULT_INITIAL_DELAY_SECONDS: i64 = 30;
+const DEFAULT_MULTIPLIER: f64 = 2.0;
+const DEFAULT_MAX_DELAY_SECONDS: i64 = 3600;
+const DEFAULT_JITTER: f64 = 0.1;
+
+/// How a failed job is retried, read from `retryPolicy` in its activity's configuration, e.g.
+/// `{"maxAttempts": 5, "initialDelay": 30, "multiplier": 2.0, "maxDelay": 3600, "jitter": 0.1, "nonRetryableErrors": ["InvalidContent"]}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first.
+    pub max_attempts: i32,
+    /// Seconds before the first retry.
+    pub initial_delay: i64,
+    /// Applied to the delay for every retry after the first.
+    pub multiplier: f64,
+    /// Seconds the delay is capped at.
+    pub max_delay: i64,
+    /// Fraction of the delay it's randomly moved up or down by, so jobs that failed together
+    /// don't all retry together.
+    pub jitter: f64,
+    /// Error classes that fail the job outright. A class matches the error class a runner reports,
+    /// or the start of the error.
+    pub non_retryable_errors: Vec<String>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: i32) -> Self {
+        Self {
+            max_attempts,
+            initial_delay: DEFAULT_INITIAL_DELAY_SECONDS,
+            multiplier: DEFAULT_MULTIPLIER,
+            max_delay: DEFAULT_MAX_DELAY_SECONDS,
+            jitter: DEFAULT_JITTER,
+            non_retryable_errors: Vec::new(),
+        }
+    }
+
+    /// The policy in an activity's configuration, anything it doesn't set comes from the defaults.
+    pub fn from_configuration(configuration: &Option<Value>, default_max_attempts: i32) -> Self {
+        let defaults = Self::new(default_max_attempts);
+        let Some(policy) = configuration.as_ref().and_then(|c| c.get("retryPolicy")) else {
+            return defaults;
+        };
+        Self {
+            max_attempts: policy
+                .get("maxAttempts")
+                .and_then(|a| a.as_i64())
+                .map(|a| a.max(1) as i32)
+                .unwrap_or(defaults.max_attempts),
+            initial_delay: policy
+                .get("initialDelay")
+                .and_then(|d| d.as_i64())
+                .map(|d| d.max(0))
+                .unwrap_or(defaults.initial_delay),
+            multiplier: policy
+                .get("multiplier")
+                .and_then(|m| m.as_f64())
+                .map(|m| m.max(1.0))
+                .unwrap_or(defaults.multiplier),
+            max_delay: policy
+                .get("maxDelay")
+                .and_then(|d| d.as_i64())
+                .map(|d| d.max(0))
+                .unwrap_or(defaults.max_delay),
+            jitter: policy
+                .get("jitter")
+                .and_then(|j| j.as_f64())
+                .map(|j| j.clamp(0.0, 1.0))
+                .unwrap_or(defaults.jitter),
+            non_retryable_errors: policy
+                .get("nonRetryableErrors")
+                .and_then(|e| e.as_array())
+                .map(|errors| {
+                    errors
+                        .iter()
+                        .filter_map(|e| e.as_str().map(|e| e.to_owned()))
+                        .collect()
+                })
+                .unwrap_or(defaults.non_retryable_errors),
+        }
+    }
+
+    pub fn is_retryable(&self, error: &str, error_class: Option<&str>) -> bool {
+        !self.non_retryable_errors.iter().any(|class| {
+            error_class == Some(class.as_str()) || error.starts_with(class.as_str())
+        })
+    }
+
+    pub fn has_attempts_left(&self, failures: i32) -> bool {
+        failures < self.max_attempts
+    }
+
+    /// Seconds to wait before retrying a job that has failed `failures` times.
+    pub fn delay(&self, failures: i32) -> i64 {
+        let exponent = (failures - 1).max(0);
+        let delay = (self.initial_delay as f64 * self.multiplier.powi(exponent))
+            .min(self.max_delay as f64);
+        let delay = if self.jitter > 0.0 {
+            delay * (1.0 + rand::rng().random_range(-self.jitter..=self.jitter))
+        } else {
+            delay
+        };
+        delay.round().clamp(0.0, self.max_delay as f64) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn without_jitter(max_attempts: i32) -> RetryPolicy {
+        RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::new(max_attempts)
+        }
+    }
+
+    #[test]
+    fn delay_grows_by_the_multiplier_up_to_max_delay() {
+        let policy = without_jitter(10);
+        assert_eq!(policy.delay(1), 30);
+        assert_eq!(policy.delay(2), 60);
+        assert_eq!(policy.delay(3), 120);
+        assert_eq!(policy.delay(8), 3600);
+        assert_eq!(policy.delay(100), 3600);
+    }
+
+    #[test]
+    fn delay_before_any_failure_is_the_initial_delay() {
+        assert_eq!(without_jitter(3).delay(0), 30);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction_and_max_delay() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..RetryPolicy::new(10)
+        };
+        for _ in 0..1000 {
+            let delay = policy.delay(1);
+            assert!((15..=45).contains(&delay), "{delay}");
+            let delay = policy.delay(20);
+            assert!((1800..=3600).contains(&delay), "{delay}");
+        }
+    }
+
+    #[test]
+    fn configuration_is_clamped() {
+        let configuration = Some(json!({
+            "retryPolicy": {
+                "maxAttempts": 0,
+                "initialDelay": -5,
+                "multiplier": 0.5,
+                "maxDelay": -1,
+                "jitter": 3.0
+            }
+        }));
+        let policy = RetryPolicy::from_configuration(&configuration, 5);
+        assert_eq!(policy.max_attempts, 1);
+        assert_eq!(policy.initial_delay, 0);
+        assert_eq!(policy.multiplier, 1.0);
+        assert_eq!(policy.max_delay, 0);
+        assert_eq!(policy.jitter, 1.0);
+        assert_eq!(policy.delay(3), 0);
+    }
+
+    #[test]
+    fn missing_configuration_uses_the_defaults() {
+        assert_eq!(RetryPolicy::from_configuration(&None, 7), RetryPolicy::new(7));
+        let configuration = Some(json!({ "retryPolicy": { "maxDelay": 60 } }));
+        let policy = RetryPolicy::from_configuration(&configuration, 7);
+        assert_eq!(policy, RetryPolicy { max_delay: 60, ..RetryPolicy::new(7) });
+    }
+
+    #[test]
+    fn non_retryable_errors_match_the_class_or_the_start_of_the_error() {
+        let configuration = Some(json!({ "retryPolicy": { "nonRetryableErrors": ["InvalidContent"] } }));
+        let policy = RetryPolicy::from_configuration(&configuration, 3);
+        assert!(!policy.is_retryable("something went wrong", Some("InvalidContent")));
+        assert!(!policy.is_retryable("InvalidContent: missing title", None));
+        assert!(policy.is_retryable("timed out", Some("Timeout")));
+        assert!(policy.is_retryable("timed out", None));
+    }
+
+    #[test]
+    fn attempts_include_the_first() {
+        let policy = RetryPolicy::new(3);
+        assert!(policy.has_attempts_left(1));
+        assert!(policy.has_attempts_left(2));
+        assert!(!policy.has_attempts_left(3));
+    }
+}
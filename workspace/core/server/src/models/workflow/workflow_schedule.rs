@@ -1,6 +1,8 @@
+use crate::graphql::content::metadata_mutation::WorkflowConfigurationInput;
+use crate::models::workflow::workflow_schedule_catch_up::WorkflowScheduleCatchUp;
 use async_graphql::{Error, InputObject};
-use chrono::{DateTime, Utc};
-use rrule::RRuleSet;
+use chrono::{DateTime, TimeDelta, Utc};
+use rrule::{RRuleSet, Tz};
 use serde_json::Value;
 use tokio_postgres::Row;
 use uuid::Uuid;
@@ -19,6 +21,7 @@ pub struct WorkflowSchedule {
     pub next_run: Option<DateTime<Utc>>,
     pub last_scheduled: Option<DateTime<Utc>>,
     pub enabled: bool,
+    pub catch_up: WorkflowScheduleCatchUp,
 }
 
 #[derive(InputObject)]
@@ -29,13 +32,14 @@ pub struct WorkflowScheduleInput {
     pub rrule: String,
     pub ends: Option<DateTime<Utc>>,
     pub enabled: bool,
+    pub catch_up: Option<WorkflowScheduleCatchUp>,
 }
 
 impl WorkflowScheduleInput {
     pub fn create_schedule(&self, metadata_id: Option<Uuid>, collection_id: Option<Uuid>) -> Result<WorkflowSchedule, Error> {
         let rrule: RRuleSet = self.rrule.parse()?;
         let starts = rrule.get_dt_start().to_utc();
-        Ok(WorkflowSchedule {
+        let mut schedule = WorkflowSchedule {
             id: Uuid::nil(),
             metadata_id,
             collection_id,
@@ -46,10 +50,128 @@ impl WorkflowScheduleInput {
             starts,
             ends: self.ends,
             last_run: None,
-            next_run: Some(starts),
-            last_scheduled: Some(Utc::now()),
+            next_run: None,
+            last_scheduled: None,
             enabled: self.enabled,
-        })
+            catch_up: self.catch_up.unwrap_or_default(),
+        };
+        // occurrences before the schedule was added aren't missed, they're never run
+        let now = Utc::now();
+        schedule.next_run = if starts >= now {
+            Some(starts).filter(|starts| schedule.ends.map(|ends| *starts <= ends).unwrap_or(true))
+        } else {
+            schedule.next_occurrence(now)
+        };
+        Ok(schedule)
+    }
+}
+
+impl WorkflowSchedule {
+    /// Occurrences from `from` through `until`, both inclusive and bounded by `ends`.
+    pub fn occurrences_between(&self, from: DateTime<Utc>, until: DateTime<Utc>, limit: u16) -> Vec<DateTime<Utc>> {
+        let until = self.ends.map(|ends| ends.min(until)).unwrap_or(until);
+        if until < from {
+            return Vec::new();
+        }
+        // widened by a second so the bounds are inclusive whichever way the rrule crate treats them
+        self.rrule
+            .clone()
+            .after((from - TimeDelta::seconds(1)).with_timezone(&Tz::UTC))
+            .before((until + TimeDelta::seconds(1)).with_timezone(&Tz::UTC))
+            .all(limit)
+            .dates
+            .into_iter()
+            .map(|date| date.to_utc())
+            .filter(|date| *date >= from && *date <= until)
+            .collect()
+    }
+
+    /// How many occurrences there are from `from` through `until`, counted a chunk at a time.
+    pub fn count_occurrences_between(&self, from: DateTime<Utc>, until: DateTime<Utc>) -> usize {
+        const CHUNK: u16 = 1000;
+        let mut count = 0;
+        let mut cursor = from;
+        loop {
+            let chunk = self.occurrences_between(cursor, until, CHUNK);
+            count += chunk.len();
+            match chunk.last() {
+                Some(last) if chunk.len() == CHUNK as usize => cursor = *last + TimeDelta::milliseconds(1),
+                _ => return count,
+            }
+        }
+    }
+
+    /// The latest occurrence from `from` through `until`, looked for in windows that widen back
+    /// from `until` so a long outage doesn't mean walking every occurrence since `from`.
+    pub fn latest_occurrence(&self, from: DateTime<Utc>, until: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        const CHUNK: u16 = 1000;
+        let mut window = TimeDelta::minutes(1);
+        loop {
+            let start = until.checked_sub_signed(window).map(|start| start.max(from)).unwrap_or(from);
+            let mut latest = None;
+            let mut cursor = start;
+            loop {
+                let chunk = self.occurrences_between(cursor, until, CHUNK);
+                let Some(last) = chunk.last() else {
+                    break;
+                };
+                latest = Some(*last);
+                if chunk.len() < CHUNK as usize {
+                    break;
+                }
+                cursor = *last + TimeDelta::milliseconds(1);
+            }
+            if latest.is_some() || start <= from {
+                return latest;
+            }
+            window = window * 2;
+        }
+    }
+
+    /// The first occurrence strictly after `after`, none once the schedule has ended.
+    pub fn next_occurrence(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.rrule
+            .clone()
+            .after(after.with_timezone(&Tz::UTC))
+            .all(2)
+            .dates
+            .into_iter()
+            .map(|date| date.to_utc())
+            .find(|date| *date > after)
+            .filter(|date| self.ends.map(|ends| *date <= ends).unwrap_or(true))
+    }
+
+    /// The configuration is either a list of `{ "activityId": ..., "configuration": ... }` or an
+    /// object keyed by activity id.
+    pub fn configurations(&self) -> Result<Option<Vec<WorkflowConfigurationInput>>, Error> {
+        match &self.configuration {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Object(configurations)) => Ok(Some(
+                configurations
+                    .iter()
+                    .map(|(activity_id, configuration)| WorkflowConfigurationInput {
+                        activity_id: activity_id.clone(),
+                        configuration: configuration.clone(),
+                    })
+                    .collect(),
+            )),
+            Some(Value::Array(configurations)) => {
+                let mut inputs = Vec::new();
+                for configuration in configurations {
+                    let activity_id = configuration
+                        .get("activityId")
+                        .or_else(|| configuration.get("activity_id"))
+                        .and_then(|id| id.as_str())
+                        .ok_or_else(|| Error::new("schedule configuration is missing an activityId"))?;
+                    inputs.push(WorkflowConfigurationInput {
+                        activity_id: activity_id.to_owned(),
+                        configuration: configuration.get("configuration").cloned().unwrap_or(Value::Null),
+                    });
+                }
+                Ok(Some(inputs))
+            }
+            Some(_) => Err(Error::new("schedule configuration must be a list or an object")),
+        }
     }
 }
 
@@ -70,7 +192,122 @@ impl From<&Row> for WorkflowSchedule {
             next_run: row.get("next_run"),
             last_scheduled: row.get("last_scheduled"),
             enabled: row.get("enabled"),
+            catch_up: row.get("catch_up"),
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(date: &str) -> DateTime<Utc> {
+        date.parse().unwrap()
+    }
+
+    fn schedule(rrule: RRuleSet, ends: Option<DateTime<Utc>>) -> WorkflowSchedule {
+        WorkflowSchedule {
+            id: Uuid::nil(),
+            metadata_id: None,
+            collection_id: None,
+            workflow_id: "test".to_owned(),
+            attributes: None,
+            configuration: None,
+            starts: rrule.get_dt_start().to_utc(),
+            rrule,
+            ends,
+            last_run: None,
+            next_run: None,
+            last_scheduled: None,
+            enabled: true,
+            catch_up: WorkflowScheduleCatchUp::default(),
+        }
+    }
+
+    fn parsed(rrule: &str, ends: Option<DateTime<Utc>>) -> WorkflowSchedule {
+        schedule(rrule.parse().unwrap(), ends)
+    }
+
+    /// 9am in New York is 14:00 UTC before DST starts on 2024-03-10 and 13:00 UTC after.
+    fn new_york_daily() -> WorkflowSchedule {
+        parsed("DTSTART;TZID=America/New_York:20240301T090000\nRRULE:FREQ=DAILY", None)
+    }
+
+    #[test]
+    fn occurrences_follow_the_time_zone_across_dst() {
+        let schedule = new_york_daily();
+        assert_eq!(
+            schedule.occurrences_between(utc("2024-03-09T00:00:00Z"), utc("2024-03-12T00:00:00Z"), 10),
+            vec![utc("2024-03-09T14:00:00Z"), utc("2024-03-10T13:00:00Z"), utc("2024-03-11T13:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn occurrences_between_is_inclusive_and_limited() {
+        let schedule = new_york_daily();
+        let from = utc("2024-03-02T14:00:00Z");
+        let until = utc("2024-03-04T14:00:00Z");
+        assert_eq!(schedule.occurrences_between(from, until, 10).len(), 3);
+        assert_eq!(schedule.occurrences_between(from, until, 2), vec![from, utc("2024-03-03T14:00:00Z")]);
+        assert!(schedule.occurrences_between(until, from, 10).is_empty());
+    }
+
+    #[test]
+    fn next_occurrence_is_strictly_after_across_dst() {
+        let schedule = new_york_daily();
+        assert_eq!(schedule.next_occurrence(utc("2024-03-09T14:00:00Z")), Some(utc("2024-03-10T13:00:00Z")));
+        assert_eq!(schedule.next_occurrence(utc("2024-03-09T15:00:00Z")), Some(utc("2024-03-10T13:00:00Z")));
+    }
+
+    #[test]
+    fn latest_occurrence_across_dst() {
+        let schedule = new_york_daily();
+        assert_eq!(
+            schedule.latest_occurrence(utc("2024-03-01T00:00:00Z"), utc("2024-03-11T12:59:59Z")),
+            Some(utc("2024-03-10T13:00:00Z"))
+        );
+        assert_eq!(
+            schedule.latest_occurrence(utc("2024-03-01T00:00:00Z"), utc("2024-03-11T13:00:00Z")),
+            Some(utc("2024-03-11T13:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn latest_occurrence_after_a_long_gap() {
+        let schedule = parsed("DTSTART:20240101T000000Z\nRRULE:FREQ=HOURLY", None);
+        let from = utc("2024-01-01T00:00:00Z");
+        let until = utc("2024-06-01T12:34:56Z");
+        assert_eq!(schedule.latest_occurrence(from, until), Some(utc("2024-06-01T12:00:00Z")));
+        // more than one chunk, january and february 2024 are 60 days
+        assert_eq!(schedule.count_occurrences_between(from, utc("2024-03-01T00:00:00Z")), 60 * 24 + 1);
+    }
+
+    #[test]
+    fn latest_occurrence_is_none_before_the_first() {
+        let schedule = new_york_daily();
+        assert_eq!(schedule.latest_occurrence(utc("2024-02-01T00:00:00Z"), utc("2024-03-01T13:59:59Z")), None);
+    }
+
+    #[test]
+    fn occurrences_stop_at_ends() {
+        let schedule = parsed("DTSTART:20240101T000000Z\nRRULE:FREQ=HOURLY", Some(utc("2024-01-01T05:30:00Z")));
+        let from = utc("2024-01-01T00:00:00Z");
+        let until = utc("2024-01-02T00:00:00Z");
+        assert_eq!(schedule.occurrences_between(from, until, 100).len(), 6);
+        assert_eq!(schedule.latest_occurrence(from, until), Some(utc("2024-01-01T05:00:00Z")));
+        assert_eq!(schedule.next_occurrence(utc("2024-01-01T05:00:00Z")), None);
+    }
+
+    #[test]
+    fn empty_rrule_has_no_occurrences() {
+        let schedule = schedule(RRuleSet::new(Tz::UTC.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()), None);
+        let from = utc("2023-01-01T00:00:00Z");
+        let until = utc("2025-01-01T00:00:00Z");
+        assert!(schedule.occurrences_between(from, until, 10).is_empty());
+        assert_eq!(schedule.latest_occurrence(from, until), None);
+        assert_eq!(schedule.next_occurrence(from), None);
+        assert_eq!(schedule.count_occurrences_between(from, until), 0);
+    }
+}
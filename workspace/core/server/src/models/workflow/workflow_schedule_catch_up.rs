@@ -0,0 +1,57 @@
+use async_graphql::Enum;
+use bytes::{BufMut, BytesMut};
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// What the scheduler does with occurrences that were missed, e.g. while no server was running.
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Default)]
+pub enum WorkflowScheduleCatchUp {
+    /// Missed occurrences are dropped, only an occurrence that is due now runs.
+    Skip,
+    /// Missed occurrences run as a single execution.
+    #[default]
+    Once,
+    /// Every missed occurrence runs.
+    All,
+}
+
+impl<'a> FromSql<'a> for WorkflowScheduleCatchUp {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> async_graphql::Result<WorkflowScheduleCatchUp, Box<dyn Error + Sync + Send>> {
+        let e: String = String::from_utf8_lossy(raw).parse().unwrap();
+        Ok(match e.as_str() {
+            "skip" => WorkflowScheduleCatchUp::Skip,
+            "once" => WorkflowScheduleCatchUp::Once,
+            "all" => WorkflowScheduleCatchUp::All,
+            _ => WorkflowScheduleCatchUp::Once,
+        })
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "workflow_schedule_catch_up"
+    }
+}
+
+impl ToSql for WorkflowScheduleCatchUp {
+    fn to_sql(
+        &self,
+        _: &Type,
+        w: &mut BytesMut,
+    ) -> async_graphql::Result<IsNull, Box<dyn Error + Sync + Send>> {
+        match *self {
+            WorkflowScheduleCatchUp::Skip => w.put_slice("skip".as_ref()),
+            WorkflowScheduleCatchUp::Once => w.put_slice("once".as_ref()),
+            WorkflowScheduleCatchUp::All => w.put_slice("all".as_ref()),
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "workflow_schedule_catch_up"
+    }
+
+    to_sql_checked!();
+}
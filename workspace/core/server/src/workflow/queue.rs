@@ -1,4 +1,7 @@
 use crate::datastores::notifier::Notifier;
+use crate::models::workflow::activities::DEFAULT_LEASE_TIMEOUT_SECONDS;
+use crate::models::workflow::dead_letter::{WorkflowDeadLetter, WorkflowDeadLetterFilter};
+use crate::models::workflow::priority::WorkflowPriority;
 use crate::models::workflow::execution_plan::{
     WorkflowExecutePlanState, WorkflowExecutionId, WorkflowExecutionPlan, WorkflowJob,
     WorkflowJobId,
@@ -30,6 +33,22 @@ pub struct JobQueues {
 const QUEUE_PLAN_PREFIX: &str = "queue::plan";
 const QUEUE_JOB_PREFIX: &str = "queue::job";
 
+/// Failed jobs of unfinished plans that gave up on them, leaving out jobs that are waiting to be
+/// retried. The filter is `$1` to `$6`.
+const DEAD_LETTERS_QUERY: &str = "from workflow_plans p
+    cross join lateral jsonb_array_elements(p.configuration->'failed') as f(job_index)
+    cross join lateral (select p.configuration->'jobs'->(f.job_index::int) as job) as j
+    where p.finished is null
+    and coalesce((p.configuration->>'failure')::boolean, false)
+    and j.job->>'retry_at' is null
+    and ($1::varchar is null or p.queue = $1)
+    and ($2::varchar is null or p.workflow_id = $2)
+    and ($3::varchar is null or j.job->'workflow_activity'->>'activity_id' = $3)
+    and ($4::varchar is null or strpos(lower(j.job->>'error'), lower($4)) > 0)
+    and ($5::timestamptz is null or coalesce((j.job->>'failed_at')::timestamptz, p.modified) >= $5)
+    and ($6::timestamptz is null or coalesce((j.job->>'failed_at')::timestamptz, p.modified) < $6)";
+const DEAD_LETTER_FAILED_AT: &str = "coalesce((j.job->>'failed_at')::timestamptz, p.modified)";
+
 impl JobQueues {
     pub fn new(pool: TracingPool, redis: RedisClient, notifier: Arc<Notifier>) -> Self {
         Self {
@@ -51,6 +70,23 @@ impl JobQueues {
         format!("queue::pending::job::{queue}")
     }
 
+    /// The pending list for a priority lane, normal priority keeps the original list.
+    pub fn pending_job_queue_key_for(queue: &str, priority: &WorkflowPriority) -> String {
+        match priority {
+            WorkflowPriority::Normal => Self::pending_job_queue_key(queue),
+            _ => format!("queue::pending::{}::job::{queue}", priority.lane()),
+        }
+    }
+
+    /// Hash of queued jobs that aren't in the normal lane, to the lane they're in.
+    pub fn job_priorities_key(queue: &str) -> String {
+        format!("queue::priorities::job::{queue}")
+    }
+
+    fn dequeue_turn_key(queue: &str) -> String {
+        format!("queue::turn::job::{queue}")
+    }
+
     pub fn running_plan_queue_key(queue: &str) -> String {
         format!("queue::running::plan::{queue}")
     }
@@ -389,6 +425,123 @@ impl JobQueues {
         Ok(ids)
     }
 
+    #[tracing::instrument(skip(self, filter, offset, limit))]
+    pub async fn get_dead_letters(
+        &self,
+        filter: &WorkflowDeadLetterFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<WorkflowDeadLetter>, Error> {
+        let connection = self.pool.get().await?;
+        let stmt = connection
+            .prepare_cached(&format!(
+                "select p.id, p.queue, p.configuration, f.job_index::int as job_index {DEAD_LETTERS_QUERY} order by {DEAD_LETTER_FAILED_AT} desc, p.id, job_index offset $7 limit $8"
+            ))
+            .await?;
+        let rows = connection
+            .query(
+                &stmt,
+                &[
+                    &filter.queue,
+                    &filter.workflow_id,
+                    &filter.activity_id,
+                    &filter.error,
+                    &filter.failed_after,
+                    &filter.failed_before,
+                    &offset,
+                    &limit,
+                ],
+            )
+            .await?;
+        let mut dead_letters = Vec::new();
+        for row in rows {
+            let configuration: Value = row.get("configuration");
+            dead_letters.push(WorkflowDeadLetter {
+                job_id: WorkflowJobId {
+                    id: row.get("id"),
+                    queue: row.get("queue"),
+                    index: row.get("job_index"),
+                },
+                plan: from_value(configuration)?,
+            });
+        }
+        Ok(dead_letters)
+    }
+
+    #[tracing::instrument(skip(self, filter))]
+    pub async fn get_dead_letter_count(&self, filter: &WorkflowDeadLetterFilter) -> Result<i64, Error> {
+        let connection = self.pool.get().await?;
+        let stmt = connection
+            .prepare_cached(&format!("select count(*) as count {DEAD_LETTERS_QUERY}"))
+            .await?;
+        let rows = connection
+            .query(
+                &stmt,
+                &[
+                    &filter.queue,
+                    &filter.workflow_id,
+                    &filter.activity_id,
+                    &filter.error,
+                    &filter.failed_after,
+                    &filter.failed_before,
+                ],
+            )
+            .await?;
+        Ok(rows.first().map(|row| row.get("count")).unwrap_or(0))
+    }
+
+    #[tracing::instrument(skip(self, ids, context, job_context))]
+    pub async fn retry_dead_letters(
+        &self,
+        ids: &[WorkflowJobId],
+        context: Option<&Value>,
+        job_context: Option<&Value>,
+    ) -> Result<(), Error> {
+        let mut connection = self.pool.get().await?;
+        let db_txn = connection.transaction().await?;
+        let mut redis_txn = RedisTransaction::new();
+        for id in ids {
+            let Some(mut plan) = self.get_plan_and_lock_by_job(&db_txn, id).await? else {
+                continue;
+            };
+            plan.retry_dead_letter(id, &mut redis_txn, context, job_context)?;
+            self.set_plan(&db_txn, &plan, false).await?;
+        }
+        // the jobs stay dead letters (rolled back) when redis can't queue them again
+        redis_txn.execute(&self.redis).await?;
+        db_txn.commit().await?;
+        self.incr("queue::dead::retried::count").await?;
+        Ok(())
+    }
+
+    /// Returns the plans that were finished by discarding their jobs.
+    #[tracing::instrument(skip(self, ids))]
+    pub async fn discard_dead_letters(
+        &self,
+        ids: &[WorkflowJobId],
+    ) -> Result<Vec<WorkflowExecutionPlan>, Error> {
+        let mut connection = self.pool.get().await?;
+        let db_txn = connection.transaction().await?;
+        let mut redis_txn = RedisTransaction::new();
+        let mut plans: Vec<WorkflowExecutionPlan> = Vec::new();
+        for id in ids {
+            let Some(mut plan) = self.get_plan_and_lock_by_job(&db_txn, id).await? else {
+                continue;
+            };
+            if plan.finished.is_some() {
+                continue;
+            }
+            plan.discard_dead_letter(id, &mut redis_txn)?;
+            self.set_plan(&db_txn, &plan, false).await?;
+            plans.push(plan);
+        }
+        // the plans stay unfinished (rolled back) when redis can't take the jobs out of the queue
+        redis_txn.execute(&self.redis).await?;
+        db_txn.commit().await?;
+        self.incr("queue::dead::discarded::count").await?;
+        Ok(plans)
+    }
+
     #[tracing::instrument(skip(self, ids))]
     pub async fn retry_jobs(&self, ids: Vec<WorkflowJobId>) -> Result<(), Error> {
         let mut redis_txn = RedisTransaction::new();
@@ -413,11 +566,21 @@ impl JobQueues {
             r"
             local pending_queue = tostring(KEYS[1])
             local running_queue = tostring(KEYS[2])
+            local high_queue = tostring(KEYS[3])
+            local low_queue = tostring(KEYS[4])
+            local priorities = tostring(KEYS[5])
             local current_timestamp = tonumber(ARGV[1])
             local expired_items = redis.call('ZRANGEBYSCORE', running_queue, 0, current_timestamp)
             if #expired_items > 0 then
                 for i, item in ipairs(expired_items) do
-                    redis.call('RPUSH', pending_queue, item)
+                    local lane = redis.call('HGET', priorities, item)
+                    if lane == 'high' then
+                        redis.call('RPUSH', high_queue, item)
+                    elseif lane == 'low' then
+                        redis.call('RPUSH', low_queue, item)
+                    else
+                        redis.call('RPUSH', pending_queue, item)
+                    end
                     redis.call('ZREM', running_queue, item)
                     redis.call('INCR', 'queue::expired::count')
                 end
@@ -430,9 +593,15 @@ impl JobQueues {
             let queue = queue_parts.split("::").last().unwrap();
             let key1 = JobQueues::pending_job_queue_key(queue);
             let key2 = JobQueues::running_job_queue_key(queue);
+            let key3 = JobQueues::pending_job_queue_key_for(queue, &WorkflowPriority::High);
+            let key4 = JobQueues::pending_job_queue_key_for(queue, &WorkflowPriority::Low);
+            let key5 = JobQueues::job_priorities_key(queue);
             let result: i32 = script
                 .key(key1)
                 .key(key2)
+                .key(key3)
+                .key(key4)
+                .key(key5)
                 .arg(time)
                 .invoke_async(&mut connection)
                 .await?;
@@ -547,42 +716,65 @@ impl JobQueues {
         Ok(ids)
     }
 
+    /// Pops from the priority lanes in a weighted round robin. Each dequeue advances the queue's
+    /// turn, and the turn picks the lane to try first, so out of every round of dequeues the high,
+    /// normal and low lanes are preferred in proportion to their weights. An empty lane falls
+    /// through to the others, highest first.
     fn new_dequeue_script(&self) -> Script {
         Script::new(
             r"
-                local job_queue     = tostring(KEYS[1])
-                local running_queue = tostring(KEYS[2])
+                local high_queue    = tostring(KEYS[1])
+                local normal_queue  = tostring(KEYS[2])
+                local low_queue     = tostring(KEYS[3])
+                local running_queue = tostring(KEYS[4])
+                local turn_key      = tostring(KEYS[5])
 
                 local now   = tonumber(ARGV[1]) -- Current timestamp
                 local delay = tonumber(ARGV[2]) -- Expiration delay
+                local high_weight   = tonumber(ARGV[3])
+                local normal_weight = tonumber(ARGV[4])
+                local low_weight    = tonumber(ARGV[5])
 
-                local item = redis.call('LPOP', job_queue)
-                if item then
-                    local expire_time = now + delay
-                    redis.call('ZADD', running_queue, expire_time, item)
-                    redis.call('INCR', 'queue::dequeued::count')
-                    return tostring(item)
+                local turn = redis.call('INCR', turn_key) % (high_weight + normal_weight + low_weight)
+                local lanes
+                if turn < high_weight then
+                    lanes = { high_queue, normal_queue, low_queue }
+                elseif turn < high_weight + normal_weight then
+                    lanes = { normal_queue, high_queue, low_queue }
                 else
-                    return nil -- Nothing to pop
+                    lanes = { low_queue, high_queue, normal_queue }
+                end
+
+                for _, lane in ipairs(lanes) do
+                    local item = redis.call('LPOP', lane)
+                    if item then
+                        local expire_time = now + delay
+                        redis.call('ZADD', running_queue, expire_time, item)
+                        redis.call('INCR', 'queue::dequeued::count')
+                        return tostring(item)
+                    end
                 end
+                return nil -- Nothing to pop
             ",
         )
     }
 
-    #[tracing::instrument(skip(self, pending_key, running_key))]
-    async fn dequeue_from_redis(
-        &self,
-        pending_key: &str,
-        running_key: &str,
-    ) -> Result<Option<String>, Error> {
+    #[tracing::instrument(skip(self, queue))]
+    async fn dequeue_from_redis(&self, queue: &str) -> Result<Option<String>, Error> {
         let pooled_connection = self.redis.get().await?;
         let mut connection = pooled_connection.get_connection().await?;
         let script = self.new_dequeue_script();
         let result: Vec<u8> = script
-            .key(pending_key)
-            .key(running_key)
+            .key(JobQueues::pending_job_queue_key_for(queue, &WorkflowPriority::High))
+            .key(JobQueues::pending_job_queue_key_for(queue, &WorkflowPriority::Normal))
+            .key(JobQueues::pending_job_queue_key_for(queue, &WorkflowPriority::Low))
+            .key(JobQueues::running_job_queue_key(queue))
+            .key(JobQueues::dequeue_turn_key(queue))
             .arg(Utc::now().timestamp())
-            .arg(1800)
+            .arg(DEFAULT_LEASE_TIMEOUT_SECONDS)
+            .arg(WorkflowPriority::High.weight())
+            .arg(WorkflowPriority::Normal.weight())
+            .arg(WorkflowPriority::Low.weight())
             .invoke_async(&mut connection)
             .await?;
         if result.is_empty() {
@@ -594,9 +786,7 @@ impl JobQueues {
 
     #[tracing::instrument(skip(self, queue))]
     async fn dequeue_job(&self, queue: &str) -> Result<Option<WorkflowJobId>, Error> {
-        let pending_key = JobQueues::pending_job_queue_key(queue);
-        let running_key = JobQueues::running_job_queue_key(queue);
-        if let Some(id) = self.dequeue_from_redis(&pending_key, &running_key).await? {
+        if let Some(id) = self.dequeue_from_redis(queue).await? {
             let id_parts = id.get(QUEUE_JOB_PREFIX.len() + 2..).unwrap();
             let mut id_parts = id_parts.split("::");
             let queue = id_parts.next().unwrap();
@@ -645,6 +835,16 @@ impl JobQueues {
                     return Ok(None);
                 }
                 job.parent = plan.parent;
+                // the activity isn't known until the plan is read, so the default lease is swapped for its own.
+                // the job is already running by now, so it's handed out on the default lease if that fails
+                let lease_timeout = job.workflow_activity.lease_timeout();
+                if lease_timeout != DEFAULT_LEASE_TIMEOUT_SECONDS {
+                    let mut txn = RedisTransaction::new();
+                    txn.add_op(JobCheckin(job.id.clone(), lease_timeout));
+                    if let Err(e) = txn.execute(&self.redis).await {
+                        error!("failed to set the lease of job {}, keeping the default: {e:?}", job.id);
+                    }
+                }
                 Ok(Some(job))
             }
             Ok(None) => Ok(None),
@@ -744,6 +944,7 @@ impl JobQueues {
         &self,
         job_id: &WorkflowJobId,
         error: &str,
+        error_class: Option<&str>,
         try_again: bool,
     ) -> Result<WorkflowExecutionPlan, Error> {
         let mut connection = self.pool.get().await?;
@@ -752,7 +953,7 @@ impl JobQueues {
             return Err(Error::new("can't set job context, missing plan"));
         };
         let mut redis_txn = RedisTransaction::new();
-        plan.set_job_failed(job_id, &db_txn, &mut redis_txn, self, error, try_again)
+        plan.set_job_failed(job_id, &db_txn, &mut redis_txn, self, error, error_class, try_again)
             .await?;
         db_txn.commit().await?;
         redis_txn.execute(&self.redis).await?;
@@ -771,17 +972,32 @@ impl JobQueues {
         if plan.finished.is_some() {
             return Ok(());
         }
-        if let Some(job) = plan.jobs.get(job_id.index as usize) {
-            if job.complete {
-                return Ok(());
-            }
+        let Some(job) = plan.jobs.get(job_id.index as usize) else {
+            return Err(Error::new("can't check in, missing job"));
+        };
+        if job.complete {
+            return Ok(());
         }
         let mut txn = RedisTransaction::new();
-        txn.add_op(JobCheckin(job_id.clone()));
+        txn.add_op(JobCheckin(job_id.clone(), job.workflow_activity.lease_timeout()));
         txn.execute(&self.redis).await?;
         Ok(())
     }
 
+    /// When a running job's lease runs out and it's handed to another worker, none when it isn't running.
+    #[tracing::instrument(skip(self, job_id))]
+    pub async fn get_job_lease_expiration(
+        &self,
+        job_id: &WorkflowJobId,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        let pooled_connection = self.redis.get().await?;
+        let mut connection = pooled_connection.get_connection().await?;
+        let running_key = JobQueues::running_job_queue_key(&job_id.queue);
+        let key = JobQueues::queue_job_key(&job_id.queue, &job_id.id, job_id.index);
+        let expiration: Option<f64> = connection.zscore(running_key, key).await?;
+        Ok(expiration.and_then(|e| DateTime::from_timestamp(e as i64, 0)))
+    }
+
     #[tracing::instrument(skip(self, job_id))]
     pub async fn set_execution_plan_job_complete(
         &self,
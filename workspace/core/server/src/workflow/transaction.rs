@@ -1,4 +1,5 @@
 use crate::models::workflow::execution_plan::{WorkflowExecutionId, WorkflowJobId};
+use crate::models::workflow::priority::WorkflowPriority;
 use crate::redis::RedisClient;
 use crate::workflow::queue::JobQueues;
 use async_graphql::Error;
@@ -26,18 +27,40 @@ impl RedisTransaction {
         let mut arg_ix = 0;
         for op in &self.ops {
             match op {
-                RedisTransactionOp::QueueJob(_) => {
+                RedisTransactionOp::QueueJob(_, priority) => {
                     let rpush = format!(
                         "redis.call('RPUSH', tostring(KEYS[{}]), tostring(KEYS[{}]))\n",
                         key_ix + 1,
                         key_ix + 2
                     );
-                    key_ix += 2;
                     script.push_str(&rpush);
+                    if *priority != WorkflowPriority::Normal {
+                        let hset = format!(
+                            "redis.call('HSET', tostring(KEYS[{}]), tostring(KEYS[{}]), tostring(ARGV[{}]))\n",
+                            key_ix + 3,
+                            key_ix + 2,
+                            arg_ix + 1
+                        );
+                        script.push_str(&hset);
+                        key_ix += 1;
+                        arg_ix += 1;
+                    }
+                    key_ix += 2;
+                }
+                RedisTransactionOp::CancelQueueJob(_) => {
+                    // the job is in the lane the priorities hash has for it, normal when it isn't there
+                    let lrem_hdel = format!(
+                        "do\nlocal lane = redis.call('HGET', tostring(KEYS[{priorities}]), tostring(KEYS[{job}]))\nlocal pending = tostring(KEYS[{normal}])\nif lane == 'high' then\npending = tostring(KEYS[{high}])\nelseif lane == 'low' then\npending = tostring(KEYS[{low}])\nend\nredis.call('LREM', pending, 0, tostring(KEYS[{job}]))\nredis.call('HDEL', tostring(KEYS[{priorities}]), tostring(KEYS[{job}]))\nend\n",
+                        normal = key_ix + 1,
+                        high = key_ix + 2,
+                        low = key_ix + 3,
+                        job = key_ix + 4,
+                        priorities = key_ix + 5,
+                    );
+                    key_ix += 5;
+                    script.push_str(&lrem_hdel);
                 }
-                RedisTransactionOp::CancelQueueJob(_)
-                | RedisTransactionOp::RemovePlanRunning(_)
-                | RedisTransactionOp::RemoveJobRunning(_) => {
+                RedisTransactionOp::RemovePlanRunning(_) => {
                     let zrem = format!(
                         "redis.call('ZREM', tostring(KEYS[{}]), tostring(KEYS[{}]))\n",
                         key_ix + 1,
@@ -46,14 +69,50 @@ impl RedisTransaction {
                     key_ix += 2;
                     script.push_str(&zrem);
                 }
-                RedisTransactionOp::PlanCheckin(_)
-                | RedisTransactionOp::JobCheckin(_)
-                | RedisTransactionOp::QueueJobLater(_, _) => {
+                RedisTransactionOp::RemoveJobRunning(_) => {
+                    let zrem_hdel = format!(
+                        "redis.call('ZREM', tostring(KEYS[{}]), tostring(KEYS[{}]))\nredis.call('HDEL', tostring(KEYS[{}]), tostring(KEYS[{}]))\n",
+                        key_ix + 1,
+                        key_ix + 2,
+                        key_ix + 3,
+                        key_ix + 2
+                    );
+                    key_ix += 3;
+                    script.push_str(&zrem_hdel);
+                }
+                RedisTransactionOp::PlanCheckin(_) => {
                     let zadd_incr = format!("redis.call('ZADD', tostring(KEYS[{}]), tonumber(ARGV[{}]) + tonumber(ARGV[{}]), tostring(KEYS[{}]))\nredis.call('INCR', 'queue::job::checkin::count')\n", key_ix + 1, arg_ix + 1, arg_ix + 2, key_ix + 2);
                     key_ix += 2;
                     arg_ix += 2;
                     script.push_str(&zadd_incr);
                 }
+                RedisTransactionOp::JobCheckin(_, _) => {
+                    // XX only extends a lease that's still held, a job the expiration checker already
+                    // moved back to pending isn't put back in running
+                    let zadd_incr = format!("redis.call('ZADD', tostring(KEYS[{}]), 'XX', tonumber(ARGV[{}]) + tonumber(ARGV[{}]), tostring(KEYS[{}]))\nredis.call('INCR', 'queue::job::checkin::count')\n", key_ix + 1, arg_ix + 1, arg_ix + 2, key_ix + 2);
+                    key_ix += 2;
+                    arg_ix += 2;
+                    script.push_str(&zadd_incr);
+                }
+                RedisTransactionOp::QueueJobLater(_, _, priority) => {
+                    let zadd_incr = format!("redis.call('ZADD', tostring(KEYS[{}]), tonumber(ARGV[{}]) + tonumber(ARGV[{}]), tostring(KEYS[{}]))\nredis.call('INCR', 'queue::job::checkin::count')\n", key_ix + 1, arg_ix + 1, arg_ix + 2, key_ix + 2);
+                    script.push_str(&zadd_incr);
+                    if *priority != WorkflowPriority::Normal {
+                        // the expiration checker reads the lane from here when it moves the job
+                        // back to pending
+                        let hset = format!(
+                            "redis.call('HSET', tostring(KEYS[{}]), tostring(KEYS[{}]), tostring(ARGV[{}]))\n",
+                            key_ix + 3,
+                            key_ix + 2,
+                            arg_ix + 3
+                        );
+                        script.push_str(&hset);
+                        key_ix += 1;
+                        arg_ix += 1;
+                    }
+                    key_ix += 2;
+                    arg_ix += 2;
+                }
                 RedisTransactionOp::AddMetadataRunning(_)
                 | RedisTransactionOp::AddCollectionRunning(_) => {
                     let incrby = format!(
@@ -76,12 +135,16 @@ impl RedisTransaction {
         let mut invocation = script.prepare_invoke();
         for op in &self.ops {
             match op {
-                RedisTransactionOp::QueueJob(op) => {
-                    let queue_key = JobQueues::pending_job_queue_key(&op.queue);
+                RedisTransactionOp::QueueJob(op, priority) => {
+                    let queue_key = JobQueues::pending_job_queue_key_for(&op.queue, priority);
                     let key = JobQueues::queue_job_key(&op.queue, &op.id, op.index);
                     invocation.key(&queue_key).key(&key);
+                    if *priority != WorkflowPriority::Normal {
+                        let priorities_key = JobQueues::job_priorities_key(&op.queue);
+                        invocation.key(&priorities_key).arg(priority.lane());
+                    }
                 }
-                RedisTransactionOp::QueueJobLater(op, timeout) => {
+                RedisTransactionOp::QueueJobLater(op, timeout, priority) => {
                     // putting in running queue so that when the timeout checker will find this
                     // and re-run it later.  TODO: maybe do this differently
                     let queue_key = JobQueues::running_job_queue_key(&op.queue);
@@ -91,11 +154,25 @@ impl RedisTransaction {
                         .key(&key)
                         .arg(Utc::now().timestamp())
                         .arg(timeout);
+                    if *priority != WorkflowPriority::Normal {
+                        let priorities_key = JobQueues::job_priorities_key(&op.queue);
+                        invocation.key(&priorities_key).arg(priority.lane());
+                    }
                 }
                 RedisTransactionOp::CancelQueueJob(op) => {
-                    let queue_key = JobQueues::pending_job_queue_key(&op.queue);
+                    let normal_key = JobQueues::pending_job_queue_key(&op.queue);
+                    let high_key =
+                        JobQueues::pending_job_queue_key_for(&op.queue, &WorkflowPriority::High);
+                    let low_key =
+                        JobQueues::pending_job_queue_key_for(&op.queue, &WorkflowPriority::Low);
                     let key = JobQueues::queue_job_key(&op.queue, &op.id, op.index);
-                    invocation.key(&queue_key).key(&key);
+                    let priorities_key = JobQueues::job_priorities_key(&op.queue);
+                    invocation
+                        .key(&normal_key)
+                        .key(&high_key)
+                        .key(&low_key)
+                        .key(&key)
+                        .key(&priorities_key);
                 }
                 RedisTransactionOp::RemovePlanRunning(op) => {
                     let queue_key = JobQueues::running_plan_queue_key(&op.queue);
@@ -105,7 +182,8 @@ impl RedisTransaction {
                 RedisTransactionOp::RemoveJobRunning(op) => {
                     let queue_key = JobQueues::running_job_queue_key(&op.queue);
                     let key = JobQueues::queue_job_key(&op.queue, &op.id, op.index);
-                    invocation.key(&queue_key).key(&key);
+                    let priorities_key = JobQueues::job_priorities_key(&op.queue);
+                    invocation.key(&queue_key).key(&key).key(&priorities_key);
                 }
                 RedisTransactionOp::PlanCheckin(op) => {
                     let queue_key = JobQueues::running_plan_queue_key(&op.queue);
@@ -116,14 +194,14 @@ impl RedisTransaction {
                         .arg(Utc::now().timestamp())
                         .arg(1800);
                 }
-                RedisTransactionOp::JobCheckin(op) => {
+                RedisTransactionOp::JobCheckin(op, lease_timeout) => {
                     let queue_key = JobQueues::running_job_queue_key(&op.queue);
                     let key = JobQueues::queue_job_key(&op.queue, &op.id, op.index);
                     invocation
                         .key(&queue_key)
                         .key(&key)
                         .arg(Utc::now().timestamp())
-                        .arg(1800);
+                        .arg(lease_timeout);
                 }
                 RedisTransactionOp::AddMetadataRunning(op) => {
                     let key = op.to_string();
@@ -169,9 +247,10 @@ impl RedisTransaction {
 
 pub enum RedisTransactionOp {
     PlanCheckin(WorkflowExecutionId),
-    JobCheckin(WorkflowJobId),
-    QueueJob(WorkflowJobId),
-    QueueJobLater(WorkflowJobId, i64),
+    /// Extends the job's lease by the seconds given, if it still holds one.
+    JobCheckin(WorkflowJobId, i64),
+    QueueJob(WorkflowJobId, WorkflowPriority),
+    QueueJobLater(WorkflowJobId, i64, WorkflowPriority),
     CancelQueueJob(WorkflowJobId),
     RemovePlanRunning(WorkflowExecutionId),
     RemoveJobRunning(WorkflowJobId),
@@ -1,78 +1,399 @@
+use std::collections::BTreeSet;
 use std::error::Error;
-use std::sync::Arc;
-use std::sync::atomic::AtomicI64;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize};
 use std::sync::atomic::Ordering::Relaxed;
-use std::time::Duration;
-use async_channel::{bounded, Receiver, Sender};
+use std::time::{Duration, Instant};
+use async_channel::{unbounded, Receiver, Sender};
+use log::{debug, warn};
 use tokio::time::timeout;
 
-pub struct Pool<T> {
-    index: Arc<AtomicI64>,
+type CreateFn<T> = Arc<dyn Fn(usize) -> T + Send + Sync + 'static>;
+type HealthCheckFn<T> = Arc<dyn Fn(&T) -> bool + Send + Sync + 'static>;
+type RetireFn<T> = Arc<dyn Fn(T) + Send + Sync + 'static>;
+
+struct Inner<T> {
+    index: AtomicI64,
     sender: Sender<PoolObject<T>>,
     receiver: Receiver<PoolObject<T>>,
-    capacity: usize,
-    create_fn: Arc<dyn Fn(usize) -> T + Send + Sync + 'static>,
+    capacity: AtomicUsize,
+    /// Slots with a live object of the current generation, idle or in use.
+    slots: Mutex<BTreeSet<usize>>,
+    in_use: AtomicUsize,
+    acquired: AtomicU64,
+    timeouts: AtomicU64,
+    replaced: AtomicU64,
+    retired: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+pub struct Pool<T> {
+    inner: Arc<Inner<T>>,
+    create_fn: CreateFn<T>,
+    health_check: Option<HealthCheckFn<T>>,
+    on_retire: Option<RetireFn<T>>,
 }
 
 pub struct PoolObject<T> {
     index: i64,
+    slot: usize,
     pub object: T,
 }
 
+impl<T> PoolObject<T> {
+    /// The index the object was created with, a replacement keeps the index of the object it replaced.
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    Timeout,
+}
+
+impl Display for PoolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolError::Timeout => write!(f, "timed out waiting for a pool object"),
+        }
+    }
+}
+
+impl Error for PoolError {}
+
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    pub capacity: usize,
+    pub idle: usize,
+    pub in_use: usize,
+    /// Successful acquires, the wait times are averaged over these.
+    pub acquired: u64,
+    pub timeouts: u64,
+    /// Objects recreated after failing a health check.
+    pub replaced: u64,
+    /// Objects dropped because they were from an old generation, beyond the capacity, or idle when the
+    /// pool was recycled or closed.
+    pub retired: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+}
+
+impl PoolStats {
+    pub fn average_wait(&self) -> Duration {
+        if self.acquired == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_micros((self.total_wait.as_micros() / self.acquired as u128) as u64)
+        }
+    }
+}
+
 impl<T> Pool<T> {
     pub async fn new(capacity: usize, create_fn: impl Fn(usize) -> T + Send + Sync + 'static) -> Self {
-        let (sender, receiver) = bounded(capacity);
+        // unbounded since resize grows the pool past the capacity it was created with, the slots keep
+        // the objects in it to the capacity
+        let (sender, receiver) = unbounded();
         let pool = Pool {
-            index: Arc::new(AtomicI64::new(0)),
-            sender,
-            receiver,
-            capacity,
+            inner: Arc::new(Inner {
+                index: AtomicI64::new(0),
+                sender,
+                receiver,
+                capacity: AtomicUsize::new(capacity),
+                slots: Mutex::new(BTreeSet::new()),
+                in_use: AtomicUsize::new(0),
+                acquired: AtomicU64::new(0),
+                timeouts: AtomicU64::new(0),
+                replaced: AtomicU64::new(0),
+                retired: AtomicU64::new(0),
+                wait_micros: AtomicU64::new(0),
+                max_wait_micros: AtomicU64::new(0),
+            }),
             create_fn: Arc::new(create_fn),
+            health_check: None,
+            on_retire: None,
         };
         pool.recycle().await;
         pool
     }
 
+    /// Checked whenever an object is acquired or released, an unhealthy object is dropped and
+    /// recreated in its slot.
+    pub fn with_health_check(mut self, health_check: impl Fn(&T) -> bool + Send + Sync + 'static) -> Self {
+        self.health_check = Some(Arc::new(health_check));
+        self
+    }
+
+    /// Called with every object the pool drops, whether it was replaced, retired, recycled or the
+    /// pool was closed, so anything the object holds can be cleaned up.
+    pub fn with_on_retire(mut self, on_retire: impl Fn(T) + Send + Sync + 'static) -> Self {
+        self.on_retire = Some(Arc::new(on_retire));
+        self
+    }
+
+    fn is_healthy(&self, object: &T) -> bool {
+        self.health_check.as_ref().map(|check| check(object)).unwrap_or(true)
+    }
+
+    fn create(&self, index: i64, slot: usize) -> PoolObject<T> {
+        PoolObject { index, slot, object: (self.create_fn)(slot) }
+    }
+
+    fn retire(&self, object: PoolObject<T>) {
+        if let Some(on_retire) = &self.on_retire {
+            on_retire(object.object);
+        }
+    }
+
+    /// Whether the object still belongs in the pool, retires it from its slot when it doesn't.
+    fn is_current(&self, object: &PoolObject<T>) -> bool {
+        if object.index != self.inner.index.load(Relaxed) {
+            debug!("dropping pool object {} from an old generation", object.slot);
+            self.inner.retired.fetch_add(1, Relaxed);
+            return false;
+        }
+        if object.slot >= self.inner.capacity.load(Relaxed) {
+            debug!("dropping pool object {} beyond the capacity", object.slot);
+            self.inner.slots.lock().unwrap().remove(&object.slot);
+            self.inner.retired.fetch_add(1, Relaxed);
+            return false;
+        }
+        true
+    }
+
+    fn replace(&self, object: PoolObject<T>) -> PoolObject<T> {
+        warn!("replacing unhealthy pool object {}", object.slot);
+        self.inner.replaced.fetch_add(1, Relaxed);
+        let (index, slot) = (object.index, object.slot);
+        self.retire(object);
+        self.create(index, slot)
+    }
+
+    /// Replaces every object with a new generation, objects in use are dropped when they're released.
     pub async fn recycle(&self) {
-        let index = self.index.fetch_add(1, Relaxed) + 1;
-        let create = &self.create_fn;
-        while !self.sender.is_empty() {
-            let _ = timeout(Duration::from_millis(3000), self.receiver.recv()).await;
+        let index = self.inner.index.fetch_add(1, Relaxed) + 1;
+        self.drain().await;
+        let capacity = self.inner.capacity.load(Relaxed);
+        *self.inner.slots.lock().unwrap() = (0..capacity).collect();
+        for slot in 0..capacity {
+            self.inner.sender.send(self.create(index, slot)).await.unwrap();
         }
-        assert!(self.sender.is_empty());
-        for i in 0..self.capacity {
-            self.sender.send(PoolObject { index, object: create(i) }).await.unwrap();
+    }
+
+    async fn drain(&self) {
+        while !self.inner.sender.is_empty() {
+            if let Ok(Ok(object)) = timeout(Duration::from_millis(3000), self.inner.receiver.recv()).await {
+                self.inner.retired.fetch_add(1, Relaxed);
+                self.retire(object);
+            }
+        }
+    }
+
+    /// Grows the pool by creating objects for the new slots, or shrinks it by dropping idle objects
+    /// beyond the capacity now and objects in use once they're released.
+    pub async fn resize(&self, capacity: usize) {
+        self.inner.capacity.store(capacity, Relaxed);
+        let index = self.inner.index.load(Relaxed);
+        let mut idle = Vec::new();
+        while let Ok(object) = self.inner.receiver.try_recv() {
+            idle.push(object);
+        }
+        for object in idle {
+            if self.is_current(&object) {
+                self.inner.sender.send(object).await.unwrap();
+            } else {
+                self.retire(object);
+            }
+        }
+        let missing: Vec<usize> = {
+            let mut slots = self.inner.slots.lock().unwrap();
+            let missing: Vec<usize> = (0..capacity).filter(|slot| !slots.contains(slot)).collect();
+            slots.extend(missing.iter().copied());
+            missing
+        };
+        for slot in missing {
+            self.inner.sender.send(self.create(index, slot)).await.unwrap();
         }
     }
 
+    /// Waits for an idle object as long as it takes.
     pub async fn acquire(&self) -> PoolObject<T> {
-        self.receiver.recv().await.unwrap()
+        let started = Instant::now();
+        loop {
+            let object = self.inner.receiver.recv().await.unwrap();
+            if !self.is_current(&object) {
+                self.retire(object);
+                continue;
+            }
+            let object = if self.is_healthy(&object.object) { object } else { self.replace(object) };
+            self.inner.in_use.fetch_add(1, Relaxed);
+            let waited = started.elapsed().as_micros() as u64;
+            self.inner.acquired.fetch_add(1, Relaxed);
+            self.inner.wait_micros.fetch_add(waited, Relaxed);
+            self.inner.max_wait_micros.fetch_max(waited, Relaxed);
+            return object;
+        }
+    }
+
+    /// Like `acquire`, but gives up with `PoolError::Timeout` when no object is idle within the duration.
+    pub async fn acquire_timeout(&self, duration: Duration) -> Result<PoolObject<T>, PoolError> {
+        match timeout(duration, self.acquire()).await {
+            Ok(object) => Ok(object),
+            Err(_) => {
+                self.inner.timeouts.fetch_add(1, Relaxed);
+                Err(PoolError::Timeout)
+            }
+        }
     }
 
     pub async fn release(&self, object: PoolObject<T>) -> Result<(), Box<dyn Error>> {
-        if object.index == self.index.load(Relaxed) {
-            let _ = timeout(Duration::from_millis(3000), self.sender.send(object)).await?;
+        self.inner.in_use.fetch_sub(1, Relaxed);
+        if !self.is_current(&object) {
+            self.retire(object);
+            return Ok(());
+        }
+        let object = if self.is_healthy(&object.object) { object } else { self.replace(object) };
+        if self.inner.sender.send(object).await.is_err() {
+            return Err("pool is closed".into());
         }
         Ok(())
     }
 
     pub async fn close(&self) {
-        self.index.fetch_add(1, Relaxed);
-        while !self.sender.is_empty() {
-            let _ = timeout(Duration::from_millis(3000), self.receiver.recv()).await;
+        self.inner.index.fetch_add(1, Relaxed);
+        self.inner.slots.lock().unwrap().clear();
+        self.drain().await;
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            capacity: self.inner.capacity.load(Relaxed),
+            idle: self.inner.receiver.len(),
+            in_use: self.inner.in_use.load(Relaxed),
+            acquired: self.inner.acquired.load(Relaxed),
+            timeouts: self.inner.timeouts.load(Relaxed),
+            replaced: self.inner.replaced.load(Relaxed),
+            retired: self.inner.retired.load(Relaxed),
+            total_wait: Duration::from_micros(self.inner.wait_micros.load(Relaxed)),
+            max_wait: Duration::from_micros(self.inner.max_wait_micros.load(Relaxed)),
         }
-        assert!(self.sender.is_empty());
     }
 }
 
 impl<T> Clone for Pool<T> {
     fn clone(&self) -> Self {
         Pool {
-            sender: self.sender.clone(),
-            receiver: self.receiver.clone(),
-            index: Arc::clone(&self.index),
-            capacity: self.capacity,
+            inner: Arc::clone(&self.inner),
             create_fn: Arc::clone(&self.create_fn),
+            health_check: self.health_check.clone(),
+            on_retire: self.on_retire.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    struct Item {
+        slot: usize,
+        healthy: Arc<AtomicBool>,
+    }
+
+    struct Counts {
+        created: Arc<AtomicUsize>,
+        retired: Arc<Mutex<Vec<usize>>>,
+    }
+
+    async fn new_pool(capacity: usize) -> (Pool<Item>, Counts) {
+        let created = Arc::new(AtomicUsize::new(0));
+        let retired = Arc::new(Mutex::new(Vec::new()));
+        let pool_created = Arc::clone(&created);
+        let pool_retired = Arc::clone(&retired);
+        let pool = Pool::new(capacity, move |slot| {
+            pool_created.fetch_add(1, Relaxed);
+            Item { slot, healthy: Arc::new(AtomicBool::new(true)) }
+        })
+        .await
+        .with_health_check(|item| item.healthy.load(Relaxed))
+        .with_on_retire(move |item| pool_retired.lock().unwrap().push(item.slot));
+        (pool, Counts { created, retired })
+    }
+
+    #[tokio::test]
+    async fn acquire_timeout_when_every_object_is_in_use() {
+        let (pool, _) = new_pool(1).await;
+        let object = pool.acquire().await;
+        assert!(matches!(pool.acquire_timeout(Duration::from_millis(10)).await, Err(PoolError::Timeout)));
+        assert_eq!(pool.stats().timeouts, 1);
+        pool.release(object).await.unwrap();
+        let object = pool.acquire_timeout(Duration::from_millis(10)).await.unwrap();
+        assert_eq!(object.slot(), 0);
+        assert_eq!(pool.stats().acquired, 2);
+    }
+
+    #[tokio::test]
+    async fn shrink_retires_objects_in_use_when_released() {
+        let (pool, counts) = new_pool(3).await;
+        let objects = vec![pool.acquire().await, pool.acquire().await, pool.acquire().await];
+        pool.resize(1).await;
+        assert_eq!(pool.stats().idle, 0);
+        for object in objects {
+            pool.release(object).await.unwrap();
         }
+        let stats = pool.stats();
+        assert_eq!((stats.capacity, stats.idle, stats.in_use, stats.retired), (1, 1, 0, 2));
+        let mut retired = counts.retired.lock().unwrap().clone();
+        retired.sort();
+        assert_eq!(retired, vec![1, 2]);
+        assert_eq!(pool.acquire().await.slot(), 0);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn grow_creates_only_the_new_slots() {
+        let (pool, counts) = new_pool(1).await;
+        let object = pool.acquire().await;
+        pool.resize(3).await;
+        assert_eq!(pool.stats().idle, 2);
+        assert_eq!(counts.created.load(Relaxed), 3);
+        pool.release(object).await.unwrap();
+        let stats = pool.stats();
+        assert_eq!((stats.capacity, stats.idle, stats.in_use), (3, 3, 0));
+        assert!(counts.retired.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unhealthy_object_is_replaced_in_its_slot() {
+        let (pool, counts) = new_pool(2).await;
+        let object = pool.acquire().await;
+        let slot = object.slot();
+        object.object.healthy.store(false, Relaxed);
+        pool.release(object).await.unwrap();
+        assert_eq!(pool.stats().replaced, 1);
+        assert_eq!(pool.stats().idle, 2);
+        assert_eq!(*counts.retired.lock().unwrap(), vec![slot]);
+        assert_eq!(counts.created.load(Relaxed), 3);
+        let first = pool.acquire().await;
+        let second = pool.acquire().await;
+        assert!(first.object.healthy.load(Relaxed) && second.object.healthy.load(Relaxed));
+    }
+
+    #[tokio::test]
+    async fn release_during_recycle_retires_the_old_generation() {
+        let (pool, counts) = new_pool(2).await;
+        let object = pool.acquire().await;
+        let (_, released) = tokio::join!(pool.recycle(), pool.release(object));
+        released.unwrap();
+        let stats = pool.stats();
+        assert_eq!((stats.idle, stats.in_use, stats.retired), (2, 0, 2));
+        assert_eq!(counts.retired.lock().unwrap().len(), 2);
+        assert_eq!(counts.created.load(Relaxed), 4);
+        let first = pool.acquire().await;
+        let second = pool.acquire().await;
+        assert_ne!(first.slot(), second.slot());
+        assert_eq!(counts.created.load(Relaxed), 4);
+    }
+}